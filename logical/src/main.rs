@@ -1,5 +1,3 @@
-mod models;
-
 fn main() {
     println!("Hello, world!");
 }
@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
-use storeful::Context;
+use storeful::{prelude::*, Context, IndexValue, Key, Storeable, Versioned};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IncomingLog {
@@ -39,3 +39,69 @@ impl Display for Log {
         )
     }
 }
+
+impl Versioned for Log {}
+
+impl Storeable for Log {
+    const INDEXES: &'static [&'static str] = &["context"];
+
+    fn primary(&self) -> Key {
+        Key::new()
+            .with_i64(self.timestamp())
+            .with_context(&self.context)
+            .with_str(&self.message)
+    }
+
+    /// Dates nanoseconds can't reach are clamped, `validate` keeps them from being stored.
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+            .timestamp_nanos_opt()
+            .unwrap_or(if self.timestamp.timestamp() < 0 {
+                i64::MIN
+            } else {
+                i64::MAX
+            })
+    }
+
+    fn index_values(&self) -> Vec<IndexValue> {
+        self.context
+            .values()
+            .iter()
+            .map(|context_value| IndexValue::context_value("context", context_value))
+            .collect()
+    }
+
+    fn context(&self) -> Option<&Context> {
+        Some(&self.context)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self.timestamp.timestamp_nanos_opt() {
+            Some(_) => Ok(()),
+            None => Err(StorefulError::InvalidTimestamp(self.timestamp.to_rfc3339())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use storeful::{memory::MemoryBackend, Storeful};
+
+    use super::*;
+
+    #[test]
+    fn refuses_dates_past_nanoseconds() {
+        let storeful = Storeful::new(MemoryBackend::new("logs".into(), &Log::trees()));
+        let log = |timestamp: &str| Log {
+            timestamp: timestamp.parse().unwrap(),
+            context: Context::default(),
+            message: "started".into(),
+        };
+        assert!(matches!(
+            storeful.post([log("2300-01-01T00:00:00Z")]),
+            Err(StorefulError::InvalidTimestamp(_))
+        ));
+        assert_eq!(log("1600-01-01T00:00:00Z").timestamp(), i64::MIN);
+        storeful.post([log("2000-01-01T00:00:00Z")]).unwrap();
+    }
+}
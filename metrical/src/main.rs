@@ -1,29 +1,57 @@
 use std::sync::Arc;
 
-use storage::Metrical;
-use storeful::{prelude::*, sled::SledBackend, Args, Config, Storeful};
-use tokio::sync::Mutex;
-
-mod models;
-mod query;
-mod storage;
+use metrical::storage::Metrical;
+use storeful::{
+    memory::MemoryBackend, prelude::*, sled::SledBackend, Args, Backend, BackendDatabase, Config,
+    Storeful, SERIES_TREES,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::default();
 
-    let sled = SledBackend::open(
-        args.db_path(),
-        "metrics".into(),
-        &["name", "timestamp", "context"],
-    )?;
+    match args.backend() {
+        Backend::Sled => {
+            let sled = SledBackend::open(args.db_path(), "metrics".into(), SERIES_TREES)?;
+            serve(sled, args).await
+        }
+        Backend::Memory => {
+            let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+            serve(memory, args).await
+        }
+        #[cfg(feature = "rocksdb")]
+        Backend::RocksDB => {
+            let rocksdb = storeful::rocksdb::RocksDBBackend::open(
+                args.db_path(),
+                "metrics".into(),
+                SERIES_TREES,
+            )?;
+            serve(rocksdb, args).await
+        }
+        #[cfg(not(feature = "rocksdb"))]
+        Backend::RocksDB => Err(StorefulError::BackendUnavailable("rocksdb".into())),
+    }
+}
 
-    let storeful = Storeful::new(sled);
-    let metrical = Metrical::new(storeful);
+async fn serve<B>(backend: B, args: Args) -> Result<()>
+where
+    B: BackendDatabase + Send + Sync + 'static,
+{
+    let storeful = Storeful::new(backend)
+        .with_partition_width(args.partition_width())
+        .with_codec(args.codec()?)
+        .with_chunks(args.chunks())
+        .open()?;
+    if args.migrate() {
+        let migrated = storeful.migrate_series()?;
+        eprintln!("Migrated {} series", migrated);
+        return Ok(());
+    }
+    let metrical = Metrical::new(storeful, args.ingest());
 
-    let handler = Arc::new(Mutex::new(metrical));
+    let handler = Arc::new(metrical);
 
-    let config: Config = args.into();
+    let config = Config::new(args, "metrics");
     config.start(handler).await?;
 
     // Wait for all tasks to finish
@@ -34,27 +62,24 @@ async fn main() -> Result<()> {
 mod tests {
     use super::*;
 
-    use std::path::PathBuf;
-
-    use chrono::Utc;
-    use models::Metric;
-    use query::MetricQuery;
+    use chrono::{DateTime, Utc};
+    use metrical::{models::Metric, query::MetricQuery};
     use rand::prelude::SliceRandom;
-    use storage::Metrical;
-    use storeful::{Context, ContextValue, ModelEndpoints, Storeful};
+    use std::time::Duration;
+    use storeful::{
+        Context, ContextValue, Expired, IngestConfig, ModelEndpoints, Order, Query, Storeful,
+    };
 
     #[tokio::test]
     async fn test() {
-        let path = PathBuf::from("./test.db");
-        let sled = SledBackend::open(&path, "metrics".into(), &["name", "timestamp", "context"])
-            .expect("Failed to open sled backend");
-        let storeful = Storeful::new(sled);
-        let mut metrical = Metrical::new(storeful);
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let storeful = Storeful::new(memory);
+        let metrical = Metrical::new(storeful, IngestConfig::default());
 
         let start_time = Utc::now();
 
         let mut metrics = vec![];
-        let metric_count = 1000;
+        let metric_count: i64 = 1000;
         for i in 0..metric_count {
             // Random value between 0 and 1
             let random_value = rand::random::<f64>();
@@ -63,14 +88,13 @@ mod tests {
             let name_choices = ["cpu_usage", "memory_usage", "disk_usage"];
             let host_choices = ["localhost", "server1", "server2", "server3", "server4"];
             let region_choices = ["us-west", "us-east", "eu-west", "eu-east"];
-            let metric = Metric {
-                name: name_choices
+            let metric = Metric::new(
+                DateTime::from_timestamp_nanos(random_timestamp),
+                name_choices
                     .choose(&mut rand::thread_rng())
                     .unwrap()
                     .to_string(),
-                timestamp: random_timestamp,
-                value: random_value,
-                context: Context(vec![
+                Context::new([
                     ContextValue {
                         key: "host".into(),
                         value: host_choices
@@ -85,12 +109,14 @@ mod tests {
                             .unwrap()
                             .to_string(),
                     },
-                ]),
-            };
+                ])
+                .unwrap(),
+                random_value,
+            );
             metrics.push(metric);
         }
 
-        metrical.post_multi(metrics).await.unwrap();
+        metrical.post_multi(metrics.clone()).await.unwrap();
 
         let written_time = Utc::now();
 
@@ -108,10 +134,30 @@ mod tests {
                 value: "us-west".into(),
             });
 
-        let results = metrical.query(query).await.unwrap();
+        let results = metrical.query(query).await.unwrap().records;
 
         // dbg!(&results);
 
+        let has_label =
+            |metric: &Metric, key: &str, value: &str| metric.series.context.get(key) == Some(value);
+        let expected = metrics
+            .iter()
+            .filter(|metric| metric.series.name == "memory_usage")
+            .filter(|metric| metric.timestamp.timestamp_nanos_opt().unwrap() <= metric_count / 2)
+            .filter(|metric| has_label(metric, "host", "localhost"))
+            .filter(|metric| has_label(metric, "region", "us-west"))
+            .count();
+        assert_eq!(results.len(), expected);
+
+        // A filter matching nothing empties the result, whatever the other filters match
+        let nothing = MetricQuery::empty()
+            .with_name("unknown_usage".into())
+            .with_context_value(ContextValue {
+                key: "host".into(),
+                value: "localhost".into(),
+            });
+        assert!(metrical.query(nothing).await.unwrap().records.is_empty());
+
         let queried_time = Utc::now();
 
         println!(
@@ -125,4 +171,365 @@ mod tests {
             (queried_time - start_time).num_milliseconds()
         );
     }
+
+    #[tokio::test]
+    async fn delete() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let metrical = Metrical::new(Storeful::new(memory), IngestConfig::default());
+
+        let metrics = (0..10)
+            .map(|i| {
+                Metric::new(
+                    DateTime::from_timestamp_nanos(i),
+                    ["cpu_usage", "disk_usage"][i as usize % 2].into(),
+                    Context::default().with_value("host", "localhost"),
+                    i as f64,
+                )
+            })
+            .collect();
+        metrical.post_multi(metrics).await.unwrap();
+
+        let cpu_usage = || MetricQuery::empty().with_name("cpu_usage".into());
+        let localhost = || {
+            MetricQuery::empty().with_context_value(ContextValue {
+                key: "host".into(),
+                value: "localhost".into(),
+            })
+        };
+
+        // A filter that matches nothing deletes nothing, rather than being skipped over
+        let gpu_usage = localhost().with_name("gpu_usage".into());
+        assert_eq!(metrical.delete(gpu_usage).await.unwrap(), 0);
+
+        assert_eq!(metrical.delete(cpu_usage()).await.unwrap(), 5);
+        assert!(metrical
+            .query(cpu_usage())
+            .await
+            .unwrap()
+            .records
+            .is_empty());
+
+        // The index entries of the deleted metrics are gone as well
+        let remaining = metrical.query(localhost()).await.unwrap().records;
+        assert_eq!(remaining.len(), 5);
+        assert!(remaining
+            .iter()
+            .all(|metric| metric.series.name == "disk_usage"));
+    }
+
+    #[tokio::test]
+    async fn query_stream() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let storeful = Storeful::new(memory).with_partition_width(Duration::from_nanos(10));
+        let metrical = Metrical::new(storeful, IngestConfig::default());
+
+        // Enough metrics across partitions that they are fetched in several chunks
+        let metrics = (0..1000)
+            .map(|i| {
+                Metric::new(
+                    DateTime::from_timestamp_nanos(i),
+                    "cpu_usage".into(),
+                    Context::default().with_value("host", "localhost"),
+                    i as f64,
+                )
+            })
+            .collect();
+        metrical.post_multi(metrics).await.unwrap();
+
+        let query = || MetricQuery::empty().with_timestamp_start(100);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let (result, streamed) = tokio::join!(metrical.query_stream(query(), tx), async {
+            let mut streamed = Vec::new();
+            while let Some(metric) = rx.recv().await {
+                streamed.push(metric);
+            }
+            streamed
+        });
+        result.unwrap();
+        assert_eq!(streamed.len(), 900);
+
+        let queried = metrical.query(query()).await.unwrap().records;
+        assert!(streamed
+            .iter()
+            .zip(&queried)
+            .all(|(streamed, queried)| streamed.timestamp == queried.timestamp));
+
+        // Results share their series, which is still written out as the name and labels
+        assert!(Arc::ptr_eq(&queried[0].series, &queried[1].series));
+        let json = serde_json::to_value(&queried[0]).unwrap();
+        assert_eq!(json["name"], "cpu_usage");
+        assert_eq!(
+            json["context"],
+            serde_json::json!([{ "key": "host", "value": "localhost" }])
+        );
+        let read: Metric = serde_json::from_value(json).unwrap();
+        assert_eq!(read.series, queried[0].series);
+
+        // Dropping the receiver stops the query instead of failing it
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        drop(rx);
+        metrical.query_stream(query(), tx).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_unstorable_timestamps() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let metrical = Metrical::new(Storeful::new(memory), IngestConfig::default());
+        let metric = |year: i32| {
+            Metric::new(
+                format!("{}-01-01T00:00:00Z", year).parse().unwrap(),
+                "cpu_usage".into(),
+                Context::default(),
+                year.into(),
+            )
+        };
+
+        // Nanoseconds since the epoch don't reach 2300, which fails that post alone
+        let (refused, posted) =
+            tokio::join!(metrical.post(metric(2300)), metrical.post(metric(2000)));
+        assert!(matches!(refused, Err(StorefulError::InvalidTimestamp(_))));
+        posted.unwrap();
+        assert!(matches!(
+            metrical.post_multi(vec![metric(2001), metric(1600)]).await,
+            Err(StorefulError::InvalidTimestamp(_))
+        ));
+
+        // Nothing was left locked or half written
+        metrical.post(metric(2002)).await.unwrap();
+        let all = MetricQuery::empty().with_name("cpu_usage".into());
+        let values: Vec<f64> = metrical
+            .query(all)
+            .await
+            .unwrap()
+            .records
+            .iter()
+            .map(|metric| metric.value)
+            .collect();
+        assert_eq!(values, [2000.0, 2002.0]);
+    }
+
+    #[tokio::test]
+    async fn label_order() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let metrical = Metrical::new(Storeful::new(memory), IngestConfig::default());
+
+        // The same series, labels given in a different order each time
+        for (context, value) in [
+            (
+                r#"[{"key": "host", "value": "a"}, {"key": "region", "value": "eu"}]"#,
+                1.0,
+            ),
+            (
+                r#"[{"key": "region", "value": "eu"}, {"key": "host", "value": "a"}]"#,
+                2.0,
+            ),
+        ] {
+            let metric = Metric::new(
+                DateTime::from_timestamp_nanos(0),
+                "cpu_usage".into(),
+                serde_json::from_str(context).unwrap(),
+                value,
+            );
+            metrical.post(metric).await.unwrap();
+        }
+
+        let all = MetricQuery::empty().with_name("cpu_usage".into());
+        let metrics = metrical.query(all).await.unwrap().records;
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].value, 2.0);
+    }
+
+    #[tokio::test]
+    async fn label_matchers() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let metrical = Metrical::new(Storeful::new(memory), IngestConfig::default());
+        let contexts = [
+            Context::default()
+                .with_value("host", "a")
+                .with_value("region", "eu-west"),
+            Context::default()
+                .with_value("host", "b")
+                .with_value("region", "us-east"),
+            Context::default().with_value("host", "c"),
+        ];
+        for (i, context) in contexts.into_iter().enumerate() {
+            let metric = Metric::new(
+                DateTime::from_timestamp_nanos(i as i64),
+                "cpu_usage".into(),
+                context,
+                i as f64,
+            );
+            metrical.post(metric).await.unwrap();
+        }
+
+        let queries = [
+            (vec![r#"host!="a""#], vec![1.0, 2.0]),
+            (vec![r#"region=~"eu-.*""#], vec![0.0]),
+            (vec![r#"region!~"eu-.*""#], vec![1.0, 2.0]),
+            (vec!["region"], vec![0.0, 1.0]),
+            (vec!["!region", r#"host=~"b|c""#], vec![2.0]),
+        ];
+        for (matchers, expected) in queries {
+            let query = matchers.iter().fold(
+                MetricQuery::empty().with_name("cpu_usage".into()),
+                |query, matcher| query.with_matcher(matcher.to_string()),
+            );
+            let metrics = metrical.query(query).await.unwrap().records;
+            let values: Vec<f64> = metrics.iter().map(|metric| metric.value).collect();
+            assert_eq!(values, expected, "{:?}", matchers);
+        }
+
+        let invalid = MetricQuery::empty().with_matcher("host=a".into());
+        assert!(metrical.query(invalid).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn paging() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let storeful = Storeful::new(memory).with_partition_width(Duration::from_nanos(10));
+        let metrical = Metrical::new(storeful, IngestConfig::default());
+
+        // Two hosts per timestamp, so pages have to split between equal timestamps
+        let mut metrics: Vec<Metric> = (0..200)
+            .map(|i| {
+                Metric::new(
+                    DateTime::from_timestamp_nanos(i / 2),
+                    ["cpu_usage", "disk_usage"][i as usize % 4 / 2].into(),
+                    Context::default().with_value("host", &format!("server{}", i % 2)),
+                    i as f64,
+                )
+            })
+            .collect();
+        metrics.shuffle(&mut rand::thread_rng());
+        metrical.post_multi(metrics).await.unwrap();
+
+        // By name the index is scanned, without it the timestamp index is
+        let queries = [
+            (MetricQuery::empty().with_name("cpu_usage".into()), 100),
+            (MetricQuery::empty().with_timestamp_start(10), 180),
+        ];
+        for (query, expected) in queries {
+            for order in [Order::Ascending, Order::Descending] {
+                let mut pages = Vec::new();
+                let mut continuation = None;
+                loop {
+                    let page_query = MetricQuery {
+                        continuation: continuation.take(),
+                        ..query.clone()
+                    }
+                    .with_order(order)
+                    .with_limit(7);
+                    // Sent along the way a client would, continuation and all
+                    let page_query = MetricQuery::from_str(&page_query.to_string());
+                    let page = metrical.query(page_query).await.unwrap();
+                    assert!(page.records.len() <= 7);
+                    pages.extend(page.records);
+                    match page.continuation {
+                        Some(next) => continuation = Some(next),
+                        None => break,
+                    }
+                }
+
+                let timestamps: Vec<i64> = pages
+                    .iter()
+                    .map(|metric| metric.timestamp.timestamp_nanos_opt().unwrap())
+                    .collect();
+                let mut sorted = timestamps.clone();
+                sorted.sort();
+                if order == Order::Descending {
+                    sorted.reverse();
+                }
+                assert_eq!(timestamps, sorted);
+
+                // Every metric shows up exactly once across the pages
+                let mut values: Vec<i64> = pages.iter().map(|metric| metric.value as i64).collect();
+                values.sort();
+                values.dedup();
+                assert_eq!(values.len(), expected);
+            }
+        }
+
+        let latest = MetricQuery::empty()
+            .with_order(Order::Descending)
+            .with_limit(3);
+        let latest = metrical.query(latest).await.unwrap().records;
+        let timestamps: Vec<i64> = latest
+            .iter()
+            .map(|metric| metric.timestamp.timestamp_nanos_opt().unwrap())
+            .collect();
+        assert_eq!(timestamps, [99, 99, 98]);
+
+        let garbage = MetricQuery::empty().with_continuation("not a token".into());
+        assert!(metrical.query(garbage).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let metrical = Arc::new(Metrical::new(
+            Storeful::new(memory),
+            IngestConfig::default(),
+        ));
+
+        // Writers and readers share the handler without taking turns on a lock
+        let mut tasks = Vec::new();
+        for writer in 0..8 {
+            let metrical = metrical.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..50 {
+                    let metric = Metric::new(
+                        DateTime::from_timestamp_nanos(writer * 50 + i),
+                        "cpu_usage".into(),
+                        Context::default().with_value("host", "localhost"),
+                        i as f64,
+                    );
+                    metrical.post(metric).await.unwrap();
+                    let query = MetricQuery::empty().with_name("cpu_usage".into());
+                    assert!(!metrical.query(query).await.unwrap().records.is_empty());
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let all = MetricQuery::empty().with_name("cpu_usage".into());
+        assert_eq!(metrical.query(all).await.unwrap().records.len(), 400);
+    }
+
+    #[tokio::test]
+    async fn expire() {
+        let memory = MemoryBackend::new("metrics".into(), SERIES_TREES);
+        let storeful = Storeful::new(memory).with_partition_width(Duration::from_nanos(10));
+        let metrical = Metrical::new(storeful, IngestConfig::default());
+
+        let metrics = (0..40)
+            .map(|i| {
+                Metric::new(
+                    DateTime::from_timestamp_nanos(i),
+                    "cpu_usage".into(),
+                    Context::default().with_value("host", "localhost"),
+                    i as f64,
+                )
+            })
+            .collect();
+        metrical.post_multi(metrics).await.unwrap();
+
+        // 0..20 fill two partitions that are dropped whole, 20..25 are removed one by one
+        let expired = metrical.expire(25).await.unwrap();
+        assert_eq!(
+            expired,
+            Expired {
+                partitions: 2,
+                records: 5
+            }
+        );
+
+        let all = MetricQuery::empty().with_timestamp_start(0);
+        let remaining = metrical.query(all).await.unwrap().records;
+        assert_eq!(remaining.len(), 15);
+        assert!(remaining
+            .iter()
+            .all(|metric| metric.timestamp.timestamp_nanos_opt().unwrap() >= 25));
+    }
 }
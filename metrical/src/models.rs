@@ -1,8 +1,8 @@
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use chrono::{DateTime, SecondsFormat, Utc};
-use serde::{Deserialize, Serialize};
-use storeful::Context;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use storeful::{prelude::*, Context, IndexValue, Key, Sample, Sampled, Series};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IncomingMetric {
@@ -12,22 +12,69 @@ pub struct IncomingMetric {
     pub value: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// A sample of a series.
+///
+/// Metrics read by the same query share their series rather than each holding a copy of its
+/// name and labels. They are written out with the name and labels alongside the timestamp and
+/// value all the same.
+#[derive(Debug, Clone)]
 pub struct Metric {
     pub timestamp: DateTime<Utc>,
-    pub name: String,
-    pub context: Context,
+    pub series: Arc<Series>,
     pub value: f64,
 }
 
+impl Metric {
+    pub fn new(timestamp: DateTime<Utc>, name: String, context: Context, value: f64) -> Self {
+        Metric {
+            timestamp,
+            series: Arc::new(Series { name, context }),
+            value,
+        }
+    }
+}
+
 impl From<IncomingMetric> for Metric {
     fn from(incoming: IncomingMetric) -> Self {
-        Metric {
-            timestamp: incoming.timestamp.unwrap_or(Utc::now()),
-            name: incoming.name,
-            context: incoming.context,
-            value: incoming.value,
+        Metric::new(
+            incoming.timestamp.unwrap_or(Utc::now()),
+            incoming.name,
+            incoming.context,
+            incoming.value,
+        )
+    }
+}
+
+/// The fields of a `Metric` as they are written out, with its series flattened into it.
+#[derive(Serialize, Deserialize)]
+struct MetricFields<N, C> {
+    timestamp: DateTime<Utc>,
+    name: N,
+    context: C,
+    value: f64,
+}
+
+impl Serialize for Metric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        MetricFields {
+            timestamp: self.timestamp,
+            name: &self.series.name,
+            context: &self.series.context,
+            value: self.value,
         }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Metric {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let fields = MetricFields::<String, Context>::deserialize(deserializer)?;
+        Ok(Metric::new(
+            fields.timestamp,
+            fields.name,
+            fields.context,
+            fields.value,
+        ))
     }
 }
 
@@ -37,9 +84,47 @@ impl Display for Metric {
             f,
             "{} {}{} {}",
             self.timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
-            self.name,
-            self.context,
+            self.series.name,
+            self.series.context,
             self.value
         )
     }
 }
+
+impl Sampled for Metric {
+    fn name(&self) -> &str {
+        &self.series.name
+    }
+
+    fn context(&self) -> &Context {
+        &self.series.context
+    }
+
+    fn sample(&self) -> Result<Sample> {
+        // Nanoseconds since the epoch only reach from 1677 to 2262
+        let timestamp = self
+            .timestamp
+            .timestamp_nanos_opt()
+            .ok_or_else(|| StorefulError::InvalidTimestamp(self.timestamp.to_rfc3339()))?;
+        Ok(Sample {
+            timestamp,
+            value: self.value,
+        })
+    }
+
+    fn index_values(series: &Series) -> Vec<IndexValue> {
+        let mut index_values = vec![IndexValue::new("name", Key::new().with_str(&series.name))];
+        for context_value in series.context.values() {
+            index_values.push(IndexValue::context_value("context", context_value));
+        }
+        index_values
+    }
+
+    fn from_sample(series: &Arc<Series>, sample: Sample) -> Self {
+        Metric {
+            timestamp: DateTime::from_timestamp_nanos(sample.timestamp),
+            series: series.clone(),
+            value: sample.value,
+        }
+    }
+}
@@ -1,22 +1,74 @@
 use serde::{Deserialize, Serialize};
-use storeful::{ContextValue, Context, Query};
+use storeful::{
+    prelude::*, Context, ContextValue, IndexQuery, IndexValue, Key, LabelMatcher, Order, Query,
+};
 
 impl Query for MetricQuery {
     fn from_str(s: &str) -> Self {
-        todo!()
+        serde_json::from_str(s).unwrap()
     }
 
     fn to_string(&self) -> String {
-        todo!()
+        serde_json::to_string(self).unwrap()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl IndexQuery for MetricQuery {
+    fn timestamp_start(&self) -> Option<i64> {
+        self.timestamp_start
+    }
+
+    fn timestamp_end(&self) -> Option<i64> {
+        self.timestamp_end
+    }
+
+    fn index_values(&self) -> Vec<IndexValue> {
+        let mut index_values = Vec::new();
+        if let Some(name) = &self.name {
+            index_values.push(IndexValue::new("name", Key::new().with_str(name)));
+        }
+        if let Some(context) = &self.context {
+            for context_value in context.values() {
+                index_values.push(IndexValue::context_value("context", context_value));
+            }
+        }
+        index_values
+    }
+
+    fn label_matchers(&self) -> Result<Vec<LabelMatcher>> {
+        self.matchers
+            .iter()
+            .map(|matcher| LabelMatcher::parse("context", matcher))
+            .collect()
+    }
+
+    fn order(&self) -> Order {
+        self.order
+    }
+
+    fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    fn continuation(&self) -> Option<&str> {
+        self.continuation.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetricQuery {
     pub name: Option<String>,
     pub timestamp_start: Option<i64>,
     pub timestamp_end: Option<i64>,
     pub context: Option<Context>,
+    /// Label matchers such as `host!="a"`, `region=~"eu-.*"`, `env` or `!env`.
+    #[serde(default)]
+    pub matchers: Vec<String>,
+    #[serde(default)]
+    pub order: Order,
+    pub limit: Option<usize>,
+    /// The `continuation` of the previous page.
+    pub continuation: Option<String>,
 }
 
 impl MetricQuery {
@@ -26,6 +78,10 @@ impl MetricQuery {
             timestamp_start: None,
             timestamp_end: None,
             context: None,
+            matchers: Vec::new(),
+            order: Order::default(),
+            limit: None,
+            continuation: None,
         }
     }
 
@@ -44,13 +100,32 @@ impl MetricQuery {
         self
     }
 
+    pub fn with_order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn with_continuation(mut self, continuation: String) -> Self {
+        self.continuation = Some(continuation);
+        self
+    }
+
     pub fn with_context_value(mut self, context_value: ContextValue) -> Self {
-        if let Some(mut context) = self.context {
-            context.0.push(context_value);
-            self.context = Some(context);
-        } else {
-            self.context = Some(Context(vec![context_value]));
-        }
+        self.context = Some(
+            self.context
+                .unwrap_or_default()
+                .with_value(&context_value.key, &context_value.value),
+        );
+        self
+    }
+
+    pub fn with_matcher(mut self, matcher: String) -> Self {
+        self.matchers.push(matcher);
         self
     }
 }
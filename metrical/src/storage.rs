@@ -1,112 +1,71 @@
-use std::collections::HashSet;
-
 use crate::models::Metric;
 use crate::query::MetricQuery;
-use storeful::{intersect, prelude::*, BackendDatabase, ModelEndpoints, Storeful};
+use storeful::{
+    prelude::*, BackendDatabase, BlockingStoreful, Expired, IngestBuffer, IngestConfig,
+    ModelEndpoints, Page, Sampled, Storeful,
+};
+use tokio::sync::mpsc::Sender;
 
 pub struct Metrical<B>
 where
-    B: BackendDatabase + Send + Sync,
+    B: BackendDatabase + Send + Sync + 'static,
 {
-    storeful: Storeful<B>,
+    storeful: BlockingStoreful<B>,
+    ingest: IngestBuffer<Metric>,
 }
 
 impl<B> Metrical<B>
 where
-    B: BackendDatabase + Send + Sync,
+    B: BackendDatabase + Send + Sync + 'static,
 {
-    pub fn new(storeful: Storeful<B>) -> Self {
-        Self { storeful }
-    }
-
-    pub fn get_metrics(&self, primaries: &HashSet<Box<[u8]>>) -> Result<Vec<Metric>> {
-        let results = self.storeful.backend.get_multi(primaries)?;
-        let mut metrics = Vec::new();
-        for result in results {
-            metrics.push(bincode::deserialize(&result)?);
+    /// Batches posts as configured by `ingest`, has to be called from within a tokio runtime.
+    pub fn new(storeful: Storeful<B>, ingest: IngestConfig) -> Self {
+        let storeful = BlockingStoreful::new(storeful);
+        let writer = storeful.clone();
+        Self {
+            storeful,
+            ingest: IngestBuffer::new(ingest, move |metrics| {
+                let writer = writer.clone();
+                async move { writer.post_samples(metrics).await }
+            }),
         }
-        Ok(metrics)
     }
 }
 
 impl<B> ModelEndpoints<Metric, MetricQuery> for Metrical<B>
 where
-    B: BackendDatabase + Send + Sync,
+    B: BackendDatabase + Send + Sync + 'static,
 {
-    async fn post(&mut self, metric: Metric) -> Result<()> {
-        let timestamp = format!("{:0>20}", metric.timestamp.timestamp_nanos_opt().unwrap());
-        let primary = format!(
-            "{}|{}|{}",
-            metric.name,
-            timestamp,
-            metric.context.to_key_string()
-        );
-        self.storeful
-            .backend
-            .put(&primary, &bincode::serialize(&metric)?)?;
+    async fn post(&self, metric: Metric) -> Result<()> {
+        metric.sample()?;
+        self.ingest.post(vec![metric]).await
+    }
 
-        self.storeful.backend.create_index(
-            "timestamp",
-            &primary,
-            &format!("timestamp|{:0>20}|{}", timestamp, primary),
-        )?;
+    async fn post_multi(&self, input: Vec<Metric>) -> Result<()> {
+        // Refused before joining a batch, where it would fail the posts of other clients too
+        for metric in &input {
+            metric.sample()?;
+        }
+        self.ingest.post(input).await
+    }
 
-        self.storeful.backend.create_index(
-            "name",
-            &primary,
-            &format!("name|{}|{}", metric.name, primary),
-        )?;
+    async fn query(&self, query: MetricQuery) -> Result<Page<Metric>> {
+        self.storeful.query_sample_page(query).await
+    }
 
-        for context_value in metric.context.0 {
-            self.storeful.backend.create_index(
-                "context",
-                &primary,
-                &format!(
-                    "context_value|{}:{}|{}",
-                    context_value.key, context_value.value, &primary
-                ),
-            )?;
-        }
+    async fn query_stream(&self, query: MetricQuery, results: Sender<Metric>) -> Result<()> {
+        self.storeful.query_sample_stream(query, results).await
+    }
 
-        Ok(())
+    async fn delete(&self, query: MetricQuery) -> Result<usize> {
+        self.storeful.delete_samples(query).await
     }
 
-    async fn post_multi(&mut self, input: Vec<Metric>) -> Result<()> {
-        self.storeful.backend.start_batch()?;
-        for metric in input {
-            self.post(metric).await?;
-        }
-        self.storeful.backend.commit_batch()?;
-        Ok(())
+    async fn expire(&self, before: i64) -> Result<Expired> {
+        self.storeful.expire_samples(before).await
     }
 
-    async fn query(&mut self, query: MetricQuery) -> Result<Vec<Metric>> {
-        let mut primaries = HashSet::new();
-        if let Some(name) = query.name {
-            let name_key = format!("name|{}|", name);
-            let name_primaries = self.storeful.backend.query_index("name", &name_key)?;
-            intersect(&mut primaries, name_primaries);
-        }
-        if query.timestamp_start.is_some() || query.timestamp_end.is_some() {
-            let timestamp_primaries = self
-                .storeful
-                .backend
-                .query_timestamp_index(query.timestamp_start, query.timestamp_end)?;
-            intersect(&mut primaries, timestamp_primaries);
-        }
-        if let Some(context) = query.context {
-            for context_value in context.0 {
-                let context_value_key = format!(
-                    "context_value|{}:{}|",
-                    context_value.key, context_value.value
-                );
-                let context_value_primaries = self
-                    .storeful
-                    .backend
-                    .query_index("context", &context_value_key)?;
-                intersect(&mut primaries, context_value_primaries);
-            }
-        }
-        self.get_metrics(&primaries)
+    async fn migrate(&self) -> Result<usize> {
+        self.storeful.migrate_series().await
     }
 }
@@ -1,6 +1,11 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use clap::{command, Parser};
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    model_retention, parse_duration, parse_queue_limit, parse_retention, prelude::*, ChunkConfig,
+    Codec, Compression, IngestConfig, RetentionRule, DEFAULT_PARTITION_WIDTH,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -8,6 +13,9 @@ pub struct RawArgs {
     #[clap(long)]
     db_path: Option<PathBuf>,
 
+    #[clap(long, value_enum)]
+    backend: Option<Backend>,
+
     #[clap(long)]
     host: Option<String>,
 
@@ -16,6 +24,54 @@ pub struct RawArgs {
 
     #[clap(long)]
     http: bool,
+
+    /// Remove records older than this, e.g. `30d` or `12h`, or only those of one model with
+    /// e.g. `metrics=30d`. Can be given more than once.
+    #[clap(long, value_parser = parse_retention)]
+    retention: Vec<RetentionRule>,
+
+    /// How much time each partition covers, e.g. `1d`. Has to stay the same for as long as a
+    /// database is in use, a store opened with another width than it was created with is
+    /// refused.
+    #[clap(long, value_parser = parse_duration)]
+    partition_width: Option<Duration>,
+
+    /// The most records written at once when posts are batched together.
+    #[clap(long)]
+    ingest_batch_size: Option<usize>,
+
+    /// How long a post waits for others to share its write with, in milliseconds.
+    #[clap(long)]
+    ingest_batch_delay_ms: Option<u64>,
+
+    /// How many posted records may wait to be written before posts are refused with a 429.
+    #[clap(long, value_parser = parse_queue_limit)]
+    ingest_queue_limit: Option<usize>,
+
+    /// The most samples stored in one compressed chunk of a series.
+    #[clap(long)]
+    chunk_samples: Option<usize>,
+
+    /// The most time one compressed chunk of a series spans, e.g. `2h`.
+    #[clap(long, value_parser = parse_duration)]
+    chunk_window: Option<Duration>,
+
+    /// How stored values are compressed, values written before stay readable when changed.
+    #[clap(long, value_enum)]
+    compression: Option<Compression>,
+
+    /// The zstd compression level.
+    #[clap(long)]
+    compression_level: Option<i32>,
+
+    /// A zstd dictionary, e.g. one trained on log messages. New values are compressed with the
+    /// last one given, the others are only read from.
+    #[clap(long)]
+    compression_dictionary: Vec<PathBuf>,
+
+    /// Rewrite everything stored as an older model version as the current one, then exit.
+    #[clap(long)]
+    migrate: bool,
 }
 
 impl RawArgs {
@@ -27,21 +83,62 @@ impl RawArgs {
 
 impl From<RawArgs> for Args {
     fn from(raw_args: RawArgs) -> Self {
+        let defaults = IngestConfig::default();
+        let chunk_defaults = ChunkConfig::default();
         Args {
             db_path: raw_args.db_path.unwrap(),
+            backend: raw_args.backend.unwrap_or_default(),
             host: "127.0.0.1".to_string(),
             port: 4040,
             http: raw_args.http,
+            retention: raw_args.retention,
+            partition_width: raw_args.partition_width.unwrap_or(DEFAULT_PARTITION_WIDTH),
+            ingest: IngestConfig {
+                batch_size: raw_args.ingest_batch_size.unwrap_or(defaults.batch_size),
+                batch_delay: raw_args
+                    .ingest_batch_delay_ms
+                    .map_or(defaults.batch_delay, Duration::from_millis),
+                queue_limit: raw_args.ingest_queue_limit.unwrap_or(defaults.queue_limit),
+            },
+            compression: raw_args.compression.unwrap_or_default(),
+            compression_level: raw_args.compression_level,
+            compression_dictionary: raw_args.compression_dictionary,
+            chunks: ChunkConfig {
+                samples: raw_args.chunk_samples.unwrap_or(chunk_defaults.samples),
+                window: raw_args.chunk_window.unwrap_or(chunk_defaults.window),
+            },
+            migrate: raw_args.migrate,
         }
     }
 }
 
+/// The storage engine a binary opens at startup.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Sled,
+    /// Keeps everything in memory, `db_path` is ignored.
+    Memory,
+    /// Only available when built with the `rocksdb` feature.
+    #[value(name = "rocksdb")]
+    RocksDB,
+}
+
 #[derive(Debug)]
 pub struct Args {
     pub db_path: PathBuf,
+    pub backend: Backend,
     pub host: String,
     pub port: u16,
     pub http: bool,
+    pub retention: Vec<RetentionRule>,
+    pub partition_width: Duration,
+    pub ingest: IngestConfig,
+    pub chunks: ChunkConfig,
+    pub compression: Compression,
+    pub compression_level: Option<i32>,
+    pub compression_dictionary: Vec<PathBuf>,
+    pub migrate: bool,
 }
 
 impl Default for Args {
@@ -55,6 +152,10 @@ impl Args {
         &self.db_path
     }
 
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
     pub fn host(&self) -> &str {
         &self.host
     }
@@ -66,4 +167,37 @@ impl Args {
     pub fn http(&self) -> bool {
         self.http
     }
+
+    /// How long the records of the model stored under `model` are kept.
+    pub fn retention(&self, model: &str) -> Option<Duration> {
+        model_retention(&self.retention, model)
+    }
+
+    pub fn partition_width(&self) -> Duration {
+        self.partition_width
+    }
+
+    pub fn ingest(&self) -> IngestConfig {
+        self.ingest
+    }
+
+    pub fn chunks(&self) -> ChunkConfig {
+        self.chunks
+    }
+
+    pub fn migrate(&self) -> bool {
+        self.migrate
+    }
+
+    /// The codec set up by the compression flags, reading any dictionaries given.
+    pub fn codec(&self) -> Result<Codec> {
+        let mut codec = Codec::new(self.compression);
+        if let Some(level) = self.compression_level {
+            codec = codec.with_level(level);
+        }
+        for path in &self.compression_dictionary {
+            codec = codec.with_dictionary(&std::fs::read(path)?);
+        }
+        Ok(codec)
+    }
 }
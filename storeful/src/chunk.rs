@@ -0,0 +1,473 @@
+use std::time::Duration;
+
+use crate::{format_header, prelude::*, read_format, Sample};
+
+/// The version of the chunk encoding, written before its header. Chunks written before it was
+/// start right at their sample count, whose first byte is never `0xff`.
+const CHUNK_FORMAT: u8 = 1;
+
+/// Bytes before the bit stream: the sample count, then the first and last timestamps.
+const HEADER: usize = 4 + 8 + 8;
+
+/// When a chunk of samples is sealed and the next one started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    /// The most samples in one chunk.
+    pub samples: usize,
+    /// The most time between the first and last sample of a chunk.
+    pub window: Duration,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            samples: 120,
+            window: Duration::from_secs(2 * 60 * 60),
+        }
+    }
+}
+
+/// What a chunk holds, readable without decoding its samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub count: u32,
+    pub first: i64,
+    pub last: i64,
+}
+
+impl ChunkHeader {
+    pub fn read(bytes: &[u8]) -> Result<Self> {
+        let header = read_format(bytes, CHUNK_FORMAT)?
+            .get(..HEADER)
+            .ok_or(StorefulError::InvalidChunk)?;
+        Ok(Self {
+            count: u32::from_be_bytes(header[0..4].try_into().unwrap()),
+            first: i64::from_be_bytes(header[4..12].try_into().unwrap()),
+            last: i64::from_be_bytes(header[12..20].try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Clone)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits of the last byte already written, 8 when a new one has to be started.
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 8 {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    /// Writes the lowest `count` bits of `value`, most significant first.
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or(StorefulError::InvalidChunk)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | u64::from(self.read_bit()?);
+        }
+        Ok(value)
+    }
+}
+
+/// Sizes of the delta of delta buckets after their `0`, `10`, `110`, `1110` and `1111` prefixes.
+const DELTA_BUCKETS: [u32; 4] = [16, 24, 32, 64];
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// Compresses the samples of a single series, appended in timestamp order.
+///
+/// Timestamps are stored as the change in their delta, which is zero for regular intervals and
+/// then takes a single bit. Values are XORed with the previous one, so repeated or slowly
+/// changing values only store the few bits that differ.
+#[derive(Clone)]
+pub struct ChunkEncoder {
+    bits: BitWriter,
+    count: u32,
+    first: i64,
+    timestamp: i64,
+    delta: i64,
+    value: u64,
+    /// Leading and trailing zeros of the last XOR written with its own window.
+    window: Option<(u32, u32)>,
+}
+
+impl ChunkEncoder {
+    /// Picks up where the encoder that wrote `bytes` left off, so samples can be appended to
+    /// the chunk without encoding the ones already in it again.
+    pub fn resume(bytes: &[u8]) -> Result<Self> {
+        let mut decoder = ChunkDecoder::new(bytes)?;
+        for sample in &mut decoder {
+            sample?;
+        }
+        let body = &bytes[decoder.start..];
+        let used = decoder.position.div_ceil(8);
+        if decoder.read == 0 || used != body.len() {
+            return Err(StorefulError::InvalidChunk);
+        }
+        Ok(Self {
+            bits: BitWriter {
+                bytes: body.to_vec(),
+                used: match decoder.position % 8 {
+                    0 => 8,
+                    bits => bits as u8,
+                },
+            },
+            count: decoder.header.count,
+            first: decoder.header.first,
+            timestamp: decoder.timestamp,
+            delta: decoder.delta,
+            value: decoder.value,
+            window: decoder.window,
+        })
+    }
+
+    pub fn new(first: Sample) -> Self {
+        let mut bits = BitWriter {
+            bytes: Vec::new(),
+            used: 8,
+        };
+        bits.write_bits(first.value.to_bits(), 64);
+        Self {
+            bits,
+            count: 1,
+            first: first.timestamp,
+            timestamp: first.timestamp,
+            delta: 0,
+            value: first.value.to_bits(),
+            window: None,
+        }
+    }
+
+    pub fn header(&self) -> ChunkHeader {
+        ChunkHeader {
+            count: self.count,
+            first: self.first,
+            last: self.timestamp,
+        }
+    }
+
+    /// Appends `sample`, which has to come after every sample already in the chunk.
+    pub fn push(&mut self, sample: Sample) {
+        debug_assert!(sample.timestamp > self.timestamp);
+        let delta = sample.timestamp.wrapping_sub(self.timestamp);
+        let delta_of_delta = zigzag(delta.wrapping_sub(self.delta));
+        if delta_of_delta == 0 {
+            self.bits.write_bit(false);
+        } else {
+            for (i, size) in DELTA_BUCKETS.into_iter().enumerate() {
+                let last = i == DELTA_BUCKETS.len() - 1;
+                if last || delta_of_delta < 1 << size {
+                    // `1` for every bucket passed over, closed by a `0` unless it's the last one
+                    self.bits.write_bits(u64::MAX, i as u32 + 1);
+                    if !last {
+                        self.bits.write_bit(false);
+                    }
+                    self.bits.write_bits(delta_of_delta, size);
+                    break;
+                }
+            }
+        }
+        self.count += 1;
+        self.timestamp = sample.timestamp;
+        self.delta = delta;
+
+        let value = sample.value.to_bits();
+        let xor = value ^ self.value;
+        self.value = value;
+        if xor == 0 {
+            self.bits.write_bit(false);
+            return;
+        }
+        self.bits.write_bit(true);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match self.window {
+            Some((window_leading, window_trailing))
+                if leading >= window_leading && trailing >= window_trailing =>
+            {
+                self.bits.write_bit(false);
+                self.bits.write_bits(
+                    xor >> window_trailing,
+                    64 - window_leading - window_trailing,
+                );
+            }
+            _ => {
+                let meaningful = 64 - leading - trailing;
+                self.bits.write_bit(true);
+                self.bits.write_bits(leading.into(), 5);
+                self.bits.write_bits((meaningful - 1).into(), 6);
+                self.bits.write_bits(xor >> trailing, meaningful);
+                self.window = Some((leading, trailing));
+            }
+        }
+    }
+
+    pub fn finish(&self) -> Vec<u8> {
+        let format = format_header(CHUNK_FORMAT);
+        let mut bytes = Vec::with_capacity(format.len() + HEADER + self.bits.bytes.len());
+        bytes.extend_from_slice(&format);
+        bytes.extend_from_slice(&self.count.to_be_bytes());
+        bytes.extend_from_slice(&self.first.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.bits.bytes);
+        bytes
+    }
+}
+
+/// Reads the samples of a chunk written by `ChunkEncoder` one at a time, in timestamp order.
+///
+/// Samples are decoded straight from the bytes of the chunk as the iterator advances, nothing
+/// is allocated for them.
+pub struct ChunkDecoder<B> {
+    bytes: B,
+    header: ChunkHeader,
+    /// Where the bit stream starts, past the format version and header.
+    start: usize,
+    /// The next bit to read, past the header.
+    position: usize,
+    /// Samples decoded so far.
+    read: u32,
+    timestamp: i64,
+    delta: i64,
+    value: u64,
+    /// The window of the last XOR read with its own, `None` until there is one.
+    window: Option<(u32, u32)>,
+}
+
+impl<B: AsRef<[u8]>> ChunkDecoder<B> {
+    pub fn new(bytes: B) -> Result<Self> {
+        let header = ChunkHeader::read(bytes.as_ref())?;
+        let body = read_format(bytes.as_ref(), CHUNK_FORMAT)?;
+        let start = bytes.as_ref().len() - body.len() + HEADER;
+        Ok(Self {
+            bytes,
+            header,
+            start,
+            position: 0,
+            read: 0,
+            timestamp: header.first,
+            delta: 0,
+            value: 0,
+            window: None,
+        })
+    }
+
+    pub fn header(&self) -> ChunkHeader {
+        self.header
+    }
+
+    fn read_sample(&mut self) -> Result<Sample> {
+        let mut bits = BitReader {
+            bytes: &self.bytes.as_ref()[self.start..],
+            position: self.position,
+        };
+        if self.read == 0 {
+            self.value = bits.read_bits(64)?;
+        } else {
+            let mut bucket = None;
+            for (i, size) in DELTA_BUCKETS.into_iter().enumerate() {
+                if !bits.read_bit()? {
+                    bucket = (i > 0).then(|| DELTA_BUCKETS[i - 1]);
+                    break;
+                }
+                if i == DELTA_BUCKETS.len() - 1 {
+                    bucket = Some(size);
+                }
+            }
+            let delta_of_delta = match bucket {
+                Some(size) => unzigzag(bits.read_bits(size)?),
+                None => 0,
+            };
+            self.delta = self.delta.wrapping_add(delta_of_delta);
+            self.timestamp = self.timestamp.wrapping_add(self.delta);
+
+            if bits.read_bit()? {
+                if bits.read_bit()? {
+                    let leading = bits.read_bits(5)? as u32;
+                    let meaningful = bits.read_bits(6)? as u32 + 1;
+                    self.window = Some((leading, 64 - leading - meaningful));
+                }
+                let (leading, trailing) = self.window.unwrap_or((0, 0));
+                self.value ^= bits.read_bits(64 - leading - trailing)? << trailing;
+            }
+        }
+        self.position = bits.position;
+        self.read += 1;
+        if self.read == self.header.count && self.timestamp != self.header.last {
+            return Err(StorefulError::InvalidChunk);
+        }
+        Ok(Sample {
+            timestamp: self.timestamp,
+            value: f64::from_bits(self.value),
+        })
+    }
+}
+
+impl<B: AsRef<[u8]>> Iterator for ChunkDecoder<B> {
+    type Item = Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read >= self.header.count {
+            return None;
+        }
+        let sample = self.read_sample();
+        if sample.is_err() {
+            // Nothing past a broken sample can be trusted
+            self.read = self.header.count;
+        }
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.header.count.saturating_sub(self.read) as usize;
+        (0, Some(remaining))
+    }
+}
+
+/// Every sample in a chunk written by `ChunkEncoder`, in timestamp order.
+pub fn decode_chunk(bytes: &[u8]) -> Result<Vec<Sample>> {
+    ChunkDecoder::new(bytes)?.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(samples: &[Sample]) -> Vec<u8> {
+        let mut encoder = ChunkEncoder::new(samples[0]);
+        for sample in &samples[1..] {
+            encoder.push(*sample);
+        }
+        encoder.finish()
+    }
+
+    #[test]
+    fn round_trips() {
+        let values = [
+            0.0,
+            -0.0,
+            1.5,
+            1.5,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            42.0,
+        ];
+        let timestamps = [i64::MIN, -1, 0, 10, 20, 30, 1 << 40, i64::MAX];
+        let samples: Vec<Sample> = timestamps
+            .into_iter()
+            .zip(values)
+            .map(|(timestamp, value)| Sample { timestamp, value })
+            .collect();
+        let bytes = encode(&samples);
+        assert_eq!(decode_chunk(&bytes).unwrap(), samples);
+        assert_eq!(
+            ChunkHeader::read(&bytes).unwrap(),
+            ChunkHeader {
+                count: 8,
+                first: i64::MIN,
+                last: i64::MAX
+            }
+        );
+
+        let nan = encode(&[Sample {
+            timestamp: 0,
+            value: f64::NAN,
+        }]);
+        assert!(decode_chunk(&nan).unwrap()[0].value.is_nan());
+    }
+
+    #[test]
+    fn regular_samples_compress() {
+        // Every 15 seconds, with a value that rarely changes
+        let samples: Vec<Sample> = (0..120)
+            .map(|i| Sample {
+                timestamp: 1_700_000_000_000_000_000 + i * 15_000_000_000,
+                value: (i / 10) as f64,
+            })
+            .collect();
+        let bytes = encode(&samples);
+        assert_eq!(decode_chunk(&bytes).unwrap(), samples);
+        assert!(bytes.len() < 120 * 2, "{} bytes", bytes.len());
+        assert!(decode_chunk(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn reads_chunks_without_a_format_version() {
+        let samples: Vec<Sample> = (0..10)
+            .map(|i| Sample {
+                timestamp: i * 10,
+                value: i as f64,
+            })
+            .collect();
+        let bytes = encode(&samples);
+        assert_eq!(bytes[..2], format_header(CHUNK_FORMAT));
+
+        // As written before chunks had a format version
+        let legacy = &bytes[2..];
+        assert_eq!(decode_chunk(legacy).unwrap(), samples);
+        assert_eq!(ChunkHeader::read(legacy).unwrap().count, 10);
+
+        let newer = [&format_header(CHUNK_FORMAT + 1)[..], legacy].concat();
+        assert!(matches!(
+            decode_chunk(&newer),
+            Err(StorefulError::UnknownFormat(2))
+        ));
+    }
+
+    #[test]
+    fn resumes_where_it_left_off() {
+        let samples: Vec<Sample> = (0..50)
+            .map(|i| Sample {
+                timestamp: i * 15 + i % 3,
+                value: [1.0, 1.5, -20.25, f64::MAX][i as usize % 4],
+            })
+            .collect();
+        let whole = encode(&samples);
+        for split in 1..samples.len() {
+            let mut encoder = ChunkEncoder::resume(&encode(&samples[..split])).unwrap();
+            for sample in &samples[split..] {
+                encoder.push(*sample);
+            }
+            assert_eq!(encoder.finish(), whole, "split at {}", split);
+        }
+        assert!(ChunkEncoder::resume(&whole[..whole.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::{borrow::Cow, collections::HashMap, io::Read, sync::Arc};
+
+use clap::ValueEnum;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::prelude::*;
+
+const RAW: u8 = 0x00;
+const LZ4: u8 = 0x01;
+const ZSTD: u8 = 0x02;
+/// Followed by the `u32` id of the dictionary, then the compressed value.
+const ZSTD_DICTIONARY: u8 = 0x03;
+
+/// How a `Codec` compresses the values it writes.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// A value read back by `Codec::decode_owned`, left where it was read into unless it was
+/// compressed.
+pub enum Decoded {
+    Raw(Box<[u8]>),
+    Decompressed(Vec<u8>),
+}
+
+impl AsRef<[u8]> for Decoded {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            // Past the tag, which `decode_owned` checked
+            Self::Raw(encoded) => &encoded[1..],
+            Self::Decompressed(value) => value,
+        }
+    }
+}
+
+/// A zstd dictionary, prepared once for every value it compresses or decompresses.
+struct Dictionary {
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    fn new(dictionary: &[u8], level: i32) -> Self {
+        Self {
+            encoder: EncoderDictionary::copy(dictionary, level),
+            decoder: DecoderDictionary::copy(dictionary),
+        }
+    }
+}
+
+/// Compresses stored values, tagging each with how it was compressed.
+///
+/// Values are read by their tag rather than by the current settings, so the compression can be
+/// changed at any time and older values stay readable. The same goes for zstd dictionaries, as
+/// long as every dictionary values were written with is still added.
+#[derive(Clone)]
+pub struct Codec {
+    compression: Compression,
+    level: i32,
+    dictionaries: HashMap<u32, Arc<Dictionary>>,
+    /// The dictionary new zstd values are compressed with, and its contents.
+    dictionary: Option<(u32, Arc<[u8]>)>,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(Compression::None)
+    }
+}
+
+impl std::fmt::Debug for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Codec")
+            .field("compression", &self.compression)
+            .field("level", &self.level)
+            .field("dictionary", &self.dictionary.as_ref().map(|(id, _)| id))
+            .finish()
+    }
+}
+
+/// FNV-1a, identifying a dictionary by its contents.
+fn dictionary_id(dictionary: &[u8]) -> u32 {
+    dictionary.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x01000193)
+    })
+}
+
+fn compression_error(e: impl std::fmt::Display) -> StorefulError {
+    StorefulError::Compression(e.to_string())
+}
+
+impl Codec {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            dictionaries: HashMap::new(),
+            dictionary: None,
+        }
+    }
+
+    /// Sets the zstd compression level, higher is smaller but slower.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        // The current dictionary is prepared for a single level, so it is prepared again
+        if let Some((id, dictionary)) = &self.dictionary {
+            self.dictionaries
+                .insert(*id, Arc::new(Dictionary::new(dictionary, level)));
+        }
+        self
+    }
+
+    /// Compresses zstd values with `dictionary` from now on, such as one trained on log
+    /// messages. Dictionaries added before stay around to read what was written with them.
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> Self {
+        let id = dictionary_id(dictionary);
+        self.dictionaries
+            .insert(id, Arc::new(Dictionary::new(dictionary, self.level)));
+        self.dictionary = Some((id, dictionary.into()));
+        self
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// `value` compressed as configured, behind the tag `decode` reads it back by.
+    pub fn encode(&self, value: &[u8]) -> Result<Vec<u8>> {
+        let mut encoded = Vec::with_capacity(value.len() / 2 + 8);
+        match (
+            self.compression,
+            self.dictionary.as_ref().map(|(id, _)| *id),
+        ) {
+            (Compression::None, _) => {
+                encoded.push(RAW);
+                encoded.extend_from_slice(value);
+            }
+            (Compression::Lz4, _) => {
+                encoded.push(LZ4);
+                encoded.extend_from_slice(&lz4_flex::compress_prepend_size(value));
+            }
+            (Compression::Zstd, None) => {
+                encoded.push(ZSTD);
+                zstd::stream::copy_encode(value, &mut encoded, self.level)
+                    .map_err(compression_error)?;
+            }
+            (Compression::Zstd, Some(id)) => {
+                encoded.push(ZSTD_DICTIONARY);
+                encoded.extend_from_slice(&id.to_be_bytes());
+                let mut encoder = zstd::stream::Encoder::with_prepared_dictionary(
+                    &mut encoded,
+                    &self.dictionaries[&id].encoder,
+                )
+                .map_err(compression_error)?;
+                std::io::copy(&mut &value[..], &mut encoder).map_err(compression_error)?;
+                encoder.finish().map_err(compression_error)?;
+            }
+        }
+        Ok(encoded)
+    }
+
+    /// The value `encode` was given, whichever codec it was written with.
+    pub fn decode<'a>(&self, encoded: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let (&tag, value) = encoded
+            .split_first()
+            .ok_or(StorefulError::UnknownCodec(None))?;
+        match tag {
+            RAW => Ok(Cow::Borrowed(value)),
+            LZ4 => Ok(Cow::Owned(
+                lz4_flex::decompress_size_prepended(value).map_err(compression_error)?,
+            )),
+            ZSTD => Ok(Cow::Owned(
+                zstd::stream::decode_all(value).map_err(compression_error)?,
+            )),
+            ZSTD_DICTIONARY => {
+                let (id, value) = value
+                    .split_first_chunk::<4>()
+                    .ok_or(StorefulError::UnknownCodec(Some(tag)))?;
+                let id = u32::from_be_bytes(*id);
+                let dictionary = self
+                    .dictionaries
+                    .get(&id)
+                    .ok_or(StorefulError::UnknownDictionary(id))?;
+                let mut decoder =
+                    zstd::stream::Decoder::with_prepared_dictionary(value, &dictionary.decoder)
+                        .map_err(compression_error)?;
+                let mut decoded = Vec::new();
+                decoder
+                    .read_to_end(&mut decoded)
+                    .map_err(compression_error)?;
+                Ok(Cow::Owned(decoded))
+            }
+            tag => Err(StorefulError::UnknownCodec(Some(tag))),
+        }
+    }
+
+    /// Like `decode`, but takes the value as read from the backend so an uncompressed one is
+    /// used as is rather than copied.
+    pub fn decode_owned(&self, encoded: Box<[u8]>) -> Result<Decoded> {
+        let decompressed = match self.decode(&encoded)? {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(value) => Some(value),
+        };
+        Ok(match decompressed {
+            Some(value) => Decoded::Decompressed(value),
+            None => Decoded::Raw(encoded),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_values_of_every_codec() {
+        let value = "GET /index.html 200 ".repeat(32).into_bytes();
+        let dictionary = "GET /index.html 200 POST /login 302 "
+            .repeat(16)
+            .into_bytes();
+        let codecs = [
+            Codec::new(Compression::None),
+            Codec::new(Compression::Lz4),
+            Codec::new(Compression::Zstd).with_level(19),
+            Codec::new(Compression::Zstd).with_dictionary(&dictionary),
+        ];
+        // A codec set up differently still reads what any of them wrote
+        let reader = Codec::new(Compression::Lz4).with_dictionary(&dictionary);
+        for codec in &codecs {
+            let encoded = codec.encode(&value).unwrap();
+            if codec.compression() != Compression::None {
+                assert!(encoded.len() < value.len() / 4, "{:?}", codec);
+            }
+            assert_eq!(codec.decode(&encoded).unwrap(), value);
+            assert_eq!(reader.decode(&encoded).unwrap(), value);
+            let owned = reader.decode_owned(encoded.into()).unwrap();
+            assert_eq!(owned.as_ref(), value);
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_codecs() {
+        let with_dictionary = Codec::new(Compression::Zstd).with_dictionary(b"some dictionary");
+        let encoded = with_dictionary.encode(b"value").unwrap();
+        assert!(matches!(
+            Codec::default().decode(&encoded),
+            Err(StorefulError::UnknownDictionary(_))
+        ));
+        assert!(matches!(
+            Codec::default().decode(&[0x7f, 1, 2]),
+            Err(StorefulError::UnknownCodec(Some(0x7f)))
+        ));
+        assert!(Codec::default().decode(&[]).is_err());
+    }
+}
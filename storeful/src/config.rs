@@ -1,38 +1,48 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 
-use futures::future::join_all;
+use futures::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::sync::Mutex;
 
-use crate::{http, prelude::*, Args, ModelEndpoints, Query};
+use crate::{http, prelude::*, retention, Args, ModelEndpoints, Query, RetentionStatus};
 
 pub struct Config {
     pub host: String,
     pub port: u16,
     pub http: bool,
+    pub retention: Option<Duration>,
 }
 
 impl Config {
-    pub async fn start<T, Q, M>(&self, handler: Arc<Mutex<M>>) -> Result<()>
+    /// Set up by `args` for the model stored under `model`, e.g. `metrics`.
+    pub fn new(args: Args, model: &str) -> Self {
+        Self {
+            retention: args.retention(model),
+            host: args.host,
+            port: args.port,
+            http: args.http,
+        }
+    }
+
+    pub async fn start<T, Q, M>(&self, handler: Arc<M>) -> Result<()>
     where
         T: Send + Sync + Serialize + DeserializeOwned + 'static,
         Q: Query,
         M: ModelEndpoints<T, Q> + Send + Sync + 'static,
     {
-        let mut tasks = vec![];
+        let status = Arc::new(StdMutex::new(RetentionStatus::new(self.retention)));
+        let mut tasks: Vec<BoxFuture<'_, Result<()>>> = vec![];
         if self.http {
-            tasks.push(http::start(handler.clone(), &self.host, self.port));
+            tasks.push(http::start(handler.clone(), status.clone(), &self.host, self.port).boxed());
         }
-        join_all(tasks).await.into_iter().collect::<Result<()>>()
-    }
-}
-
-impl From<Args> for Config {
-    fn from(args: Args) -> Self {
-        Self {
-            host: args.host,
-            port: args.port,
-            http: args.http,
+        if let Some(retention) = self.retention {
+            tasks.push(retention::start(handler.clone(), retention, status).boxed());
         }
+        join_all(tasks).await.into_iter().collect::<Result<()>>()
     }
 }
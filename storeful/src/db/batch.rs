@@ -0,0 +1,113 @@
+use crate::Partition;
+
+/// A write to be applied as part of a `Batch`.
+#[derive(Debug, Clone)]
+pub enum BatchWrite {
+    Put {
+        partition: Partition,
+        key: Box<[u8]>,
+        value: Box<[u8]>,
+    },
+    Index {
+        partition: Partition,
+        index: String,
+        key: Box<[u8]>,
+        primary: Box<[u8]>,
+    },
+    Delete {
+        partition: Partition,
+        key: Box<[u8]>,
+    },
+    DeleteIndex {
+        partition: Partition,
+        index: String,
+        key: Box<[u8]>,
+    },
+}
+
+impl BatchWrite {
+    pub fn partition(&self) -> Partition {
+        match self {
+            BatchWrite::Put { partition, .. }
+            | BatchWrite::Index { partition, .. }
+            | BatchWrite::Delete { partition, .. }
+            | BatchWrite::DeleteIndex { partition, .. } => *partition,
+        }
+    }
+}
+
+/// Writes collected during a single call, applied atomically by `BackendDatabase::write_batch`.
+///
+/// Nothing reaches the backend until the batch is written, so a batch that is dropped, for
+/// example because an error returned early, simply discards its writes.
+#[derive(Debug, Default, Clone)]
+pub struct Batch {
+    writes: Vec<BatchWrite>,
+    /// Values of the store itself, written along with everything else.
+    metadata: Vec<(String, Box<[u8]>)>,
+}
+
+impl Batch {
+    pub fn put(&mut self, partition: Partition, key: &[u8], value: &[u8]) {
+        self.writes.push(BatchWrite::Put {
+            partition,
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    pub fn create_index(&mut self, partition: Partition, index: &str, primary: &[u8], key: &[u8]) {
+        self.writes.push(BatchWrite::Index {
+            partition,
+            index: index.into(),
+            key: key.into(),
+            primary: primary.into(),
+        });
+    }
+
+    pub fn delete(&mut self, partition: Partition, key: &[u8]) {
+        self.writes.push(BatchWrite::Delete {
+            partition,
+            key: key.into(),
+        });
+    }
+
+    pub fn delete_index(&mut self, partition: Partition, index: &str, key: &[u8]) {
+        self.writes.push(BatchWrite::DeleteIndex {
+            partition,
+            index: index.into(),
+            key: key.into(),
+        });
+    }
+
+    /// Sets the metadata `key` of the store, read back with `BackendDatabase::metadata`.
+    pub fn set_metadata(&mut self, key: &str, value: &[u8]) {
+        self.metadata.push((key.into(), value.into()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BatchWrite> {
+        self.writes.iter()
+    }
+
+    /// Takes the metadata set so far out of the batch, leaving the other writes.
+    pub fn take_metadata(&mut self) -> Vec<(String, Box<[u8]>)> {
+        std::mem::take(&mut self.metadata)
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len() + self.metadata.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty() && self.metadata.is_empty()
+    }
+}
+
+impl IntoIterator for Batch {
+    type Item = BatchWrite;
+    type IntoIter = std::vec::IntoIter<BatchWrite>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.writes.into_iter()
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use tokio::{sync::mpsc::Sender, task};
+
+use crate::{prelude::*, BackendDatabase, Expired, IndexQuery, Page, Sampled, Storeable, Storeful};
+
+/// A `Storeful` whose backend calls run on tokio's blocking pool.
+///
+/// Backends do their IO synchronously. Running it here instead of on the runtime's workers
+/// means a large scan only ever ties up a blocking thread, while requests served from the same
+/// runtime carry on.
+pub struct BlockingStoreful<B>
+where
+    B: BackendDatabase + Send + Sync + 'static,
+{
+    storeful: Arc<Storeful<B>>,
+}
+
+impl<B> Clone for BlockingStoreful<B>
+where
+    B: BackendDatabase + Send + Sync + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            storeful: self.storeful.clone(),
+        }
+    }
+}
+
+impl<B> BlockingStoreful<B>
+where
+    B: BackendDatabase + Send + Sync + 'static,
+{
+    pub fn new(storeful: Storeful<B>) -> Self {
+        Self {
+            storeful: Arc::new(storeful),
+        }
+    }
+
+    /// Runs `f` on the blocking pool.
+    pub async fn run<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Storeful<B>) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let storeful = self.storeful.clone();
+        task::spawn_blocking(move || f(&storeful)).await?
+    }
+
+    pub async fn post<T: Storeable + 'static>(&self, records: Vec<T>) -> Result<()> {
+        self.run(move |storeful| storeful.post(records)).await
+    }
+
+    pub async fn query_page<T, Q>(&self, query: Q) -> Result<Page<T>>
+    where
+        T: Storeable + 'static,
+        Q: IndexQuery + Send + 'static,
+    {
+        self.run(move |storeful| storeful.query_page(&query)).await
+    }
+
+    /// Sends everything matching `query` to `results`, stopping once the receiver is dropped.
+    ///
+    /// A slow receiver holds up the scan rather than having results pile up in memory.
+    pub async fn query_stream<T, Q>(&self, query: Q, results: Sender<T>) -> Result<()>
+    where
+        T: Storeable + 'static,
+        Q: IndexQuery + Send + 'static,
+    {
+        self.run(move |storeful| {
+            for record in storeful.query_iter(&query)? {
+                if results.blocking_send(record?).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn delete<T, Q>(&self, query: Q) -> Result<usize>
+    where
+        T: Storeable + 'static,
+        Q: IndexQuery + Send + 'static,
+    {
+        self.run(move |storeful| storeful.delete::<T, Q>(&query))
+            .await
+    }
+
+    pub async fn expire<T: Storeable + 'static>(&self, before: i64) -> Result<Expired> {
+        self.run(move |storeful| storeful.expire::<T>(before)).await
+    }
+
+    pub async fn migrate<T: Storeable + 'static>(&self) -> Result<usize> {
+        self.run(move |storeful| storeful.migrate::<T>()).await
+    }
+
+    pub async fn create_label_index<T: Storeable + 'static>(&self, key: String) -> Result<usize> {
+        self.run(move |storeful| storeful.create_label_index::<T>(&key))
+            .await
+    }
+
+    pub async fn drop_label_index(&self, key: String) -> Result<()> {
+        self.run(move |storeful| storeful.drop_label_index(&key))
+            .await
+    }
+
+    pub async fn post_samples<T: Sampled + 'static>(&self, records: Vec<T>) -> Result<()> {
+        self.run(move |storeful| storeful.post_samples(records))
+            .await
+    }
+
+    pub async fn query_sample_page<T, Q>(&self, query: Q) -> Result<Page<T>>
+    where
+        T: Sampled + 'static,
+        Q: IndexQuery + Send + 'static,
+    {
+        self.run(move |storeful| storeful.query_sample_page(&query))
+            .await
+    }
+
+    /// Sends every sample matching `query` to `results`, like `query_stream`.
+    pub async fn query_sample_stream<T, Q>(&self, query: Q, results: Sender<T>) -> Result<()>
+    where
+        T: Sampled + 'static,
+        Q: IndexQuery + Send + 'static,
+    {
+        self.run(move |storeful| {
+            for record in storeful.query_samples_iter(&query)? {
+                if results.blocking_send(record?).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn delete_samples<Q>(&self, query: Q) -> Result<usize>
+    where
+        Q: IndexQuery + Send + 'static,
+    {
+        self.run(move |storeful| storeful.delete_samples(&query))
+            .await
+    }
+
+    pub async fn expire_samples(&self, before: i64) -> Result<Expired> {
+        self.run(move |storeful| storeful.expire_samples(before))
+            .await
+    }
+
+    pub async fn migrate_series(&self) -> Result<usize> {
+        self.run(move |storeful| storeful.migrate_series()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{memory::MemoryBackend, IndexValue, Key, Versioned};
+
+    #[derive(Serialize, Deserialize)]
+    struct Event {
+        timestamp: i64,
+    }
+
+    impl Versioned for Event {}
+
+    impl Storeable for Event {
+        const INDEXES: &'static [&'static str] = &[];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+    }
+
+    struct All;
+
+    impl IndexQuery for All {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_without_holding_up_writes() {
+        let storeful = BlockingStoreful::new(Storeful::new(MemoryBackend::new(
+            "test".into(),
+            &Event::trees(),
+        )));
+        let events = (0..1000).map(|timestamp| Event { timestamp }).collect();
+        storeful.post(events).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let stream = tokio::spawn({
+            let storeful = storeful.clone();
+            async move { storeful.query_stream::<Event, _>(All, tx).await }
+        });
+        // The stream now waits on this receiver, which a write doesn't have to
+        assert_eq!(rx.recv().await.unwrap().timestamp, 0);
+        let post = storeful.post(vec![Event { timestamp: 1000 }]);
+        tokio::time::timeout(Duration::from_secs(10), post)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut streamed = 1;
+        while rx.recv().await.is_some() {
+            streamed += 1;
+        }
+        stream.await.unwrap().unwrap();
+        assert!(streamed >= 1000);
+    }
+
+    #[tokio::test]
+    async fn leaves_the_runtime_free() {
+        let storeful = BlockingStoreful::new(Storeful::new(MemoryBackend::new("test".into(), &[])));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let blocked = storeful.run(move |_| Ok(rx.recv().is_ok()));
+        // The test runtime has a single thread, so this only runs if `blocked` isn't on it
+        let unblock = async move { tx.send(()).unwrap() };
+        let (received, ()) = tokio::join!(blocked, unblock);
+        assert!(received.unwrap());
+    }
+}
@@ -0,0 +1,481 @@
+use crate::{
+    half_open, prelude::*, BackendDatabase, Batch, BatchWrite, Entries, IndexEntry, Key, Order,
+    Partition, Primaries,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+    sync::RwLock,
+};
+
+type Tree = BTreeMap<Box<[u8]>, Box<[u8]>>;
+
+/// Where in a tree a scan has left to read.
+type Range = (Bound<Box<[u8]>>, Bound<Box<[u8]>>);
+
+/// How many entries a scan copies out each time it takes the lock.
+const SCAN_CHUNK: usize = 256;
+
+/// The primaries and index trees of a single partition.
+struct MemoryPartition {
+    primaries: Tree,
+    indexes: HashMap<String, Tree>,
+}
+
+impl MemoryPartition {
+    fn new(tree_names: &BTreeSet<String>) -> Self {
+        Self {
+            primaries: Tree::new(),
+            indexes: tree_names
+                .iter()
+                .map(|tree_name| (tree_name.to_string(), Tree::new()))
+                .collect(),
+        }
+    }
+}
+
+/// A `BackendDatabase` kept entirely in ordered in-memory maps.
+///
+/// Nothing is persisted, which makes it suited to tests and short-lived instances. Scans copy
+/// what they match out from under the lock a chunk at a time, rather than holding it while they
+/// are read.
+pub struct MemoryBackend {
+    tree_names: RwLock<BTreeSet<String>>,
+    partitions: RwLock<BTreeMap<Partition, MemoryPartition>>,
+    metadata: RwLock<HashMap<String, Box<[u8]>>>,
+}
+
+impl MemoryBackend {
+    /// Nothing is shared between instances, so `master_key` is only there to match the other
+    /// backends.
+    pub fn new(_master_key: String, tree_names: &[&'static str]) -> Self {
+        Self {
+            tree_names: RwLock::new(tree_names.iter().map(|tree| tree.to_string()).collect()),
+            partitions: RwLock::new(BTreeMap::new()),
+            metadata: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn check_tree(&self, tree: &str) -> Result<()> {
+        if self.tree_names.read()?.contains(tree) {
+            Ok(())
+        } else {
+            Err(StorefulError::IndexNotFound(tree.into()))
+        }
+    }
+
+    /// Runs `f` on the `tree` index of `partition`, `None` if nothing was written to it yet.
+    fn read_tree<R>(
+        &self,
+        partition: Partition,
+        tree: &str,
+        f: impl FnOnce(&Tree) -> R,
+    ) -> Result<Option<R>> {
+        self.check_tree(tree)?;
+        let partitions = self.partitions.read()?;
+        let Some(partition) = partitions.get(&partition) else {
+            return Ok(None);
+        };
+        // Dropped since it was checked
+        let tree = partition
+            .indexes
+            .get(tree)
+            .ok_or_else(|| StorefulError::IndexNotFound(tree.into()))?;
+        Ok(Some(f(tree)))
+    }
+}
+
+/// Entries of a tree within a range, read a chunk at a time.
+///
+/// Each chunk resumes past the last key of the one before, so writes in between show up in
+/// whatever is left of the range, just as they would for the other backends.
+struct Scan<'a> {
+    backend: &'a MemoryBackend,
+    partition: Partition,
+    tree: String,
+    range: Range,
+    /// Only entries starting with it are read, the scan ends at the first that doesn't.
+    prefix: Box<[u8]>,
+    order: Order,
+    chunk: std::vec::IntoIter<IndexEntry>,
+    done: bool,
+}
+
+impl<'a> Scan<'a> {
+    fn new(
+        backend: &'a MemoryBackend,
+        partition: Partition,
+        tree: &str,
+        range: Range,
+        prefix: &[u8],
+        order: Order,
+    ) -> Self {
+        Self {
+            backend,
+            partition,
+            tree: tree.into(),
+            range,
+            prefix: prefix.into(),
+            order,
+            chunk: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<Vec<IndexEntry>> {
+        let chunk = self.backend.read_tree(self.partition, &self.tree, |tree| {
+            let range = tree.range::<[u8], _>((
+                self.range.0.as_ref().map(|key| &**key),
+                self.range.1.as_ref().map(|key| &**key),
+            ));
+            match self.order {
+                Order::Ascending => copy_chunk(range, &self.prefix),
+                Order::Descending => copy_chunk(range.rev(), &self.prefix),
+            }
+        })?;
+        let chunk = chunk.unwrap_or_default();
+        match chunk.last() {
+            Some((last, _)) if chunk.len() == SCAN_CHUNK => match self.order {
+                Order::Ascending => self.range.0 = Bound::Excluded(last.clone()),
+                Order::Descending => self.range.1 = Bound::Excluded(last.clone()),
+            },
+            _ => self.done = true,
+        }
+        Ok(chunk)
+    }
+}
+
+/// Copies out the next chunk of `entries` that start with `prefix`.
+fn copy_chunk<'a>(
+    entries: impl Iterator<Item = (&'a Box<[u8]>, &'a Box<[u8]>)>,
+    prefix: &[u8],
+) -> Vec<IndexEntry> {
+    entries
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .take(SCAN_CHUNK)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<IndexEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.chunk.next() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_chunk() {
+                Ok(chunk) => self.chunk = chunk.into_iter(),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl BackendDatabase for MemoryBackend {
+    fn write_batch(&self, mut batch: Batch) -> Result<()> {
+        let mut partitions = self.partitions.write()?;
+        let tree_names = self.tree_names.read()?;
+        // Check every tree up front so a bad write leaves nothing half applied, under the locks
+        // the batch is applied with so no tree can be dropped in between
+        for write in batch.iter() {
+            if let BatchWrite::Index { index, .. } | BatchWrite::DeleteIndex { index, .. } = write {
+                if !tree_names.contains(index) {
+                    return Err(StorefulError::IndexNotFound(index.clone()));
+                }
+            }
+        }
+        self.metadata.write()?.extend(batch.take_metadata());
+        for write in batch {
+            let partition = partitions
+                .entry(write.partition())
+                .or_insert_with(|| MemoryPartition::new(&tree_names));
+            match write {
+                BatchWrite::Put { key, value, .. } => {
+                    partition.primaries.insert(key, value);
+                }
+                BatchWrite::Index {
+                    index,
+                    key,
+                    primary,
+                    ..
+                } => {
+                    if let Some(tree) = partition.indexes.get_mut(&index) {
+                        tree.insert(key, primary);
+                    }
+                }
+                BatchWrite::Delete { key, .. } => {
+                    partition.primaries.remove(&key);
+                }
+                BatchWrite::DeleteIndex { index, key, .. } => {
+                    if let Some(tree) = partition.indexes.get_mut(&index) {
+                        tree.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, partition: Partition, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        Ok(self
+            .partitions
+            .read()?
+            .get(&partition)
+            .and_then(|partition| partition.primaries.get(key).cloned()))
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<Box<[u8]>>> {
+        Ok(self.metadata.read()?.get(key).cloned())
+    }
+
+    fn get_multi(&self, partition: Partition, keys: &[Box<[u8]>]) -> Result<Vec<Box<[u8]>>> {
+        let partitions = self.partitions.read()?;
+        let Some(partition) = partitions.get(&partition) else {
+            return Ok(Vec::new());
+        };
+        Ok(keys
+            .iter()
+            .filter_map(|key| partition.primaries.get(key).cloned())
+            .collect())
+    }
+
+    fn scan_range(
+        &self,
+        partition: Partition,
+        tree: &str,
+        (lower, upper): (Bound<Key>, Bound<Key>),
+        order: Order,
+    ) -> Result<Primaries<'_>> {
+        self.check_tree(tree)?;
+        // BTreeMap panics on inverted ranges rather than yielding nothing
+        let Some((lower, upper)) = half_open((lower, upper)) else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let range = (
+            lower.map(|key| key.as_bytes().into()),
+            upper.map(|key| key.as_bytes().into()),
+        );
+        let scan = Scan::new(self, partition, tree, range, &[], order);
+        Ok(Box::new(scan.map(|entry| entry.map(|(_, value)| value))))
+    }
+
+    fn get_index(&self, partition: Partition, tree: &str, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        Ok(self
+            .read_tree(partition, tree, |tree| tree.get(key).cloned())?
+            .flatten())
+    }
+
+    fn scan_index(
+        &self,
+        partition: Partition,
+        tree: &str,
+        index_key: &[u8],
+    ) -> Result<Primaries<'_>> {
+        Ok(Box::new(
+            self.scan_index_entries(partition, tree, index_key)?
+                .map(|entry| entry.map(|(_, value)| value)),
+        ))
+    }
+
+    fn scan_index_entries(
+        &self,
+        partition: Partition,
+        tree: &str,
+        index_key: &[u8],
+    ) -> Result<Entries<'_>> {
+        self.check_tree(tree)?;
+        let range = (Bound::Included(index_key.into()), Bound::Unbounded);
+        Ok(Box::new(Scan::new(
+            self,
+            partition,
+            tree,
+            range,
+            index_key,
+            Order::Ascending,
+        )))
+    }
+
+    fn contains_index(&self, partition: Partition, tree: &str, key: &[u8]) -> Result<bool> {
+        Ok(self
+            .read_tree(partition, tree, |tree| tree.contains_key(key))?
+            .unwrap_or(false))
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        Ok(self.tree_names.read()?.iter().cloned().collect())
+    }
+
+    fn create_tree(&self, tree: &str) -> Result<()> {
+        let mut partitions = self.partitions.write()?;
+        if !self.tree_names.write()?.insert(tree.into()) {
+            return Ok(());
+        }
+        for partition in partitions.values_mut() {
+            partition.indexes.insert(tree.into(), Tree::new());
+        }
+        Ok(())
+    }
+
+    fn drop_tree(&self, tree: &str) -> Result<()> {
+        let mut partitions = self.partitions.write()?;
+        if !self.tree_names.write()?.remove(tree) {
+            return Err(StorefulError::IndexNotFound(tree.into()));
+        }
+        for partition in partitions.values_mut() {
+            partition.indexes.remove(tree);
+        }
+        Ok(())
+    }
+
+    fn partitions(&self) -> Result<Vec<Partition>> {
+        Ok(self.partitions.read()?.keys().copied().collect())
+    }
+
+    fn drop_partition(&self, partition: Partition) -> Result<()> {
+        self.partitions.write()?.remove(&partition);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TIMESTAMP_INDEX;
+
+    fn primary(backend: &MemoryBackend, partition: i64, key: &[u8]) -> Option<Box<[u8]>> {
+        backend.get(Partition(partition), key).unwrap()
+    }
+
+    #[test]
+    fn keeps_partitions_apart() {
+        let logs = MemoryBackend::new("logs".into(), &[TIMESTAMP_INDEX, "context"]);
+
+        // The same keys in the same trees of each partition
+        for (partition, value) in [(0, &b"old"[..]), (1, &b"new"[..])] {
+            let mut batch = Batch::default();
+            batch.put(Partition(partition), b"key", value);
+            batch.create_index(Partition(partition), "context", value, b"index");
+            logs.write_batch(batch).unwrap();
+        }
+        assert_eq!(primary(&logs, 0, b"key").as_deref(), Some(&b"old"[..]));
+        assert_eq!(primary(&logs, 1, b"key").as_deref(), Some(&b"new"[..]));
+        assert_eq!(logs.partitions().unwrap(), [Partition(0), Partition(1)]);
+        let indexed = |partition| {
+            logs.get_index(Partition(partition), "context", b"index")
+                .unwrap()
+        };
+        assert_eq!(indexed(1).as_deref(), Some(&b"new"[..]));
+
+        // Keys that are missing are left out rather than failing the rest
+        let keys = [b"key".to_vec().into(), b"missing".to_vec().into()];
+        assert_eq!(logs.get_multi(Partition(1), &keys).unwrap().len(), 1);
+
+        // Dropping a partition leaves the others alone
+        logs.drop_partition(Partition(0)).unwrap();
+        assert_eq!(primary(&logs, 0, b"key"), None);
+        assert_eq!(indexed(0), None);
+        assert_eq!(indexed(1).as_deref(), Some(&b"new"[..]));
+        assert_eq!(logs.partitions().unwrap(), [Partition(1)]);
+
+        let mut batch = Batch::default();
+        batch.delete(Partition(1), b"key");
+        batch.delete_index(Partition(1), "context", b"index");
+        logs.write_batch(batch).unwrap();
+        assert_eq!(primary(&logs, 1, b"key"), None);
+        assert_eq!(indexed(1), None);
+    }
+
+    #[test]
+    fn keeps_created_trees() {
+        let logs = MemoryBackend::new("logs".into(), &[TIMESTAMP_INDEX]);
+        let mut batch = Batch::default();
+        batch.put(Partition(0), b"key", b"log");
+        logs.write_batch(batch).unwrap();
+
+        // Partitions written before the tree was created get it as well
+        logs.create_tree("label:service.name").unwrap();
+        let mut batch = Batch::default();
+        batch.create_index(Partition(0), "label:service.name", b"key", b"api");
+        logs.write_batch(batch).unwrap();
+        assert_eq!(
+            logs.trees().unwrap(),
+            ["label:service.name", TIMESTAMP_INDEX]
+        );
+        assert!(logs
+            .contains_index(Partition(0), "label:service.name", b"api")
+            .unwrap());
+
+        logs.drop_tree("label:service.name").unwrap();
+        let mut batch = Batch::default();
+        batch.create_index(Partition(0), "label:service.name", b"key", b"api");
+        assert!(matches!(
+            logs.write_batch(batch),
+            Err(StorefulError::IndexNotFound(_))
+        ));
+        assert!(matches!(
+            logs.contains_index(Partition(0), "label:service.name", b"api"),
+            Err(StorefulError::IndexNotFound(_))
+        ));
+        assert_eq!(logs.trees().unwrap(), [TIMESTAMP_INDEX]);
+        assert!(logs.drop_tree("label:service.name").is_err());
+    }
+
+    #[test]
+    fn refused_batches_leave_no_partitions() {
+        let logs = MemoryBackend::new("logs".into(), &[TIMESTAMP_INDEX]);
+        let mut batch = Batch::default();
+        batch.put(Partition(1), b"key", b"log");
+        batch.create_index(Partition(2), "unknown", b"key", b"index");
+        assert!(matches!(
+            logs.write_batch(batch),
+            Err(StorefulError::IndexNotFound(_))
+        ));
+        assert!(logs.partitions().unwrap().is_empty());
+        assert_eq!(primary(&logs, 1, b"key"), None);
+    }
+
+    #[test]
+    fn scans_past_a_chunk() {
+        let backend = MemoryBackend::new("test".into(), &["index"]);
+        let mut batch = Batch::default();
+        for i in 0..SCAN_CHUNK as u16 * 2 + 1 {
+            let key = [&b"a"[..], &i.to_be_bytes()].concat();
+            batch.create_index(Partition(0), "index", &i.to_be_bytes(), &key);
+        }
+        batch.create_index(Partition(0), "index", b"b", b"b");
+        backend.write_batch(batch).unwrap();
+        let read = |primaries: Primaries<'_>| {
+            primaries
+                .map(|value| u16::from_be_bytes((*value.unwrap()).try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        let all: Vec<u16> = (0..SCAN_CHUNK as u16 * 2 + 1).collect();
+        let prefixed = backend.scan_index(Partition(0), "index", b"a").unwrap();
+        assert_eq!(read(prefixed), all);
+        let range = (
+            Bound::Included(Key::new().with_key(b"a")),
+            Bound::Excluded(Key::new().with_key(b"b")),
+        );
+        let descending = backend
+            .scan_range(Partition(0), "index", range, Order::Descending)
+            .unwrap();
+        assert_eq!(read(descending), all.into_iter().rev().collect::<Vec<_>>());
+
+        // Writes between chunks show up in what is left of the scan
+        let mut scan = backend.scan_index(Partition(0), "index", b"a").unwrap();
+        scan.next().unwrap().unwrap();
+        let mut batch = Batch::default();
+        batch.create_index(Partition(0), "index", &u16::MAX.to_be_bytes(), b"a\xff\xff");
+        backend.write_batch(batch).unwrap();
+        assert_eq!(read(scan).last(), Some(&u16::MAX));
+    }
+}
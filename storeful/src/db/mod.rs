@@ -1,40 +1,154 @@
-use std::collections::HashSet;
+use std::{ops::Bound, time::Duration};
 
-use crate::prelude::*;
+use crate::{prelude::*, resume_after, timestamp_index_range, Key, Order, TIMESTAMP_INDEX};
 
-// pub mod rocksdb;
+mod batch;
+mod blocking;
+pub mod memory;
+mod page;
+mod partition;
+mod planner;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb;
+mod series;
 pub mod sled;
+mod storeful;
 
+pub use batch::*;
+pub use blocking::*;
+pub use page::*;
+pub use partition::*;
+pub use planner::*;
+pub use series::*;
+pub use storeful::*;
+
+/// Primaries read from a backend one at a time, as the iterator advances.
+pub type Primaries<'a> = Box<dyn Iterator<Item = Result<Box<[u8]>>> + Send + 'a>;
+/// The key and value of an index entry.
+pub type IndexEntry = (Box<[u8]>, Box<[u8]>);
+/// Index entries read from a backend one at a time, as the iterator advances.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<IndexEntry>> + Send + 'a>;
+
+/// How much time a partition covers unless configured otherwise.
+pub const DEFAULT_PARTITION_WIDTH: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A store of partitioned primaries and indexes.
+///
+/// Every method takes `&self`, so reads and writes from many threads can run at once.
 pub trait BackendDatabase {
-    fn start_batch(&mut self) -> Result<()>;
-    fn commit_batch(&mut self) -> Result<()>;
+    /// Applies every write in `batch`, or none of them, creating partitions as needed.
+    fn write_batch(&self, batch: Batch) -> Result<()>;
 
-    fn put(&mut self, key: &str, value: &[u8]) -> Result<()>;
-    fn get(&self, key: &str) -> Result<Option<Box<[u8]>>>;
-    fn get_multi(&self, keys: &HashSet<Box<[u8]>>) -> Result<Vec<Box<[u8]>>>;
+    fn get(&self, partition: Partition, key: &[u8]) -> Result<Option<Box<[u8]>>>;
+    /// The metadata `key` of the store, as last set by `Batch::set_metadata`.
+    fn metadata(&self, key: &str) -> Result<Option<Box<[u8]>>>;
+    /// The values of `keys` that exist, in the same order.
+    fn get_multi(&self, partition: Partition, keys: &[Box<[u8]>]) -> Result<Vec<Box<[u8]>>>;
 
-    fn create_index(&mut self, cf: &str, primary: &str, key: &str) -> Result<()>;
+    /// Values of the entries of `cf` with a key in `range`, sorted by key in `order`.
+    fn scan_range(
+        &self,
+        partition: Partition,
+        cf: &str,
+        range: (Bound<Key>, Bound<Key>),
+        order: Order,
+    ) -> Result<Primaries<'_>>;
+    /// The value of the entry of `cf` at exactly `key`.
+    fn get_index(&self, partition: Partition, cf: &str, key: &[u8]) -> Result<Option<Box<[u8]>>>;
+
+    /// Primaries with a timestamp in `timestamp_start..=timestamp_end`, sorted by timestamp.
+    ///
+    /// With `after`, the scan resumes past that timestamp index key in `order`.
+    fn scan_timestamp_index(
+        &self,
+        partition: Partition,
+        timestamp_start: Option<i64>,
+        timestamp_end: Option<i64>,
+        order: Order,
+        after: Option<&[u8]>,
+    ) -> Result<Primaries<'_>> {
+        let range = timestamp_index_range(timestamp_start, timestamp_end)?;
+        match resume_after(range, order, after) {
+            Some(range) => self.scan_range(partition, TIMESTAMP_INDEX, range, order),
+            None => Ok(Box::new(std::iter::empty())),
+        }
+    }
+    /// Primaries of the entries of `cf` starting with `index_key`, sorted by key.
+    fn scan_index(&self, partition: Partition, cf: &str, index_key: &[u8])
+        -> Result<Primaries<'_>>;
+    /// Like `scan_index`, along with the key of every entry.
+    fn scan_index_entries(
+        &self,
+        partition: Partition,
+        cf: &str,
+        index_key: &[u8],
+    ) -> Result<Entries<'_>>;
 
     fn query_timestamp_index(
         &self,
+        partition: Partition,
         timestamp_start: Option<i64>,
         timestamp_end: Option<i64>,
-    ) -> Result<HashSet<Box<[u8]>>>;
-    fn query_index(&self, cf: &str, index_key: &str) -> Result<HashSet<Box<[u8]>>>;
-}
+        order: Order,
+    ) -> Result<Vec<Box<[u8]>>> {
+        self.scan_timestamp_index(partition, timestamp_start, timestamp_end, order, None)?
+            .collect()
+    }
+    /// How many timestamp index entries fall in the range, counting no further than `limit`.
+    fn count_timestamp_index(
+        &self,
+        partition: Partition,
+        timestamp_start: Option<i64>,
+        timestamp_end: Option<i64>,
+        limit: usize,
+    ) -> Result<usize> {
+        let primaries = self.scan_timestamp_index(
+            partition,
+            timestamp_start,
+            timestamp_end,
+            Order::default(),
+            None,
+        )?;
+        count(primaries, limit)
+    }
 
-pub struct Storeful<B>
-where
-    B: BackendDatabase + Send + Sync,
-{
-    pub backend: B,
+    fn query_index(
+        &self,
+        partition: Partition,
+        cf: &str,
+        index_key: &[u8],
+    ) -> Result<Vec<Box<[u8]>>> {
+        self.scan_index(partition, cf, index_key)?.collect()
+    }
+    /// How many entries of `cf` start with `index_key`, counting no further than `limit`.
+    fn count_index(
+        &self,
+        partition: Partition,
+        cf: &str,
+        index_key: &[u8],
+        limit: usize,
+    ) -> Result<usize> {
+        count(self.scan_index(partition, cf, index_key)?, limit)
+    }
+    /// Whether `cf` holds an entry for exactly `key`.
+    fn contains_index(&self, partition: Partition, cf: &str, key: &[u8]) -> Result<bool>;
+
+    /// The index trees every partition has, those passed when opening the backend and those
+    /// created since.
+    fn trees(&self) -> Result<Vec<String>>;
+    /// Adds the index tree `tree` to every partition, existing and future ones.
+    fn create_tree(&self, tree: &str) -> Result<()>;
+    /// Removes the index tree `tree` along with its entries from every partition.
+    fn drop_tree(&self, tree: &str) -> Result<()>;
+
+    /// Every partition that has been written to, oldest first.
+    fn partitions(&self) -> Result<Vec<Partition>>;
+    /// Removes `partition` along with all of its primaries and index entries.
+    fn drop_partition(&self, partition: Partition) -> Result<()>;
 }
 
-impl<B> Storeful<B>
-where
-    B: BackendDatabase + Send + Sync,
-{
-    pub fn new(backend: B) -> Self {
-        Self { backend }
-    }
+fn count(primaries: Primaries<'_>, limit: usize) -> Result<usize> {
+    primaries
+        .take(limit)
+        .try_fold(0, |count, primary| primary.map(|_| count + 1))
 }
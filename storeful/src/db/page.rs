@@ -0,0 +1,118 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    prelude::*, timestamp_index_prefix, Key, KeyComponent, Order, Storeable, TIMESTAMP_INDEX,
+};
+
+/// The results of a query with a `limit`, and where to continue from if there may be more.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub records: Vec<T>,
+    /// Passed back with the same query to get the next page, `None` once there are no more.
+    pub continuation: Option<String>,
+}
+
+/// Where a record sorts in timestamp order, its key in the timestamp index.
+///
+/// Records with the same timestamp are ordered by primary, so every record has a position of
+/// its own and pages never overlap or skip any.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    key: Key,
+    timestamp: i64,
+}
+
+impl Position {
+    pub fn of<T: Storeable>(record: &T) -> Self {
+        Self::new(record.timestamp(), &record.primary())
+    }
+
+    /// The position of whatever `primary` identifies, at `timestamp`.
+    pub fn new(timestamp: i64, primary: &Key) -> Self {
+        Self {
+            key: timestamp_index_prefix(timestamp).with_key(primary.as_bytes()),
+            timestamp,
+        }
+    }
+
+    /// The components of the primary the position was made with.
+    pub fn primary(&self) -> Result<Vec<KeyComponent>> {
+        let mut components = Key::decode(self.key.as_bytes())?;
+        Ok(components.split_off(2))
+    }
+
+    /// Reads a position back from a continuation token made by `token`.
+    pub fn parse(token: &str) -> Result<Self> {
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| {
+                token
+                    .get(i..i + 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(StorefulError::InvalidContinuation)?;
+        let components = Key::decode(&bytes).map_err(|_| StorefulError::InvalidContinuation)?;
+        match components.as_slice() {
+            [KeyComponent::String(index), KeyComponent::Integer(timestamp), _, ..]
+                if index == TIMESTAMP_INDEX =>
+            {
+                Ok(Self {
+                    timestamp: *timestamp,
+                    key: Key::new().with_key(&bytes),
+                })
+            }
+            _ => Err(StorefulError::InvalidContinuation),
+        }
+    }
+
+    /// The opaque continuation token handed out to clients.
+    pub fn token(&self) -> String {
+        self.key
+            .as_bytes()
+            .iter()
+            .fold(String::new(), |mut token, byte| {
+                let _ = write!(token, "{:02x}", byte);
+                token
+            })
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.key.as_bytes()
+    }
+
+    /// Whether a scan in `order` reaches this position after `other`.
+    pub fn is_past(&self, other: &Position, order: Order) -> bool {
+        match order {
+            Order::Ascending => self > other,
+            Order::Descending => self < other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_round_trip() {
+        let position = Position {
+            key: timestamp_index_prefix(-42).with_key(Key::new().with_str("cpu").as_bytes()),
+            timestamp: -42,
+        };
+        assert_eq!(Position::parse(&position.token()).unwrap(), position);
+    }
+
+    #[test]
+    fn rejects_foreign_tokens() {
+        for token in ["", "0", "zz", "0263707500"] {
+            assert!(Position::parse(token).is_err(), "{}", token);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::fmt::Display;
+
+use crate::prelude::*;
+
+/// A block of time whose records live in their own set of trees, so it can be dropped at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Partition(pub i64);
+
+/// `19700`
+impl Display for Partition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Partition {
+    /// `metrics@19700`, the tree holding the primaries of this partition.
+    pub fn primary_tree(&self, master_key: &str) -> String {
+        format!("{}@{}", master_key, self)
+    }
+
+    /// `metrics@19700:timestamp`, the tree holding `index` for this partition.
+    pub fn index_tree(&self, master_key: &str, index: &str) -> String {
+        format!("{}@{}:{}", master_key, self, index)
+    }
+
+    /// `metrics:metadata`, the tree holding the metadata of the store of `master_key`.
+    ///
+    /// Store names can't contain ':', so it can't be taken for a tree of any other store.
+    pub fn metadata_tree(master_key: &str) -> String {
+        format!("{}:metadata", master_key)
+    }
+
+    /// Refuses names that could make the trees of one store pass for those of another.
+    pub fn check_master_key(master_key: &str) -> Result<()> {
+        if master_key.is_empty() || master_key.contains(['@', ':']) {
+            return Err(StorefulError::InvalidMasterKey(master_key.into()));
+        }
+        Ok(())
+    }
+
+    /// The partition a tree created by `primary_tree` or `index_tree` belongs to.
+    pub fn from_tree(master_key: &str, tree_name: &str) -> Option<Self> {
+        let rest = tree_name.strip_prefix(master_key)?.strip_prefix('@')?;
+        let partition = rest.split(':').next()?;
+        partition.parse().ok().map(Partition)
+    }
+
+    /// The index a tree created by `index_tree` holds, `None` for other trees.
+    pub fn index_from_tree<'a>(master_key: &str, tree_name: &'a str) -> Option<&'a str> {
+        let rest = tree_name.strip_prefix(master_key)?.strip_prefix('@')?;
+        let (partition, index) = rest.split_once(':')?;
+        partition.parse::<i64>().ok()?;
+        Some(index)
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+
+use crate::{
+    label_index, prelude::*, BackendDatabase, IndexQuery, IndexValue, Key, KeyComponent,
+    LabelFilters, LabelMatcher, Partition,
+};
+
+/// How far index entries are counted when estimating how selective a filter is.
+///
+/// Anything past this is considered equally broad, which keeps planning cheap next to a query
+/// that has to read at least that many entries anyway.
+const ESTIMATE_LIMIT: usize = 1024;
+
+/// Where a query gets its candidate primaries from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// The timestamp index, over the range of the query.
+    Timestamp,
+    /// The entries of an index for this value, each followed by the primary it points to.
+    Index(IndexValue),
+}
+
+/// How a query is answered within a single partition.
+///
+/// Only the most selective access is scanned. Every candidate it yields is then checked against
+/// the other index filters with a point lookup of `value ++ primary`, so broad filters are never
+/// read in full. Label matchers answered by an index join those filters, or rule candidates out
+/// the same way. Other matchers on a label with a label index are resolved from all of its
+/// entries in the partition up front, so candidates are checked against them without reading
+/// their records.
+///
+/// Records found through any index but the timestamp one have to be sorted, and so held in
+/// memory. Without a limit to keep that down, an index only drives the query while it holds
+/// fewer than `ESTIMATE_LIMIT` entries, otherwise the timestamp index streams the partition in
+/// order instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub driver: Access,
+    pub probes: Vec<IndexValue>,
+    /// Entries candidates must not have.
+    pub excludes: Vec<IndexValue>,
+    /// Whether candidates still have to be checked against the timestamp range of the query.
+    pub check_timestamp: bool,
+    /// How many entries the driver was counted to have, up to `ESTIMATE_LIMIT`.
+    pub estimate: usize,
+    /// What candidates must be among, for the matchers resolved from a label index.
+    pub labels: Vec<Resolved>,
+}
+
+impl Plan {
+    pub fn new<B, Q>(
+        backend: &B,
+        partition: Partition,
+        query: &Q,
+        filters: &LabelFilters,
+    ) -> Result<Self>
+    where
+        B: BackendDatabase,
+        Q: IndexQuery,
+    {
+        let has_range = query.timestamp_start().is_some() || query.timestamp_end().is_some();
+        let mut index_values = query.index_values();
+        index_values.extend(filters.include.iter().cloned());
+        let count_timestamps = || {
+            backend.count_timestamp_index(
+                partition,
+                query.timestamp_start(),
+                query.timestamp_end(),
+                ESTIMATE_LIMIT,
+            )
+        };
+
+        // Without any index filter the timestamp index is the only way in, all of it if need be
+        let mut best = if has_range || index_values.is_empty() {
+            Some((Access::Timestamp, count_timestamps()?))
+        } else {
+            None
+        };
+        for index_value in &index_values {
+            if best.as_ref().is_some_and(|(_, estimate)| *estimate == 0) {
+                break;
+            }
+            let estimate = backend.count_index(
+                partition,
+                &index_value.index,
+                index_value.key.as_bytes(),
+                ESTIMATE_LIMIT,
+            )?;
+            let sortable = query.limit().is_some() || estimate < ESTIMATE_LIMIT;
+            if sortable && best.as_ref().is_none_or(|(_, best)| estimate < *best) {
+                best = Some((Access::Index(index_value.clone()), estimate));
+            }
+        }
+        let (driver, estimate) = match best {
+            Some(best) => best,
+            None => (Access::Timestamp, count_timestamps()?),
+        };
+
+        let probes = index_values
+            .into_iter()
+            .filter(|index_value| driver != Access::Index(index_value.clone()))
+            .collect();
+        let labels = filters
+            .scanned
+            .iter()
+            .map(|matcher| Resolved::new(backend, partition, matcher))
+            .collect::<Result<Vec<_>>>()?;
+        // Nothing can get past a matcher that lets no primary through
+        let estimate = match labels.iter().any(Resolved::is_empty) {
+            true => 0,
+            false => estimate,
+        };
+        Ok(Self {
+            check_timestamp: has_range && driver != Access::Timestamp,
+            driver,
+            probes,
+            excludes: filters.exclude.clone(),
+            estimate,
+            labels,
+        })
+    }
+}
+
+/// The primaries of a partition a label matcher lets through, read from the label index of its
+/// key.
+///
+/// Only those with the label have an entry, so when a missing label is matched the primaries
+/// whose value isn't are kept instead. Either way every entry of the index in the partition is
+/// read, and the primaries kept are held for as long as the partition is queried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved {
+    /// Only these primaries are let through.
+    Only(HashSet<Box<[u8]>>),
+    /// Every primary but these is let through.
+    Except(HashSet<Box<[u8]>>),
+}
+
+impl Resolved {
+    fn new<B: BackendDatabase>(
+        backend: &B,
+        partition: Partition,
+        matcher: &LabelMatcher,
+    ) -> Result<Self> {
+        let missing = matcher.is_match(None);
+        let mut primaries = HashSet::new();
+        // Entries are sorted by value, so each value is only decoded and matched once
+        let mut last: Option<(Box<[u8]>, bool)> = None;
+        for entry in backend.scan_index_entries(partition, &label_index(&matcher.key), &[])? {
+            let (key, primary) = entry?;
+            let value = &key[..key.len().saturating_sub(primary.len())];
+            let matched = match &last {
+                Some((last, matched)) if **last == *value => *matched,
+                _ => {
+                    let Some(KeyComponent::String(label)) = Key::decode(value)?.pop() else {
+                        return Err(StorefulError::InvalidKey(0));
+                    };
+                    let matched = matcher.is_match(Some(&label));
+                    last = Some((value.into(), matched));
+                    matched
+                }
+            };
+            if matched != missing {
+                primaries.insert(primary);
+            }
+        }
+        Ok(match missing {
+            true => Self::Except(primaries),
+            false => Self::Only(primaries),
+        })
+    }
+
+    /// Whether `primary` is let through.
+    pub fn contains(&self, primary: &[u8]) -> bool {
+        match self {
+            Self::Only(primaries) => primaries.contains(primary),
+            Self::Except(primaries) => !primaries.contains(primary),
+        }
+    }
+
+    /// Whether no primary at all is let through.
+    fn is_empty(&self) -> bool {
+        matches!(self, Self::Only(primaries) if primaries.is_empty())
+    }
+}
@@ -1,154 +1,389 @@
-use crate::prelude::*;
+use crate::{half_open, prelude::*, Batch, BatchWrite, Entries, Key, Order, Partition, Primaries};
 
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::BTreeSet,
+    ops::Bound,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
 
-use chrono::Utc;
-use rocksdb::{BlockBasedOptions, ColumnFamily, Direction, IteratorMode, Options, WriteBatch, DB};
+use rocksdb::{
+    BlockBasedOptions, BoundColumnFamily, DBWithThreadMode, IteratorMode, MultiThreaded, Options,
+    ReadOptions, WriteBatch,
+};
 
 use super::BackendDatabase;
 
+/// Column families can be created and dropped through `&self` in this mode.
+type DB = DBWithThreadMode<MultiThreaded>;
+
+/// A store in a rocksdb database, its column families named after `master_key` so several
+/// stores can share one database.
 pub struct RocksDBBackend {
-    pub db: DB,
-    pub batch: Option<WriteBatch>,
+    db: Arc<DB>,
+    opts: Options,
+    master_key: String,
+    cf_names: RwLock<BTreeSet<String>>,
+    partitions: RwLock<BTreeSet<Partition>>,
 }
 
 impl BackendDatabase for RocksDBBackend {
-    fn start_batch(&mut self) -> Result<()> {
-        if self.batch.is_some() {
-            return Err(StorefulError::BatchAlreadyStarted);
+    fn write_batch(&self, mut batch: Batch) -> Result<()> {
+        // Checked before any partition is created, a refused batch doesn't leave empty ones
+        for write in batch.iter() {
+            if let BatchWrite::Index { index, .. } | BatchWrite::DeleteIndex { index, .. } = write {
+                self.check_cf(index)?;
+            }
         }
-        self.batch = Some(WriteBatch::default());
-        Ok(())
+        for write in batch.iter() {
+            self.create_partition(write.partition())?;
+        }
+
+        let mut write_batch = WriteBatch::default();
+        let metadata = batch.take_metadata();
+        if !metadata.is_empty() {
+            let cf = self.metadata_handle()?;
+            for (key, value) in metadata {
+                write_batch.put_cf(&cf, key, value);
+            }
+        }
+        for write in batch {
+            let partition = write.partition();
+            match write {
+                BatchWrite::Put { key, value, .. } => {
+                    write_batch.put_cf(&self.primary_handle(partition)?, key, value)
+                }
+                BatchWrite::Index {
+                    index,
+                    key,
+                    primary,
+                    ..
+                } => write_batch.put_cf(&self.handle(partition, &index)?, key, primary),
+                BatchWrite::Delete { key, .. } => {
+                    write_batch.delete_cf(&self.primary_handle(partition)?, key)
+                }
+                BatchWrite::DeleteIndex { index, key, .. } => {
+                    write_batch.delete_cf(&self.handle(partition, &index)?, key)
+                }
+            }
+        }
+        Ok(self.db.write(write_batch)?)
     }
 
-    fn commit_batch(&mut self) -> Result<()> {
-        if let Some(batch) = self.batch.take() {
-            self.db.write(batch)?;
+    fn get(&self, partition: Partition, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        if !self.has_partition(partition)? {
+            return Ok(None);
         }
-        Ok(())
+        let result = self.db.get_cf(&self.primary_handle(partition)?, key)?;
+        Ok(result.map(|value| value.into_boxed_slice()))
     }
 
-    fn put(&mut self, key: &str, value: &[u8]) -> Result<()> {
-        if let Some(batch) = self.batch.as_mut() {
-            batch.put(key, value);
-        } else {
-            self.db.put(key, value)?;
+    fn metadata(&self, key: &str) -> Result<Option<Box<[u8]>>> {
+        let Some(cf) = self
+            .db
+            .cf_handle(&Partition::metadata_tree(&self.master_key))
+        else {
+            return Ok(None);
+        };
+        let result = self.db.get_cf(&cf, key)?;
+        Ok(result.map(|value| value.into_boxed_slice()))
+    }
+
+    fn get_multi(&self, partition: Partition, keys: &[Box<[u8]>]) -> Result<Vec<Box<[u8]>>> {
+        let mut result = Vec::new();
+        if !self.has_partition(partition)? {
+            return Ok(result);
         }
-        Ok(())
+        let cf = self.primary_handle(partition)?;
+        for value in self.db.multi_get_cf(keys.iter().map(|key| (&cf, key))) {
+            if let Some(value) = value? {
+                result.push(value.into_boxed_slice());
+            }
+        }
+        Ok(result)
     }
 
-    fn get(&self, key: &str) -> Result<Option<Box<[u8]>>> {
-        let result = self.db.get(key)?;
-        Ok(result.map(|value| value.to_vec().into_boxed_slice()))
+    fn scan_range(
+        &self,
+        partition: Partition,
+        cf: &str,
+        range: (Bound<Key>, Bound<Key>),
+        order: Order,
+    ) -> Result<Primaries<'_>> {
+        self.check_cf(cf)?;
+        if !self.has_partition(partition)? {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let Some((lower, upper)) = half_open(range) else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let cf = self.handle(partition, cf)?;
+
+        // Let rocksdb stop at the range bounds instead of checking every key
+        let mut opts = ReadOptions::default();
+        if let Bound::Included(lower) = lower {
+            opts.set_iterate_lower_bound(lower);
+        }
+        if let Bound::Excluded(upper) = upper {
+            opts.set_iterate_upper_bound(upper);
+        }
+        let mode = match order {
+            Order::Ascending => IteratorMode::Start,
+            Order::Descending => IteratorMode::End,
+        };
+        let iter = self.db.iterator_cf_opt(&cf, opts, mode);
+        Ok(Box::new(iter.map(value)))
     }
 
-    fn get_multi(&self, keys: &HashSet<Box<[u8]>>) -> Result<Vec<Box<[u8]>>> {
-        Ok(self
-            .db
-            .multi_get(keys)
-            .into_iter()
-            .flatten()
-            .flatten()
-            .map(|item| item.into_boxed_slice())
-            .collect())
+    fn get_index(&self, partition: Partition, cf: &str, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        self.check_cf(cf)?;
+        if !self.has_partition(partition)? {
+            return Ok(None);
+        }
+        let result = self.db.get_cf(&self.handle(partition, cf)?, key)?;
+        Ok(result.map(|value| value.into_boxed_slice()))
     }
 
-    fn create_index(&mut self, cf: &str, primary: &str, key: &str) -> Result<()> {
-        self.create_index_cf(cf, primary, key)
+    fn scan_index(
+        &self,
+        partition: Partition,
+        cf: &str,
+        index_key: &[u8],
+    ) -> Result<Primaries<'_>> {
+        self.check_cf(cf)?;
+        if !self.has_partition(partition)? {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let prefix = index_key.to_vec();
+        let iter = self
+            .db
+            .prefix_iterator_cf(&self.handle(partition, cf)?, index_key);
+        Ok(Box::new(
+            iter.take_while(move |item| {
+                item.as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(&prefix))
+            })
+            .map(value),
+        ))
     }
 
-    fn query_timestamp_index(
+    fn scan_index_entries(
         &self,
-        timestamp_start: Option<i64>,
-        timestamp_end: Option<i64>,
-    ) -> Result<HashSet<Box<[u8]>>> {
-        let mut results = HashSet::new();
-        let cf = self
+        partition: Partition,
+        cf: &str,
+        index_key: &[u8],
+    ) -> Result<Entries<'_>> {
+        self.check_cf(cf)?;
+        if !self.has_partition(partition)? {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let prefix = index_key.to_vec();
+        let iter = self
             .db
-            .cf_handle("timestamp")
-            .expect("timestamp cf not found");
-
-        // Prepare iterator based on start condition
-        let iter = if let Some(start) = timestamp_start {
-            self.db.iterator_cf(
-                cf,
-                IteratorMode::From(
-                    format!("timestamp:{:0>20}|", start).as_bytes(),
-                    Direction::Forward,
-                ),
-            )
-        } else {
-            self.db.iterator_cf(cf, IteratorMode::Start)
-        };
+            .prefix_iterator_cf(&self.handle(partition, cf)?, index_key);
+        Ok(Box::new(
+            iter.take_while(move |item| {
+                item.as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(&prefix))
+            })
+            .map(|item| Ok(item?)),
+        ))
+    }
 
-        for result in iter {
-            let (key_slice, primary) = result?;
+    fn contains_index(&self, partition: Partition, cf: &str, key: &[u8]) -> Result<bool> {
+        self.check_cf(cf)?;
+        if !self.has_partition(partition)? {
+            return Ok(false);
+        }
+        Ok(self
+            .db
+            .get_pinned_cf(&self.handle(partition, cf)?, key)?
+            .is_some())
+    }
 
-            // Directly parse timestamp from key bytes to avoid unnecessary conversions
-            let timestamp_str = &key_slice[10..30]; // Slice to where timestamp is in the key
-            let timestamp: i64 = std::str::from_utf8(timestamp_str)?.parse()?;
+    fn trees(&self) -> Result<Vec<String>> {
+        Ok(self.cf_names.read()?.iter().cloned().collect())
+    }
 
-            // Check range
-            if let Some(start) = timestamp_start {
-                if timestamp < start {
-                    continue;
-                }
-            }
-            if let Some(end) = timestamp_end {
-                if timestamp > end {
-                    break;
-                }
-            }
+    fn create_tree(&self, tree: &str) -> Result<()> {
+        let partitions = self.partitions.write()?;
+        if !self.cf_names.write()?.insert(tree.into()) {
+            return Ok(());
+        }
+        for partition in partitions.iter() {
+            self.db
+                .create_cf(partition.index_tree(&self.master_key, tree), &self.opts)?;
+        }
+        Ok(())
+    }
 
-            // Convert primary key only once confirmed within range
-            results.insert(primary);
+    fn drop_tree(&self, tree: &str) -> Result<()> {
+        let partitions = self.partitions.write()?;
+        if !self.cf_names.write()?.remove(tree) {
+            return Err(StorefulError::IndexNotFound(tree.into()));
         }
+        for partition in partitions.iter() {
+            self.db
+                .drop_cf(&partition.index_tree(&self.master_key, tree))?;
+        }
+        Ok(())
+    }
 
-        Ok(results)
+    fn partitions(&self) -> Result<Vec<Partition>> {
+        Ok(self.partitions.read()?.iter().copied().collect())
     }
 
-    fn query_index(&self, cf: &str, index_key: &str) -> Result<HashSet<Box<[u8]>>> {
-        let handle = self
-            .db
-            .cf_handle(cf)
-            .unwrap_or_else(|| panic!("{} column family not found", cf));
-        self.query_index_cf(handle, index_key)
+    fn drop_partition(&self, partition: Partition) -> Result<()> {
+        let mut partitions = self.partitions.write()?;
+        if !partitions.contains(&partition) {
+            return Ok(());
+        }
+        // Only forgotten once every column family is gone, skipping those a failed drop
+        // already removed so it can be retried
+        let mut cf_names: Vec<String> = self
+            .cf_names
+            .read()?
+            .iter()
+            .map(|cf_name| partition.index_tree(&self.master_key, cf_name))
+            .collect();
+        cf_names.push(partition.primary_tree(&self.master_key));
+        for cf_name in cf_names {
+            if self.db.cf_handle(&cf_name).is_some() {
+                self.db.drop_cf(&cf_name)?;
+            }
+        }
+        partitions.remove(&partition);
+        Ok(())
     }
 }
 
 impl RocksDBBackend {
-    pub fn open(path: &PathBuf, indexes: Vec<&'static str>) -> Result<Self> {
+    pub fn open(path: &PathBuf, master_key: String, cf_names: &[&'static str]) -> Result<Self> {
+        Partition::check_master_key(&master_key)?;
         let mut opts = Options::default();
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
         let mut block_opts = BlockBasedOptions::default();
         block_opts.set_bloom_filter(10.0, false);
         opts.set_block_based_table_factory(&block_opts);
+
+        // Every column family has to be opened, including the partitions of earlier runs. A new
+        // database has none yet, but one that can't be read is refused
+        let existing = if path.join("CURRENT").exists() {
+            DB::list_cf(&opts, path).map_err(|e| StorefulError::Open(e.to_string()))?
+        } else {
+            Vec::new()
+        };
+        let (partitions, cf_names) = discover(&master_key, &existing, cf_names);
         let db =
-            DB::open_cf(&opts, path, indexes).map_err(|e| StorefulError::Open(e.to_string()))?;
-        Ok(Self { db, batch: None })
+            DB::open_cf(&opts, path, existing).map_err(|e| StorefulError::Open(e.to_string()))?;
+        Ok(Self {
+            db: Arc::new(db),
+            opts,
+            master_key,
+            cf_names: RwLock::new(cf_names),
+            partitions: RwLock::new(partitions),
+        })
     }
 
-    pub fn create_index_cf(&mut self, cf: &str, primary: &str, key: &str) -> Result<()> {
-        let handle = self.db.cf_handle(cf).expect("cf not found");
-        if let Some(batch) = self.batch.as_mut() {
-            batch.put_cf(handle, key, primary);
-        } else {
-            self.db.put_cf(handle, key, primary)?;
+    /// Opens another store in the same database, rocksdb only lets a path be opened once.
+    pub fn store(&self, master_key: String, cf_names: &[&'static str]) -> Result<Self> {
+        Partition::check_master_key(&master_key)?;
+        let existing = DB::list_cf(&self.opts, self.db.path())?;
+        let (partitions, cf_names) = discover(&master_key, &existing, cf_names);
+        Ok(Self {
+            db: self.db.clone(),
+            opts: self.opts.clone(),
+            master_key,
+            cf_names: RwLock::new(cf_names),
+            partitions: RwLock::new(partitions),
+        })
+    }
+
+    /// Creates the column families of `partition` if they don't exist yet.
+    fn create_partition(&self, partition: Partition) -> Result<()> {
+        if self.has_partition(partition)? {
+            return Ok(());
+        }
+        let mut partitions = self.partitions.write()?;
+        if partitions.contains(&partition) {
+            return Ok(());
         }
+        self.db
+            .create_cf(partition.primary_tree(&self.master_key), &self.opts)?;
+        for cf_name in self.cf_names.read()?.iter() {
+            self.db
+                .create_cf(partition.index_tree(&self.master_key, cf_name), &self.opts)?;
+        }
+        partitions.insert(partition);
         Ok(())
     }
 
-    pub fn query_index_cf(&self, cf: &ColumnFamily, index_key: &str) -> Result<HashSet<Box<[u8]>>> {
-        let mut results = HashSet::new();
-        let iter = self.db.prefix_iterator_cf(cf, index_key);
-        for iter_result in iter {
-            let (key, primary) = iter_result?;
-            if !key.starts_with(index_key.as_bytes()) {
-                break;
+    /// The column family holding the metadata of the store, created the first time it is set.
+    fn metadata_handle(&self) -> Result<Arc<BoundColumnFamily<'_>>> {
+        let name = Partition::metadata_tree(&self.master_key);
+        if self.db.cf_handle(&name).is_none() {
+            // Held so two batches don't both try to create it
+            let _partitions = self.partitions.write()?;
+            if self.db.cf_handle(&name).is_none() {
+                self.db.create_cf(&name, &self.opts)?;
             }
-            results.insert(primary);
         }
-        Ok(results)
+        self.db
+            .cf_handle(&name)
+            .ok_or(StorefulError::ColumnFamilyNotFound(name))
+    }
+
+    fn has_partition(&self, partition: Partition) -> Result<bool> {
+        Ok(self.partitions.read()?.contains(&partition))
+    }
+
+    fn check_cf(&self, cf: &str) -> Result<()> {
+        if self.cf_names.read()?.contains(cf) {
+            Ok(())
+        } else {
+            Err(StorefulError::IndexNotFound(cf.into()))
+        }
+    }
+
+    /// Looks up the column family holding the primaries of `partition`.
+    fn primary_handle(&self, partition: Partition) -> Result<Arc<BoundColumnFamily<'_>>> {
+        let name = partition.primary_tree(&self.master_key);
+        self.db
+            .cf_handle(&name)
+            .ok_or(StorefulError::ColumnFamilyNotFound(name))
+    }
+
+    /// Looks up the column family `cf` of `partition` in the namespace of `master_key`.
+    fn handle(&self, partition: Partition, cf: &str) -> Result<Arc<BoundColumnFamily<'_>>> {
+        let name = partition.index_tree(&self.master_key, cf);
+        self.db
+            .cf_handle(&name)
+            .ok_or(StorefulError::ColumnFamilyNotFound(name))
     }
 }
+
+/// The partitions of `master_key` among the `existing` column families, and its indexes: those
+/// in `cf_names` and any created since.
+fn discover(
+    master_key: &str,
+    existing: &[String],
+    cf_names: &[&'static str],
+) -> (BTreeSet<Partition>, BTreeSet<String>) {
+    let partitions = existing
+        .iter()
+        .filter_map(|cf_name| Partition::from_tree(master_key, cf_name))
+        .collect();
+    let mut indexes: BTreeSet<String> = cf_names.iter().map(|cf| cf.to_string()).collect();
+    indexes.extend(
+        existing
+            .iter()
+            .filter_map(|cf_name| Partition::index_from_tree(master_key, cf_name))
+            .map(String::from),
+    );
+    (partitions, indexes)
+}
+
+fn value(item: std::result::Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>) -> Result<Box<[u8]>> {
+    Ok(item?.1)
+}
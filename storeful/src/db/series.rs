@@ -0,0 +1,1445 @@
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, BinaryHeap, HashMap},
+    ops::Bound,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use roaring::RoaringBitmap;
+
+use crate::{
+    decode_chunk, format_header, prelude::*, read_format, read_versioned, write_versioned,
+    BackendDatabase, Batch, ChunkDecoder, ChunkEncoder, ChunkHeader, Context, Expired, IndexQuery,
+    IndexValue, Key, KeyComponent, LabelMatch, LabelMatcher, Order, Page, Partition, Position,
+    Sample, Sampled, Series, Storeful, Versioned,
+};
+
+use super::TimestampRange;
+
+/// The tree mapping every series of a partition to its id and back.
+pub const SERIES_INDEX: &str = "series";
+/// The tree holding the bitmap of series ids listed under each index entry.
+pub const POSTINGS_INDEX: &str = "postings";
+/// The tree holding the chunks of samples of every series, by series id and first timestamp.
+pub const SAMPLES_INDEX: &str = "samples";
+/// Every tree a backend storing a `Sampled` model has to open.
+pub const SERIES_TREES: &[&str] = &[SERIES_INDEX, POSTINGS_INDEX, SAMPLES_INDEX];
+
+/// How many samples are removed at once, each time rewriting the chunks they were in.
+const DELETE_CHUNK: usize = 4096;
+
+/// Series ids already handed out, by partition and `series_key`.
+pub type SeriesIds = Mutex<HashMap<Partition, HashMap<Key, u32>>>;
+
+/// How many locks the chunks of different series are spread over.
+const CHUNK_LOCKS: usize = 64;
+
+/// The last chunk of each series posts appended to, as the encoder that wrote it.
+type OpenChunks = HashMap<(Partition, u32), ChunkEncoder>;
+
+/// Keeps concurrent writers of series from undoing each other's changes.
+pub(crate) struct SeriesLocks {
+    /// Held while series are added, as ids are handed out from the bitmap of every series.
+    new_series: Mutex<()>,
+    /// Held while the chunks of the series falling to each are rewritten, along with the open
+    /// chunks of those series.
+    chunks: Vec<Mutex<OpenChunks>>,
+}
+
+impl Default for SeriesLocks {
+    fn default() -> Self {
+        Self {
+            new_series: Mutex::new(()),
+            chunks: (0..CHUNK_LOCKS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+impl SeriesLocks {
+    /// Locks the chunks of every one of `series`, always in the same order so that posts
+    /// sharing some of them can't deadlock.
+    fn chunks(
+        &self,
+        series: impl IntoIterator<Item = (Partition, u32)>,
+    ) -> Result<LockedChunks<'_>> {
+        let locks: BTreeSet<usize> = series.into_iter().map(chunk_lock).collect();
+        let stripes = locks
+            .into_iter()
+            .map(|lock| Ok((lock, self.chunks[lock].lock()?)))
+            .collect::<Result<_>>()?;
+        Ok(LockedChunks { stripes })
+    }
+
+    /// Locks out every writer of series, such as while partitions are dropped.
+    pub(crate) fn all(&self) -> Result<(MutexGuard<'_, ()>, LockedChunks<'_>)> {
+        let new_series = self.new_series.lock()?;
+        let stripes = self
+            .chunks
+            .iter()
+            .enumerate()
+            .map(|(lock, chunks)| Ok((lock, chunks.lock()?)))
+            .collect::<Result<_>>()?;
+        Ok((new_series, LockedChunks { stripes }))
+    }
+}
+
+/// Which of the chunk locks series `id` of `partition` falls to.
+fn chunk_lock((partition, id): (Partition, u32)) -> usize {
+    (partition.0 as usize ^ id as usize) % CHUNK_LOCKS
+}
+
+/// The chunk locks of some series, with the open chunks of those series.
+pub(crate) struct LockedChunks<'a> {
+    stripes: BTreeMap<usize, MutexGuard<'a, OpenChunks>>,
+}
+
+impl LockedChunks<'_> {
+    /// The open chunks of the lock `series` falls to, which has to be held.
+    fn open_chunks(&mut self, series: (Partition, u32)) -> &mut OpenChunks {
+        self.stripes
+            .get_mut(&chunk_lock(series))
+            .expect("the chunks of the series are locked")
+    }
+
+    /// Forgets the open chunks of `partition`, once it is dropped.
+    pub(crate) fn forget_partition(&mut self, partition: Partition) {
+        for open in self.stripes.values_mut() {
+            open.retain(|(open_partition, _), _| *open_partition != partition);
+        }
+    }
+}
+
+/// `("key", name, labels..)`, the dictionary entry holding the id of a series.
+fn series_key(name: &str, context: &Context) -> Key {
+    Key::new()
+        .with_str("key")
+        .with_str(name)
+        .with_context(context)
+}
+
+/// `("id")`, the prefix of every dictionary entry holding the name and labels of a series.
+fn series_ids_prefix() -> Key {
+    Key::new().with_str("id")
+}
+
+/// `("id", 42)`, the dictionary entry holding the name and labels of a series.
+fn series_id_key(id: u32) -> Key {
+    series_ids_prefix().with_i64(id.into())
+}
+
+/// `("all")`, the bitmap of every series in the partition.
+fn all_series_key() -> Key {
+    Key::new().with_str("all")
+}
+
+/// `(index, key..)`, the posting list of an index entry.
+fn posting_key(index_value: &IndexValue) -> Key {
+    Key::new()
+        .with_str(&index_value.index)
+        .with_key(index_value.key.as_bytes())
+}
+
+/// `(42)`, the prefix of every chunk of series 42.
+fn series_prefix(id: u32) -> Key {
+    Key::new().with_i64(id.into())
+}
+
+/// `(42, first)`, the chunk of series 42 starting at `first`.
+fn chunk_key(id: u32, first: i64) -> Key {
+    series_prefix(id).with_i64(first)
+}
+
+/// The version of the posting list encoding. Lists written before it was start with the cookie
+/// of the roaring format instead.
+const POSTINGS_FORMAT: u8 = 1;
+
+fn read_postings(bytes: &[u8]) -> Result<RoaringBitmap> {
+    RoaringBitmap::deserialize_from(read_format(bytes, POSTINGS_FORMAT)?)
+        .map_err(|_| StorefulError::InvalidPostings)
+}
+
+fn write_postings(postings: &RoaringBitmap) -> Result<Vec<u8>> {
+    let format = format_header(POSTINGS_FORMAT);
+    let mut bytes = Vec::with_capacity(format.len() + postings.serialized_size());
+    bytes.extend_from_slice(&format);
+    postings.serialize_into(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The series dictionary of one partition, with the changes a post has yet to write.
+#[derive(Default)]
+struct Dictionary {
+    all: RoaringBitmap,
+    ids: HashMap<Key, u32>,
+    postings: HashMap<Key, RoaringBitmap>,
+    added: Vec<(u32, Series)>,
+}
+
+/// A sample found by a query, along with the series it belongs to.
+struct Found {
+    partition: Partition,
+    id: u32,
+    series: Arc<Series>,
+    sample: Sample,
+}
+
+impl Found {
+    /// Samples at the same timestamp are ordered by series id.
+    fn position(&self) -> Position {
+        Position::new(self.sample.timestamp, &Key::new().with_i64(self.id.into()))
+    }
+}
+
+/// Samples decoded from the chunks of a series as the iterator advances.
+type Samples<'a> = Box<dyn Iterator<Item = Result<Sample>> + Send + 'a>;
+
+/// The samples of one series still to be merged, with the next of them read ahead.
+struct Head<'a> {
+    order: Order,
+    id: u32,
+    series: Arc<Series>,
+    sample: Sample,
+    samples: Samples<'a>,
+}
+
+impl Head<'_> {
+    fn position(&self) -> (i64, u32) {
+        (self.sample.timestamp, self.id)
+    }
+}
+
+impl PartialEq for Head<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.position() == other.position()
+    }
+}
+
+impl Eq for Head<'_> {}
+
+impl PartialOrd for Head<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // The heap pops its greatest entry, which has to be the one that comes first in `order`
+        let ordering = self.position().cmp(&other.position());
+        match self.order {
+            Order::Ascending => ordering.reverse(),
+            Order::Descending => ordering,
+        }
+    }
+}
+
+/// The samples of a partition across all the series a query matched, in timestamp order.
+struct Merge<'a> {
+    partition: Partition,
+    heads: BinaryHeap<Head<'a>>,
+    /// A failure reading ahead, returned after the sample read before it.
+    error: Option<StorefulError>,
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Found>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        let mut head = self.heads.pop()?;
+        let found = Found {
+            partition: self.partition,
+            id: head.id,
+            series: head.series.clone(),
+            sample: head.sample,
+        };
+        if let Some(next) = head.samples.next() {
+            match next {
+                Ok(sample) => {
+                    head.sample = sample;
+                    self.heads.push(head);
+                }
+                Err(e) => self.error = Some(e),
+            }
+        }
+        Some(Ok(found))
+    }
+}
+
+/// The part of `start..=end` of series `id` that comes past `after` in `order`.
+fn sample_bounds(
+    id: u32,
+    mut start: Option<i64>,
+    mut end: Option<i64>,
+    order: Order,
+    after: Option<(i64, u32)>,
+) -> Option<(Option<i64>, Option<i64>)> {
+    // Past a position are its later timestamps, and its own in series that sort after it
+    if let Some((timestamp, after_id)) = after {
+        match order {
+            Order::Ascending => {
+                let first = if id > after_id {
+                    timestamp
+                } else {
+                    timestamp.checked_add(1)?
+                };
+                start = Some(start.map_or(first, |start| start.max(first)));
+            }
+            Order::Descending => {
+                let last = if id < after_id {
+                    timestamp
+                } else {
+                    timestamp.checked_sub(1)?
+                };
+                end = Some(end.map_or(last, |end| end.min(last)));
+            }
+        }
+    }
+    if let (Some(start), Some(end)) = (start, end) {
+        if start > end {
+            return None;
+        }
+    }
+    Some((start, end))
+}
+
+/// The timestamp and series id of a position made by `Found::position`.
+fn parse_after(position: &Position) -> Result<(i64, u32)> {
+    match position.primary()?.as_slice() {
+        [KeyComponent::Integer(id)] => Ok((
+            position.timestamp(),
+            u32::try_from(*id).map_err(|_| StorefulError::InvalidContinuation)?,
+        )),
+        _ => Err(StorefulError::InvalidContinuation),
+    }
+}
+
+impl<B> Storeful<B>
+where
+    B: BackendDatabase + Send + Sync,
+{
+    /// Whether a sample at `timestamp` can still be appended to the chunk of `header`.
+    fn fits(&self, header: &ChunkHeader, timestamp: i64) -> bool {
+        let window = i64::try_from(self.chunks.window.as_nanos()).unwrap_or(i64::MAX);
+        (header.count as usize) < self.chunks.samples
+            && timestamp
+                .checked_sub(header.first)
+                .is_some_and(|span| span <= window)
+    }
+
+    /// The last chunk of series `id` starting at or before `timestamp`, decompressed.
+    fn chunk_at(&self, partition: Partition, id: u32, timestamp: i64) -> Result<Option<Vec<u8>>> {
+        let range = (
+            Bound::Included(series_prefix(id)),
+            Bound::Included(chunk_key(id, timestamp)),
+        );
+        self.backend
+            .scan_range(partition, SAMPLES_INDEX, range, Order::Descending)?
+            .next()
+            .map(|chunk| Ok(self.codec.decode(&chunk?)?.into_owned()))
+            .transpose()
+    }
+
+    /// Applies `change` to the samples of series `id` around `first..=last`, adding the
+    /// rewritten chunks to `batch`.
+    ///
+    /// Only the chunks `change` can touch are read, along with the open chunk before them that
+    /// new samples are appended to. Whatever comes out is split into chunks again, each sealed
+    /// once it is full or spans the chunk window.
+    fn rewrite_chunks(
+        &self,
+        batch: &mut Batch,
+        partition: Partition,
+        id: u32,
+        (first, last): (i64, i64),
+        change: impl FnOnce(&mut BTreeMap<i64, f64>),
+    ) -> Result<()> {
+        let mut chunks = Vec::new();
+        if let Some(chunk) = self.chunk_at(partition, id, first)? {
+            let header = ChunkHeader::read(&chunk)?;
+            if header.last >= first || self.fits(&header, first) {
+                chunks.push(chunk);
+            }
+        }
+        let later = (
+            Bound::Excluded(chunk_key(id, first)),
+            Bound::Included(chunk_key(id, last)),
+        );
+        for chunk in self
+            .backend
+            .scan_range(partition, SAMPLES_INDEX, later, Order::Ascending)?
+        {
+            chunks.push(self.codec.decode(&chunk?)?.into_owned());
+        }
+
+        let mut samples = BTreeMap::new();
+        for chunk in &chunks {
+            let header = ChunkHeader::read(chunk)?;
+            batch.delete_index(
+                partition,
+                SAMPLES_INDEX,
+                chunk_key(id, header.first).as_bytes(),
+            );
+            for sample in decode_chunk(chunk)? {
+                samples.insert(sample.timestamp, sample.value);
+            }
+        }
+        change(&mut samples);
+
+        let mut encoders: Vec<ChunkEncoder> = Vec::new();
+        for (timestamp, value) in samples {
+            let sample = Sample { timestamp, value };
+            match encoders.last_mut() {
+                Some(encoder) if self.fits(&encoder.header(), timestamp) => encoder.push(sample),
+                _ => encoders.push(ChunkEncoder::new(sample)),
+            }
+        }
+        for encoder in encoders {
+            self.write_chunk(batch, partition, id, &encoder)?;
+        }
+        Ok(())
+    }
+
+    /// The posting list at `key` in `partition`, loaded into `postings` the first time.
+    fn posting_list<'p>(
+        &self,
+        partition: Partition,
+        postings: &'p mut HashMap<Key, RoaringBitmap>,
+        key: Key,
+    ) -> Result<&'p mut RoaringBitmap> {
+        Ok(match postings.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let listed =
+                    self.backend
+                        .get_index(partition, POSTINGS_INDEX, entry.key().as_bytes())?;
+                let listed = listed.map(|listed| read_postings(&listed)).transpose()?;
+                entry.insert(listed.unwrap_or_default())
+            }
+        })
+    }
+
+    /// The id of the series of `record` in `partition`, added to `dictionary` if it is new.
+    fn series_id<T: Sampled>(
+        &self,
+        partition: Partition,
+        dictionary: &mut Dictionary,
+        key: Key,
+        record: &T,
+    ) -> Result<u32> {
+        if let Some(id) = dictionary.ids.get(&key) {
+            return Ok(*id);
+        }
+        if let Some(id) = self
+            .backend
+            .get_index(partition, SERIES_INDEX, key.as_bytes())?
+        {
+            let id = u32::from_be_bytes(
+                id.as_ref()
+                    .try_into()
+                    .map_err(|_| StorefulError::InvalidSeriesId(id.len()))?,
+            );
+            dictionary.ids.insert(key, id);
+            return Ok(id);
+        }
+
+        let id = match dictionary.all.max() {
+            Some(max) => max
+                .checked_add(1)
+                .ok_or(StorefulError::SeriesIdsExhausted(partition))?,
+            None => 0,
+        };
+        let series = Series {
+            name: record.name().to_string(),
+            context: record.context().clone(),
+        };
+        for index_value in T::index_values(&series) {
+            self.posting_list(
+                partition,
+                &mut dictionary.postings,
+                posting_key(&index_value),
+            )?
+            .insert(id);
+        }
+        dictionary.all.insert(id);
+        dictionary.ids.insert(key, id);
+        dictionary.added.push((id, series));
+        Ok(id)
+    }
+
+    /// Hands out ids to the series of `new` that no other post added in the meantime, writing
+    /// them to the dictionary of their partition. Returns the ids of all of them.
+    ///
+    /// Series are added one post at a time, so no two of them get the same id.
+    fn add_series<T: Sampled>(
+        &self,
+        new: Vec<(Partition, Key, &T)>,
+    ) -> Result<HashMap<(Partition, Key), u32>> {
+        let _adding = self.series_locks.new_series.lock()?;
+        let mut dictionaries: HashMap<Partition, Dictionary> = HashMap::new();
+        let mut ids = HashMap::new();
+        for (partition, key, record) in new {
+            let dictionary = match dictionaries.entry(partition) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(Dictionary {
+                    all: self.all_series(partition)?,
+                    ..Dictionary::default()
+                }),
+            };
+            let id = self.series_id(partition, dictionary, key.clone(), record)?;
+            ids.insert((partition, key), id);
+        }
+
+        let mut batch = Batch::default();
+        for (partition, dictionary) in &dictionaries {
+            if dictionary.added.is_empty() {
+                continue;
+            }
+            for (id, series) in &dictionary.added {
+                let key = series_key(&series.name, &series.context);
+                batch.create_index(*partition, SERIES_INDEX, &id.to_be_bytes(), key.as_bytes());
+                batch.create_index(
+                    *partition,
+                    SERIES_INDEX,
+                    &self.codec.encode(&write_versioned(series)?)?,
+                    series_id_key(*id).as_bytes(),
+                );
+            }
+            for (key, listed) in &dictionary.postings {
+                batch.create_index(
+                    *partition,
+                    POSTINGS_INDEX,
+                    &write_postings(listed)?,
+                    key.as_bytes(),
+                );
+            }
+            batch.create_index(
+                *partition,
+                SERIES_INDEX,
+                &write_postings(&dictionary.all)?,
+                all_series_key().as_bytes(),
+            );
+        }
+        self.backend.write_batch(batch)?;
+
+        // Only remembered once written, a failed post leaves nothing behind
+        let mut known = self.series_ids.lock()?;
+        for (partition, dictionary) in dictionaries {
+            known.entry(partition).or_default().extend(dictionary.ids);
+        }
+        Ok(ids)
+    }
+
+    /// Writes every sample in `records` in a single batch, adding the series that are new
+    /// beforehand.
+    ///
+    /// Chunks are rewritten while holding the locks of their series, so concurrent posts only
+    /// wait on each other when they share series, and never lose each other's samples. A post
+    /// that fails after adding its series leaves them in place without samples.
+    ///
+    /// Samples past the last one of their series are appended to its last chunk, kept open
+    /// from the post before so the samples already in it aren't decoded and encoded again.
+    pub fn post_samples<T: Sampled>(&self, records: impl IntoIterator<Item = T>) -> Result<()> {
+        // Checked up front, nothing that can be refused is left to fail halfway through
+        let records = records
+            .into_iter()
+            .map(|record| {
+                let sample = record.sample()?;
+                let key = series_key(record.name(), record.context());
+                Ok((self.partition(sample.timestamp), key, sample, record))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut ids = HashMap::new();
+        let mut new = Vec::new();
+        {
+            let known = self.series_ids.lock()?;
+            for (partition, key, _, record) in &records {
+                match known.get(partition).and_then(|ids| ids.get(key)) {
+                    Some(id) => {
+                        ids.insert((*partition, key.clone()), *id);
+                    }
+                    None => new.push((*partition, key.clone(), record)),
+                }
+            }
+        }
+        if !new.is_empty() {
+            ids.extend(self.add_series(new)?);
+        }
+
+        let mut samples: HashMap<(Partition, u32), BTreeMap<i64, f64>> = HashMap::new();
+        for (partition, key, sample, _) in records {
+            let id = ids[&(partition, key)];
+            // A later sample at the same timestamp replaces the earlier one
+            samples
+                .entry((partition, id))
+                .or_default()
+                .insert(sample.timestamp, sample.value);
+        }
+        let mut chunks = self.series_locks.chunks(samples.keys().copied())?;
+        let mut batch = Batch::default();
+        let mut opened = Vec::new();
+        for ((partition, id), samples) in samples {
+            // Taken out until the batch is written, a failed one leaves nothing stale behind
+            let open = chunks.open_chunks((partition, id)).remove(&(partition, id));
+            if let Some(open) = self.append_samples(&mut batch, partition, id, open, &samples)? {
+                opened.push(((partition, id), open));
+                continue;
+            }
+            let (Some((&first, _)), Some((&last, _))) =
+                (samples.first_key_value(), samples.last_key_value())
+            else {
+                continue;
+            };
+            self.rewrite_chunks(&mut batch, partition, id, (first, last), |chunked| {
+                chunked.extend(samples)
+            })?;
+        }
+        self.backend.write_batch(batch)?;
+        for (series, open) in opened {
+            chunks.open_chunks(series).insert(series, open);
+        }
+        Ok(())
+    }
+
+    /// Appends `samples` to the last chunk of series `id`, adding the chunks written to
+    /// `batch`. Returns the chunk left open, or `None` when some of the samples don't come
+    /// after the last one stored and the chunks have to be rewritten instead.
+    ///
+    /// `open` is the last chunk of the series if a post already appended to it, otherwise it
+    /// is read from the backend and picked up where it was left off.
+    fn append_samples(
+        &self,
+        batch: &mut Batch,
+        partition: Partition,
+        id: u32,
+        open: Option<ChunkEncoder>,
+        samples: &BTreeMap<i64, f64>,
+    ) -> Result<Option<ChunkEncoder>> {
+        let mut open = match open {
+            Some(open) => Some(open),
+            None => self
+                .chunk_at(partition, id, i64::MAX)?
+                .map(|chunk| ChunkEncoder::resume(&chunk))
+                .transpose()?,
+        };
+        let Some((&first, _)) = samples.first_key_value() else {
+            return Ok(open);
+        };
+        if open
+            .as_ref()
+            .is_some_and(|open| first <= open.header().last)
+        {
+            return Ok(None);
+        }
+        for (&timestamp, &value) in samples {
+            let sample = Sample { timestamp, value };
+            match &mut open {
+                Some(open) if self.fits(&open.header(), timestamp) => open.push(sample),
+                _ => {
+                    if let Some(sealed) = open.replace(ChunkEncoder::new(sample)) {
+                        self.write_chunk(batch, partition, id, &sealed)?;
+                    }
+                }
+            }
+        }
+        if let Some(open) = &open {
+            self.write_chunk(batch, partition, id, open)?;
+        }
+        Ok(open)
+    }
+
+    /// Adds the chunk `encoder` wrote for series `id` to `batch`.
+    fn write_chunk(
+        &self,
+        batch: &mut Batch,
+        partition: Partition,
+        id: u32,
+        encoder: &ChunkEncoder,
+    ) -> Result<()> {
+        let key = chunk_key(id, encoder.header().first);
+        let chunk = self.codec.encode(&encoder.finish())?;
+        batch.create_index(partition, SAMPLES_INDEX, &chunk, key.as_bytes());
+        Ok(())
+    }
+
+    /// The ids of every series in `partition`.
+    fn all_series(&self, partition: Partition) -> Result<RoaringBitmap> {
+        let all = self
+            .backend
+            .get_index(partition, SERIES_INDEX, all_series_key().as_bytes())?;
+        Ok(all
+            .map(|all| read_postings(&all))
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    /// The ids of the series in `partition` listed under `index_value`.
+    fn listed_series(
+        &self,
+        partition: Partition,
+        index_value: &IndexValue,
+    ) -> Result<RoaringBitmap> {
+        let key = posting_key(index_value);
+        match self
+            .backend
+            .get_index(partition, POSTINGS_INDEX, key.as_bytes())?
+        {
+            Some(postings) => read_postings(&postings),
+            None => Ok(RoaringBitmap::new()),
+        }
+    }
+
+    /// The ids of the series in `partition` whose label meets `matcher`.
+    ///
+    /// Every posting list under the key of the label is read, since each of its values has to
+    /// be checked. The series lacking the label are the ones in none of them.
+    fn matched_series(
+        &self,
+        partition: Partition,
+        matcher: &LabelMatcher,
+    ) -> Result<RoaringBitmap> {
+        let prefix = Key::new().with_str(&matcher.index).with_str(&matcher.key);
+        if let LabelMatch::Equal(value) = &matcher.matches {
+            if !value.is_empty() {
+                return self.listed_series(
+                    partition,
+                    &IndexValue::new(
+                        matcher.index.clone(),
+                        Key::new().with_str(&matcher.key).with_str(value),
+                    ),
+                );
+            }
+        }
+        let (mut labelled, mut matched) = (RoaringBitmap::new(), RoaringBitmap::new());
+        for entry in
+            self.backend
+                .scan_index_entries(partition, POSTINGS_INDEX, prefix.as_bytes())?
+        {
+            let (key, postings) = entry?;
+            let postings = read_postings(&postings)?;
+            let Some(KeyComponent::String(value)) =
+                Key::decode(&key[prefix.as_bytes().len()..])?.pop()
+            else {
+                return Err(StorefulError::InvalidPostings);
+            };
+            if matcher.is_match(Some(&value)) {
+                matched |= &postings;
+            }
+            labelled |= postings;
+        }
+        if matcher.is_match(None) {
+            matched |= self.all_series(partition)? - labelled;
+        }
+        Ok(matched)
+    }
+
+    /// The ids of the series in `partition` listed under every index value of `query` and
+    /// meeting every one of `matchers`.
+    fn matching_series<Q: IndexQuery>(
+        &self,
+        partition: Partition,
+        query: &Q,
+        matchers: &[LabelMatcher],
+    ) -> Result<RoaringBitmap> {
+        let index_values = query.index_values();
+        let sets = index_values
+            .iter()
+            .map(|index_value| self.listed_series(partition, index_value))
+            .chain(
+                matchers
+                    .iter()
+                    .map(|matcher| self.matched_series(partition, matcher)),
+            );
+        let mut matching: Option<RoaringBitmap> = None;
+        for listed in sets {
+            let listed = listed?;
+            let matching = matching.get_or_insert(listed.clone());
+            *matching &= listed;
+            if matching.is_empty() {
+                break;
+            }
+        }
+        match matching {
+            Some(matching) => Ok(matching),
+            None => self.all_series(partition),
+        }
+    }
+
+    fn series(&self, partition: Partition, id: u32) -> Result<Series> {
+        let series = self
+            .backend
+            .get_index(partition, SERIES_INDEX, series_id_key(id).as_bytes())?
+            .ok_or(StorefulError::SeriesNotFound(id))?;
+        Ok(read_versioned(&self.codec.decode(&series)?)?.1)
+    }
+
+    /// The samples of series `id` in `start..=end`, decoded a chunk at a time in `order`.
+    fn series_samples(
+        &self,
+        partition: Partition,
+        id: u32,
+        (start, end): (Option<i64>, Option<i64>),
+        order: Order,
+    ) -> Result<Samples<'_>> {
+        // The chunk holding `start` may have started before it
+        let lower = match start {
+            Some(start) => match self.chunk_at(partition, id, start)? {
+                Some(chunk) => chunk_key(id, ChunkHeader::read(&chunk)?.first),
+                None => chunk_key(id, start),
+            },
+            None => series_prefix(id),
+        };
+        let upper = match end {
+            Some(end) => Bound::Included(chunk_key(id, end)),
+            None => Bound::Excluded(Key::new().with_i64(i64::from(id) + 1)),
+        };
+        let chunks = self.backend.scan_range(
+            partition,
+            SAMPLES_INDEX,
+            (Bound::Included(lower), upper),
+            order,
+        )?;
+        let in_range = move |sample: &Sample| {
+            start.is_none_or(|start| sample.timestamp >= start)
+                && end.is_none_or(|end| sample.timestamp <= end)
+        };
+        Ok(Box::new(chunks.flat_map(move |chunk| {
+            let chunk = chunk.and_then(|chunk| ChunkDecoder::new(self.codec.decode_owned(chunk)?));
+            let samples = match chunk {
+                Ok(chunk) => chunk.filter(move |sample| sample.as_ref().map_or(true, in_range)),
+                Err(e) => return Box::new(std::iter::once(Err(e))) as Samples,
+            };
+            match order {
+                Order::Ascending => Box::new(samples),
+                // Chunks only read forwards, so one is buffered at a time to reverse it
+                Order::Descending => Box::new(samples.collect::<Vec<_>>().into_iter().rev()),
+            }
+        })))
+    }
+
+    /// The samples in `partition` matching `query` past `after`, in the order of the query.
+    fn samples_in<Q: IndexQuery>(
+        &self,
+        partition: Partition,
+        query: &Q,
+        matchers: &[LabelMatcher],
+        after: Option<(i64, u32)>,
+    ) -> Result<Merge<'_>> {
+        let order = query.order();
+        let mut heads = BinaryHeap::new();
+        for id in &self.matching_series(partition, query, matchers)? {
+            let Some((start, end)) = sample_bounds(
+                id,
+                query.timestamp_start(),
+                query.timestamp_end(),
+                order,
+                after,
+            ) else {
+                continue;
+            };
+            let mut samples = self.series_samples(partition, id, (start, end), order)?;
+            let Some(sample) = samples.next() else {
+                continue;
+            };
+            heads.push(Head {
+                order,
+                id,
+                series: Arc::new(self.series(partition, id)?),
+                sample: sample?,
+                samples,
+            });
+        }
+        Ok(Merge {
+            partition,
+            heads,
+            error: None,
+        })
+    }
+
+    /// The position `query` continues from, checking its range along the way.
+    fn sample_continuation<Q: IndexQuery>(query: &Q) -> Result<Option<(i64, u32)>> {
+        if let (Some(start), Some(end)) = (query.timestamp_start(), query.timestamp_end()) {
+            if start > end {
+                return Err(StorefulError::InvalidQueryRange);
+            }
+        }
+        let after = query.continuation().map(Position::parse).transpose()?;
+        after.as_ref().map(parse_after).transpose()
+    }
+
+    /// Every sample matching `query`, in timestamp order and then by series id.
+    fn find_samples<'a, Q: IndexQuery>(
+        &'a self,
+        query: &'a Q,
+    ) -> Result<impl Iterator<Item = Result<Found>> + Send + 'a> {
+        let after = Self::sample_continuation(query)?;
+        let samples = self.find_samples_after(query, after)?;
+        Ok(samples.take(query.limit().unwrap_or(usize::MAX)))
+    }
+
+    /// Like `find_samples`, continuing past `after` rather than the continuation of `query`,
+    /// and without its limit.
+    fn find_samples_after<'a, Q: IndexQuery>(
+        &'a self,
+        query: &'a Q,
+        after: Option<(i64, u32)>,
+    ) -> Result<impl Iterator<Item = Result<Found>> + Send + 'a> {
+        let (mut start, mut end) = (query.timestamp_start(), query.timestamp_end());
+        if let Some((timestamp, _)) = after {
+            match query.order() {
+                Order::Ascending => start = Some(start.map_or(timestamp, |s| s.max(timestamp))),
+                Order::Descending => end = Some(end.map_or(timestamp, |e| e.min(timestamp))),
+            }
+        }
+        let matchers = query.label_matchers()?;
+        let mut partitions = self.partitions(start, end)?;
+        if query.order() == Order::Descending {
+            partitions.reverse();
+        }
+        Ok(partitions.into_iter().flat_map(move |partition| {
+            match self.samples_in(partition, query, &matchers, after) {
+                Ok(samples) => Box::new(samples) as Box<dyn Iterator<Item = _> + Send>,
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        }))
+    }
+
+    /// Every sample matching `query` along with its series, read from the backend as the
+    /// iterator advances.
+    ///
+    /// Samples of the same series share one `Series`, so filtering or aggregating them doesn't
+    /// have to build a record for every sample.
+    pub fn query_series_samples<'a, Q: IndexQuery>(
+        &'a self,
+        query: &'a Q,
+    ) -> Result<impl Iterator<Item = Result<(Arc<Series>, Sample)>> + Send + 'a> {
+        Ok(self
+            .find_samples(query)?
+            .map(|found| found.map(|found| (found.series, found.sample))))
+    }
+
+    /// Every sample matching `query`, read from the backend as the iterator advances.
+    pub fn query_samples_iter<'a, T, Q>(
+        &'a self,
+        query: &'a Q,
+    ) -> Result<impl Iterator<Item = Result<T>> + Send + 'a>
+    where
+        T: Sampled + 'a,
+        Q: IndexQuery,
+    {
+        Ok(self
+            .query_series_samples(query)?
+            .map(|found| found.map(|(series, sample)| T::from_sample(&series, sample))))
+    }
+
+    /// The samples matching `query`, with a continuation token if its limit cut them short.
+    pub fn query_sample_page<T: Sampled, Q: IndexQuery>(&self, query: &Q) -> Result<Page<T>> {
+        let mut records = Vec::new();
+        let mut last = None;
+        for found in self.find_samples(query)? {
+            let found = found?;
+            records.push(T::from_sample(&found.series, found.sample));
+            last = Some(found);
+        }
+        let continuation = match query.limit() {
+            Some(limit) if records.len() == limit => last.map(|last| last.position().token()),
+            _ => None,
+        };
+        Ok(Page {
+            records,
+            continuation,
+        })
+    }
+
+    /// Removes every sample matching `query`, returning how many were removed.
+    ///
+    /// Samples are removed `DELETE_CHUNK` at a time, so a large range is never held in memory
+    /// at once. Each chunk is found by running the query again past the last sample removed,
+    /// as the chunks a scan is reading may be rewritten under it.
+    ///
+    /// Series stay in the dictionary even once they have no samples left, until their
+    /// partition is dropped.
+    pub fn delete_samples<Q: IndexQuery>(&self, query: &Q) -> Result<usize> {
+        let mut after = Self::sample_continuation(query)?;
+        let mut left = query.limit().unwrap_or(usize::MAX);
+        let mut count = 0;
+        while left > 0 {
+            let found = self
+                .find_samples_after(query, after)?
+                .take(DELETE_CHUNK.min(left))
+                .collect::<Result<Vec<_>>>()?;
+            let Some(last) = found.last() else {
+                break;
+            };
+            after = Some((last.sample.timestamp, last.id));
+            left -= found.len();
+            count += found.len();
+
+            let mut removed: HashMap<(Partition, u32), BTreeSet<i64>> = HashMap::new();
+            for found in found {
+                removed
+                    .entry((found.partition, found.id))
+                    .or_default()
+                    .insert(found.sample.timestamp);
+            }
+            // Held so no post rewrites the same chunks in the meantime
+            let mut chunks = self.series_locks.chunks(removed.keys().copied())?;
+            let mut batch = Batch::default();
+            for ((partition, id), timestamps) in removed {
+                // The open chunk may be among those rewritten, the next post reads it again
+                chunks.open_chunks((partition, id)).remove(&(partition, id));
+                let (Some(&first), Some(&last)) = (timestamps.first(), timestamps.last()) else {
+                    continue;
+                };
+                self.rewrite_chunks(&mut batch, partition, id, (first, last), |samples| {
+                    samples.retain(|timestamp, _| !timestamps.contains(timestamp))
+                })?;
+            }
+            self.backend.write_batch(batch)?;
+        }
+        Ok(count)
+    }
+
+    /// Rewrites every series written as an older version of `Series` as its current one,
+    /// returning how many were rewritten.
+    ///
+    /// Each partition is migrated in one batch, with posts waiting until it is written.
+    pub fn migrate_series(&self) -> Result<usize> {
+        let mut migrated = 0;
+        for partition in self.backend.partitions()? {
+            let _adding = self.series_locks.new_series.lock()?;
+            let mut batch = Batch::default();
+            for id in &self.matching_series(partition, &TimestampRange::default(), &[])? {
+                let key = series_id_key(id);
+                let value = self
+                    .backend
+                    .get_index(partition, SERIES_INDEX, key.as_bytes())?
+                    .ok_or(StorefulError::SeriesNotFound(id))?;
+                let (version, series) = read_versioned::<Series>(&self.codec.decode(&value)?)?;
+                if version != Series::VERSION {
+                    let value = self.codec.encode(&write_versioned(&series)?)?;
+                    batch.create_index(partition, SERIES_INDEX, &value, key.as_bytes());
+                    migrated += 1;
+                }
+            }
+            self.backend.write_batch(batch)?;
+        }
+        Ok(migrated)
+    }
+
+    /// Removes every sample with a timestamp before `before`, dropping whole partitions first.
+    ///
+    /// The partition `before` falls in is cleaned up by `delete_samples`, a chunk of samples at
+    /// a time, so expiring a busy partition never holds all of it in memory.
+    pub fn expire_samples(&self, before: i64) -> Result<Expired> {
+        let partitions = self.drop_partitions_before(before)?;
+        let records = self.delete_samples(&TimestampRange {
+            start: None,
+            end: Some(before.saturating_sub(1)),
+        })?;
+        Ok(Expired {
+            partitions,
+            records,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{memory::MemoryBackend, ChunkConfig, ContextValue};
+
+    #[derive(Serialize, Deserialize)]
+    struct Reading {
+        name: String,
+        context: Context,
+        sample: Sample,
+    }
+
+    impl Sampled for Reading {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn context(&self) -> &Context {
+            &self.context
+        }
+
+        fn sample(&self) -> Result<Sample> {
+            Ok(self.sample)
+        }
+
+        fn index_values(series: &Series) -> Vec<IndexValue> {
+            let mut index_values = vec![IndexValue::new("name", Key::new().with_str(&series.name))];
+            for context_value in series.context.values() {
+                index_values.push(IndexValue::context_value("context", context_value));
+            }
+            index_values
+        }
+
+        fn from_sample(series: &Arc<Series>, sample: Sample) -> Self {
+            Self {
+                name: series.name.clone(),
+                context: series.context.clone(),
+                sample,
+            }
+        }
+    }
+
+    struct ByLabel(Vec<IndexValue>);
+
+    impl IndexQuery for ByLabel {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn interns_series_once() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), SERIES_TREES));
+        let reading = |host: &str, timestamp| Reading {
+            name: "cpu".into(),
+            context: Context::default().with_value("host", host),
+            sample: Sample {
+                timestamp,
+                value: timestamp as f64,
+            },
+        };
+        storeful
+            .post_samples((0..10).map(|i| reading(["a", "b"][i % 2], i as i64)))
+            .unwrap();
+        // A later post, past the cache, still finds the same series
+        storeful.series_ids.lock().unwrap().clear();
+        storeful.post_samples([reading("a", 10)]).unwrap();
+
+        let partition = storeful.partition(0);
+        let all = storeful
+            .matching_series(partition, &ByLabel(vec![]), &[])
+            .unwrap();
+        assert_eq!(all.iter().collect::<Vec<_>>(), [0, 1]);
+
+        let host = ContextValue {
+            key: "host".into(),
+            value: "a".into(),
+        };
+        let query = ByLabel(vec![
+            IndexValue::new("name", Key::new().with_str("cpu")),
+            IndexValue::context_value("context", &host),
+        ]);
+        assert_eq!(
+            storeful
+                .matching_series(partition, &query, &[])
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            [0]
+        );
+        // Index values are compared whole, a label key alone matches no series
+        let key_only = ByLabel(vec![IndexValue::new(
+            "context",
+            Key::new().with_str("host"),
+        )]);
+        assert!(storeful
+            .matching_series(partition, &key_only, &[])
+            .unwrap()
+            .is_empty());
+        let readings: Vec<Reading> = storeful
+            .query_samples_iter(&query)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let timestamps: Vec<i64> = readings.iter().map(|r| r.sample.timestamp).collect();
+        assert_eq!(timestamps, [0, 2, 4, 6, 8, 10]);
+
+        // Samples of a series share it rather than each getting a copy
+        let found: Vec<(Arc<Series>, Sample)> = storeful
+            .query_series_samples(&query)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(found.len(), 6);
+        assert!(found
+            .iter()
+            .all(|(series, _)| Arc::ptr_eq(series, &found[0].0)));
+        assert_eq!(found[0].0.context, reading("a", 0).context);
+    }
+
+    struct ByMatchers(Vec<&'static str>);
+
+    impl IndexQuery for ByMatchers {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+
+        fn label_matchers(&self) -> Result<Vec<LabelMatcher>> {
+            self.0
+                .iter()
+                .map(|matcher| LabelMatcher::parse("context", matcher))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn reads_postings_without_a_format_version() {
+        let postings: RoaringBitmap = [1, 5, 1 << 20].into_iter().collect();
+        let mut legacy = Vec::new();
+        postings.serialize_into(&mut legacy).unwrap();
+        assert_eq!(read_postings(&legacy).unwrap(), postings);
+
+        let bytes = write_postings(&postings).unwrap();
+        assert_eq!(bytes[2..], legacy);
+        assert_eq!(read_postings(&bytes).unwrap(), postings);
+    }
+
+    #[test]
+    fn matches_labels() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), SERIES_TREES));
+        let contexts = [
+            Context::default()
+                .with_value("host", "a")
+                .with_value("env", "prod"),
+            Context::default().with_value("host", "b"),
+            Context::default()
+                .with_value("host", "c")
+                .with_value("env", "dev"),
+            Context::default(),
+        ];
+        storeful
+            .post_samples(
+                contexts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, context)| Reading {
+                        name: "cpu".into(),
+                        context,
+                        sample: Sample {
+                            timestamp: i as i64,
+                            value: 0.0,
+                        },
+                    }),
+            )
+            .unwrap();
+
+        let cases: [(&[&str], &[i64]); 8] = [
+            (&["host=\"a\""], &[0]),
+            (&["host!=\"a\""], &[1, 2, 3]),
+            (&["host=~\"a|b\""], &[0, 1]),
+            (&["host!~\"a|b\""], &[2, 3]),
+            (&["env"], &[0, 2]),
+            (&["!env"], &[1, 3]),
+            (&["host=~\".+\"", "!env"], &[1]),
+            (&["env=\"prod\"", "host!=\"a\""], &[]),
+        ];
+        for (matchers, expected) in cases {
+            let readings: Vec<Reading> = storeful
+                .query_samples_iter(&ByMatchers(matchers.to_vec()))
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            let timestamps: Vec<i64> = readings.iter().map(|r| r.sample.timestamp).collect();
+            assert_eq!(timestamps, expected, "{:?}", matchers);
+        }
+        assert!(matches!(
+            storeful.query_samples_iter::<Reading, _>(&ByMatchers(vec!["host=a"])),
+            Err(StorefulError::InvalidMatcher(_))
+        ));
+    }
+
+    #[test]
+    fn seals_and_rewrites_chunks() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), SERIES_TREES)).with_chunks(
+            ChunkConfig {
+                samples: 4,
+                window: std::time::Duration::from_nanos(100),
+            },
+        );
+        let reading = |timestamp: i64| Reading {
+            name: "cpu".into(),
+            context: Context::default(),
+            sample: Sample {
+                timestamp,
+                value: timestamp as f64,
+            },
+        };
+        let chunks = || {
+            let range = (Bound::Unbounded, Bound::Unbounded);
+            storeful
+                .backend
+                .scan_range(
+                    storeful.partition(0),
+                    SAMPLES_INDEX,
+                    range,
+                    Order::Ascending,
+                )
+                .unwrap()
+                .map(|chunk| {
+                    ChunkHeader::read(&storeful.codec.decode(&chunk.unwrap()).unwrap()).unwrap()
+                })
+                .map(|header| (header.first, header.count))
+                .collect::<Vec<_>>()
+        };
+        let timestamps = || {
+            storeful
+                .query_samples_iter::<Reading, _>(&ByLabel(vec![]))
+                .unwrap()
+                .map(|reading| reading.unwrap().sample.timestamp)
+                .collect::<Vec<_>>()
+        };
+
+        // Appended one at a time, chunks fill up to four samples and then stay sealed
+        for timestamp in 0..6 {
+            storeful.post_samples([reading(timestamp)]).unwrap();
+        }
+        assert_eq!(chunks(), [(0, 4), (4, 2)]);
+        // Past the window the open chunk is sealed early
+        storeful.post_samples([reading(200)]).unwrap();
+        assert_eq!(chunks(), [(0, 4), (4, 2), (200, 1)]);
+
+        // Late samples land in the chunks around them
+        storeful.post_samples([reading(-5), reading(50)]).unwrap();
+        assert_eq!(timestamps(), [-5, 0, 1, 2, 3, 4, 5, 50, 200]);
+
+        let middle = TimestampRange {
+            start: Some(1),
+            end: Some(4),
+        };
+        assert_eq!(storeful.delete_samples(&middle).unwrap(), 4);
+        assert_eq!(timestamps(), [-5, 0, 5, 50, 200]);
+
+        // Appending after a rewrite picks up the rewritten chunk rather than the one before
+        storeful.post_samples([reading(201)]).unwrap();
+        storeful.post_samples([reading(202)]).unwrap();
+        let last = TimestampRange {
+            start: Some(202),
+            end: None,
+        };
+        assert_eq!(storeful.delete_samples(&last).unwrap(), 1);
+        storeful.post_samples([reading(203)]).unwrap();
+        assert_eq!(timestamps(), [-5, 0, 5, 50, 200, 201, 203]);
+        assert_eq!(chunks(), [(0, 3), (200, 3)]);
+    }
+
+    #[test]
+    fn deletes_samples_past_a_chunk() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), SERIES_TREES));
+        let stored = DELETE_CHUNK as i64;
+        for host in ["a", "b", "c"] {
+            storeful
+                .post_samples((0..stored).map(|timestamp| Reading {
+                    name: "cpu".into(),
+                    context: Context::default().with_value("host", host),
+                    sample: Sample {
+                        timestamp,
+                        value: 1.0,
+                    },
+                }))
+                .unwrap();
+        }
+
+        // Chunks are rewritten under the query, which has to pick up past them every time
+        let range = TimestampRange {
+            start: Some(10),
+            end: Some(stored - 11),
+        };
+        assert_eq!(
+            storeful.delete_samples(&range).unwrap(),
+            3 * (stored as usize - 20)
+        );
+        let left: Vec<i64> = storeful
+            .query_samples_iter::<Reading, _>(&ByLabel(vec![]))
+            .unwrap()
+            .map(|reading| reading.unwrap().sample.timestamp)
+            .collect();
+        assert_eq!(left.len(), 3 * 20);
+        assert!(left
+            .iter()
+            .all(|timestamp| *timestamp < 10 || *timestamp > stored - 11));
+    }
+
+    #[test]
+    fn expires_samples_past_a_chunk() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), SERIES_TREES))
+            .with_partition_width(std::time::Duration::from_nanos(DELETE_CHUNK as u64 * 4));
+        let stored = DELETE_CHUNK as i64 * 6;
+        storeful
+            .post_samples((0..stored).map(|timestamp| Reading {
+                name: "cpu".into(),
+                context: Context::default(),
+                sample: Sample {
+                    timestamp,
+                    value: 1.0,
+                },
+            }))
+            .unwrap();
+
+        // The first partition goes whole, most of the second sample by sample
+        let before = stored - 10;
+        let expired = storeful.expire_samples(before).unwrap();
+        assert_eq!(expired.partitions, 1);
+        assert_eq!(expired.records, DELETE_CHUNK * 2 - 10);
+        let left: Vec<i64> = storeful
+            .query_samples_iter::<Reading, _>(&ByLabel(vec![]))
+            .unwrap()
+            .map(|reading| reading.unwrap().sample.timestamp)
+            .collect();
+        assert_eq!(left, (before..stored).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn keeps_concurrent_posts() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), SERIES_TREES));
+        std::thread::scope(|scope| {
+            for thread in 0..4i64 {
+                let storeful = &storeful;
+                scope.spawn(move || {
+                    for i in 0..25 {
+                        let reading = |host: String| Reading {
+                            name: "cpu".into(),
+                            context: Context::default().with_value("host", &host),
+                            sample: Sample {
+                                timestamp: thread * 25 + i,
+                                value: 0.0,
+                            },
+                        };
+                        // One series shared by every thread, and one of its own
+                        storeful
+                            .post_samples([reading("shared".into()), reading(thread.to_string())])
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let partition = storeful.partition(0);
+        let all = storeful
+            .matching_series(partition, &ByLabel(vec![]), &[])
+            .unwrap();
+        assert_eq!(all.len(), 5);
+        let readings: Vec<Reading> = storeful
+            .query_samples_iter(&ByLabel(vec![]))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(readings.len(), 200);
+    }
+}
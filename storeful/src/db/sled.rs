@@ -1,72 +1,206 @@
-use sled::{Batch, Tree};
+use sled::{
+    transaction::{ConflictableTransactionError, Transactional},
+    IVec, Tree,
+};
 
-use crate::{prelude::*, BackendDatabase};
+use crate::{
+    half_open, prelude::*, BackendDatabase, Batch, BatchWrite, Entries, IndexEntry, Key, Order,
+    Partition, Primaries,
+};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
     path::PathBuf,
+    sync::RwLock,
 };
 
+/// The primaries and index trees of a single partition.
+#[derive(Clone)]
+struct SledPartition {
+    primaries: Tree,
+    indexes: HashMap<String, Tree>,
+}
+
+/// A store in a sled database, its trees named after `master_key` so several stores can share
+/// one database.
 pub struct SledBackend {
     db: sled::Db,
     master_key: String,
-    trees: HashMap<String, Tree>,
-    batch: Option<Batch>,
+    metadata: Tree,
+    tree_names: RwLock<BTreeSet<String>>,
+    partitions: RwLock<BTreeMap<Partition, SledPartition>>,
 }
 
 impl SledBackend {
     pub fn open(path: &PathBuf, master_key: String, tree_names: &[&'static str]) -> Result<Self> {
         let config = sled::Config::new().path(path);
-        let db = config.open()?;
-        let mut trees = HashMap::new();
-        for tree_name in tree_names {
-            let tree = db.open_tree(tree_name)?;
-            trees.insert(format!("{}:{}", &master_key, tree_name), tree);
-        }
-        Ok(Self {
+        Self::in_db(config.open()?, master_key, tree_names)
+    }
+
+    /// Opens another store in the same database, sled only lets a path be opened once.
+    pub fn store(&self, master_key: String, tree_names: &[&'static str]) -> Result<Self> {
+        Self::in_db(self.db.clone(), master_key, tree_names)
+    }
+
+    pub(crate) fn in_db(
+        db: sled::Db,
+        master_key: String,
+        tree_names: &[&'static str],
+    ) -> Result<Self> {
+        Partition::check_master_key(&master_key)?;
+        let backend = Self {
+            metadata: db.open_tree(Partition::metadata_tree(&master_key))?,
             db,
             master_key,
-            trees,
-            batch: None,
-        })
+            tree_names: RwLock::new(tree_names.iter().map(|tree| tree.to_string()).collect()),
+            partitions: RwLock::new(BTreeMap::new()),
+        };
+
+        // Pick up the partitions written by earlier runs, and the indexes created since
+        let mut existing = BTreeSet::new();
+        for name in backend.db.tree_names() {
+            let name = String::from_utf8(name.to_vec())?;
+            if let Some(partition) = Partition::from_tree(&backend.master_key, &name) {
+                existing.insert(partition);
+            }
+            if let Some(index) = Partition::index_from_tree(&backend.master_key, &name) {
+                backend.tree_names.write()?.insert(index.into());
+            }
+        }
+        for partition in existing {
+            backend.open_partition(partition)?;
+        }
+        Ok(backend)
     }
-}
 
-impl BackendDatabase for SledBackend {
-    fn start_batch(&mut self) -> Result<()> {
-        if self.batch.is_some() {
-            return Err(StorefulError::BatchAlreadyStarted);
-        } else {
-            self.batch = Some(sled::Batch::default());
+    /// Opens the trees of `partition`, creating them if they don't exist yet.
+    fn open_partition(&self, partition: Partition) -> Result<SledPartition> {
+        if let Some(trees) = self.partitions.read()?.get(&partition) {
+            return Ok(trees.clone());
         }
-        Ok(())
+        let mut partitions = self.partitions.write()?;
+        if let Some(trees) = partitions.get(&partition) {
+            return Ok(trees.clone());
+        }
+        let primaries = self
+            .db
+            .open_tree(partition.primary_tree(&self.master_key))?;
+        let mut indexes = HashMap::new();
+        for tree_name in self.tree_names.read()?.iter() {
+            let tree = self
+                .db
+                .open_tree(partition.index_tree(&self.master_key, tree_name))?;
+            indexes.insert(tree_name.clone(), tree);
+        }
+        let trees = SledPartition { primaries, indexes };
+        partitions.insert(partition, trees.clone());
+        Ok(trees)
     }
 
-    fn commit_batch(&mut self) -> Result<()> {
-        if let Some(batch) = self.batch.take() {
-            Ok(self.db.apply_batch(batch)?)
+    /// The `tree` index of `partition`, or `None` if nothing was written to the partition yet.
+    ///
+    /// Trees are cheap handles, so they are cloned rather than borrowed past the lock.
+    fn tree(&self, partition: Partition, tree: &str) -> Result<Option<Tree>> {
+        if let Some(trees) = self.partitions.read()?.get(&partition) {
+            return match trees.indexes.get(tree) {
+                Some(tree) => Ok(Some(tree.clone())),
+                None => Err(StorefulError::IndexNotFound(tree.into())),
+            };
+        }
+        if self.tree_names.read()?.contains(tree) {
+            Ok(None)
         } else {
-            Err(StorefulError::BatchNotStarted)
+            Err(StorefulError::IndexNotFound(tree.into()))
         }
     }
 
-    fn put(&mut self, key: &str, value: &[u8]) -> Result<()> {
-        if let Some(batch) = self.batch.as_mut() {
-            batch.insert(key, value);
-        } else {
-            self.db.insert(key, value)?;
+    /// The tree holding the primaries of `partition`, `None` if nothing was written to it yet.
+    fn primaries(&self, partition: Partition) -> Result<Option<Tree>> {
+        Ok(self
+            .partitions
+            .read()?
+            .get(&partition)
+            .map(|partition| partition.primaries.clone()))
+    }
+}
+
+impl BackendDatabase for SledBackend {
+    fn write_batch(&self, mut batch: Batch) -> Result<()> {
+        // Checked before any partition is opened, a refused batch doesn't leave empty ones
+        for write in batch.iter() {
+            if let BatchWrite::Index { index, .. } | BatchWrite::DeleteIndex { index, .. } = write {
+                self.tree(write.partition(), index)?;
+            }
+        }
+        let mut partitions = HashMap::new();
+        for write in batch.iter() {
+            if let Entry::Vacant(entry) = partitions.entry(write.partition()) {
+                entry.insert(self.open_partition(write.partition())?);
+            }
         }
+
+        // One sled batch per tree, applied together in a single transaction
+        let mut batches: HashMap<IVec, (Tree, sled::Batch)> = HashMap::new();
+        for (key, value) in batch.take_metadata() {
+            tree_batch(&mut batches, &self.metadata).insert(key.as_bytes(), value);
+        }
+        for write in batch {
+            let partition = &partitions[&write.partition()];
+            match write {
+                BatchWrite::Put { key, value, .. } => {
+                    tree_batch(&mut batches, &partition.primaries).insert(key, value)
+                }
+                BatchWrite::Index {
+                    index,
+                    key,
+                    primary,
+                    ..
+                } => tree_batch(&mut batches, index_tree(partition, &index)?).insert(key, primary),
+                BatchWrite::Delete { key, .. } => {
+                    tree_batch(&mut batches, &partition.primaries).remove(key)
+                }
+                BatchWrite::DeleteIndex { index, key, .. } => {
+                    tree_batch(&mut batches, index_tree(partition, &index)?).remove(key)
+                }
+            }
+        }
+        if batches.is_empty() {
+            return Ok(());
+        }
+
+        let (trees, batches): (Vec<Tree>, Vec<sled::Batch>) = batches.into_values().unzip();
+        let trees: Vec<&Tree> = trees.iter().collect();
+        trees.as_slice().transaction(|tx_trees| {
+            for (tx_tree, batch) in tx_trees.iter().zip(&batches) {
+                tx_tree.apply_batch(batch)?;
+            }
+            Ok::<_, ConflictableTransactionError>(())
+        })?;
         Ok(())
     }
 
-    fn get(&self, key: &str) -> Result<Option<Box<[u8]>>> {
-        let result = self.db.get(key)?;
+    fn get(&self, partition: Partition, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        let Some(primaries) = self.primaries(partition)? else {
+            return Ok(None);
+        };
+        let result = primaries.get(key)?;
         Ok(result.map(|value| value.to_vec().into_boxed_slice()))
     }
 
-    fn get_multi(&self, keys: &std::collections::HashSet<Box<[u8]>>) -> Result<Vec<Box<[u8]>>> {
+    fn metadata(&self, key: &str) -> Result<Option<Box<[u8]>>> {
+        Ok(self
+            .metadata
+            .get(key)?
+            .map(|value| value.to_vec().into_boxed_slice()))
+    }
+
+    fn get_multi(&self, partition: Partition, keys: &[Box<[u8]>]) -> Result<Vec<Box<[u8]>>> {
         let mut result = Vec::new();
+        let Some(primaries) = self.primaries(partition)? else {
+            return Ok(result);
+        };
         for key in keys {
-            let value = self.db.get(key)?;
+            let value = primaries.get(key)?;
             if let Some(value) = value {
                 result.push(value.to_vec().into_boxed_slice());
             }
@@ -74,65 +208,266 @@ impl BackendDatabase for SledBackend {
         Ok(result)
     }
 
-    fn create_index(&mut self, tree: &str, primary_key: &str, key: &str) -> Result<()> {
-        let tree = self
-            .trees
-            .get(&format!("{}:{}", &self.master_key, tree))
-            .unwrap();
-        tree.insert(key, primary_key)?;
-        Ok(())
+    fn scan_range(
+        &self,
+        partition: Partition,
+        tree: &str,
+        range: (Bound<Key>, Bound<Key>),
+        order: Order,
+    ) -> Result<Primaries<'_>> {
+        let Some(tree) = self.tree(partition, tree)? else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        // sled panics on inverted ranges rather than yielding nothing
+        let Some(range) = half_open(range) else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let range = tree.range(range);
+        Ok(match order {
+            Order::Ascending => Box::new(range.map(value)),
+            Order::Descending => Box::new(range.rev().map(value)),
+        })
     }
 
-    fn query_timestamp_index(
+    fn get_index(&self, partition: Partition, tree: &str, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        let Some(tree) = self.tree(partition, tree)? else {
+            return Ok(None);
+        };
+        Ok(tree
+            .get(key)?
+            .map(|value| value.to_vec().into_boxed_slice()))
+    }
+
+    fn scan_index(
         &self,
-        timestamp_start: Option<i64>,
-        timestamp_end: Option<i64>,
-    ) -> Result<std::collections::HashSet<Box<[u8]>>> {
-        let tree = self
-            .trees
-            .get(&format!("{}:{}", &self.master_key, "timestamp"))
-            .unwrap();
-        let mut results = HashSet::new();
-        for result in tree.iter() {
-            let (key, value) = result?;
-
-            // Directly parse timestamp from key bytes to avoid unnecessary conversions
-            let timestamp_str = &key[10..30]; // Slice to where timestamp is in the key
-            let timestamp: i64 = std::str::from_utf8(timestamp_str)?.parse()?;
-
-            // Check range
-            if let Some(start) = timestamp_start {
-                if timestamp < start {
-                    continue;
-                }
-            }
-            if let Some(end) = timestamp_end {
-                if timestamp > end {
-                    break;
-                }
+        partition: Partition,
+        tree: &str,
+        index_key: &[u8],
+    ) -> Result<Primaries<'_>> {
+        let Some(tree) = self.tree(partition, tree)? else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        Ok(Box::new(tree.scan_prefix(index_key).map(value)))
+    }
+
+    fn scan_index_entries(
+        &self,
+        partition: Partition,
+        tree: &str,
+        index_key: &[u8],
+    ) -> Result<Entries<'_>> {
+        let Some(tree) = self.tree(partition, tree)? else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        Ok(Box::new(tree.scan_prefix(index_key).map(entry)))
+    }
+
+    fn contains_index(&self, partition: Partition, tree: &str, key: &[u8]) -> Result<bool> {
+        let Some(tree) = self.tree(partition, tree)? else {
+            return Ok(false);
+        };
+        Ok(tree.contains_key(key)?)
+    }
+
+    fn trees(&self) -> Result<Vec<String>> {
+        Ok(self.tree_names.read()?.iter().cloned().collect())
+    }
+
+    fn create_tree(&self, tree: &str) -> Result<()> {
+        let mut partitions = self.partitions.write()?;
+        if !self.tree_names.write()?.insert(tree.into()) {
+            return Ok(());
+        }
+        for (partition, trees) in partitions.iter_mut() {
+            let index = self
+                .db
+                .open_tree(partition.index_tree(&self.master_key, tree))?;
+            trees.indexes.insert(tree.into(), index);
+        }
+        Ok(())
+    }
+
+    fn drop_tree(&self, tree: &str) -> Result<()> {
+        let mut partitions = self.partitions.write()?;
+        if !self.tree_names.write()?.remove(tree) {
+            return Err(StorefulError::IndexNotFound(tree.into()));
+        }
+        for trees in partitions.values_mut() {
+            if let Some(index) = trees.indexes.remove(tree) {
+                self.db.drop_tree(index.name())?;
             }
+        }
+        Ok(())
+    }
 
-            // Convert primary key only once confirmed within range
-            results.insert(value.to_vec().into_boxed_slice());
+    fn partitions(&self) -> Result<Vec<Partition>> {
+        Ok(self.partitions.read()?.keys().copied().collect())
+    }
+
+    fn drop_partition(&self, partition: Partition) -> Result<()> {
+        let mut partitions = self.partitions.write()?;
+        let Some(trees) = partitions.get(&partition) else {
+            return Ok(());
+        };
+        // Only forgotten once every tree is gone, a failed drop can be retried
+        for tree in trees.indexes.values() {
+            self.db.drop_tree(tree.name())?;
         }
-        Ok(results)
+        self.db.drop_tree(trees.primaries.name())?;
+        partitions.remove(&partition);
+        Ok(())
     }
+}
 
-    fn query_index(
-        &self,
-        tree: &str,
-        index_key: &str,
-    ) -> Result<std::collections::HashSet<Box<[u8]>>> {
-        // iterate with prefix of index_key
-        let tree = self
-            .trees
-            .get(&format!("{}:{}", &self.master_key, tree))
+/// The `index` tree of `partition`, which may have been dropped since the batch was checked.
+fn index_tree<'a>(partition: &'a SledPartition, index: &str) -> Result<&'a Tree> {
+    partition
+        .indexes
+        .get(index)
+        .ok_or_else(|| StorefulError::IndexNotFound(index.into()))
+}
+
+/// The batch collecting writes to `tree`.
+fn tree_batch<'a>(
+    batches: &'a mut HashMap<IVec, (Tree, sled::Batch)>,
+    tree: &Tree,
+) -> &'a mut sled::Batch {
+    &mut batches
+        .entry(tree.name())
+        .or_insert_with(|| (tree.clone(), sled::Batch::default()))
+        .1
+}
+
+fn value(item: sled::Result<(IVec, IVec)>) -> Result<Box<[u8]>> {
+    Ok(item?.1.to_vec().into_boxed_slice())
+}
+
+fn entry(item: sled::Result<(IVec, IVec)>) -> Result<IndexEntry> {
+    let (key, value) = item?;
+    Ok((key.to_vec().into(), value.to_vec().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TIMESTAMP_INDEX;
+
+    fn primary(backend: &SledBackend, key: &[u8]) -> Option<Box<[u8]>> {
+        backend.get(Partition(0), key).unwrap()
+    }
+
+    #[test]
+    fn keeps_stores_apart() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let metrics =
+            SledBackend::in_db(db, "metrics".into(), &[TIMESTAMP_INDEX, "context"]).unwrap();
+        let logs = metrics
+            .store("logs".into(), &[TIMESTAMP_INDEX, "context"])
             .unwrap();
-        let mut result = HashSet::new();
-        for item in tree.scan_prefix(index_key) {
-            let (_, value) = item?;
-            result.insert(value.to_vec().into_boxed_slice());
+
+        // The same keys in the same trees of each store
+        for (backend, value) in [(&metrics, &b"metric"[..]), (&logs, &b"log"[..])] {
+            let mut batch = Batch::default();
+            batch.put(Partition(0), b"key", value);
+            batch.create_index(Partition(0), "context", value, b"index");
+            backend.write_batch(batch).unwrap();
         }
-        Ok(result)
+        assert_eq!(primary(&metrics, b"key").as_deref(), Some(&b"metric"[..]));
+        assert_eq!(primary(&logs, b"key").as_deref(), Some(&b"log"[..]));
+        let indexed = |backend: &SledBackend| {
+            backend
+                .get_index(Partition(0), "context", b"index")
+                .unwrap()
+        };
+        assert_eq!(indexed(&logs).as_deref(), Some(&b"log"[..]));
+
+        // Dropping a partition of one store leaves the other's alone
+        metrics.drop_partition(Partition(0)).unwrap();
+        assert_eq!(primary(&metrics, b"key"), None);
+        assert_eq!(indexed(&logs).as_deref(), Some(&b"log"[..]));
+
+        // Reopened stores only pick up their own partitions
+        let mut batch = Batch::default();
+        batch.put(Partition(1), b"key", b"metric");
+        metrics.write_batch(batch).unwrap();
+        let reopened = logs.store("metrics".into(), &[TIMESTAMP_INDEX]).unwrap();
+        assert_eq!(reopened.partitions().unwrap(), [Partition(1)]);
+        let reopened = metrics.store("logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        assert_eq!(reopened.partitions().unwrap(), [Partition(0)]);
+
+        for master_key in ["", "logs@1", "logs:context"] {
+            assert!(matches!(
+                metrics.store(master_key.into(), &[]),
+                Err(StorefulError::InvalidMasterKey(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn keeps_created_trees() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let logs = SledBackend::in_db(db, "logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        let mut batch = Batch::default();
+        batch.put(Partition(0), b"key", b"log");
+        logs.write_batch(batch).unwrap();
+
+        // Partitions written before the tree was created get it as well
+        logs.create_tree("label:service.name").unwrap();
+        let mut batch = Batch::default();
+        batch.create_index(Partition(0), "label:service.name", b"key", b"api");
+        logs.write_batch(batch).unwrap();
+        let reopened = logs.store("logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        assert_eq!(
+            reopened.trees().unwrap(),
+            ["label:service.name", TIMESTAMP_INDEX]
+        );
+        assert!(reopened
+            .contains_index(Partition(0), "label:service.name", b"api")
+            .unwrap());
+
+        reopened.drop_tree("label:service.name").unwrap();
+        let mut batch = Batch::default();
+        batch.create_index(Partition(0), "label:service.name", b"key", b"api");
+        assert!(matches!(
+            reopened.write_batch(batch),
+            Err(StorefulError::IndexNotFound(_))
+        ));
+        let reopened = reopened.store("logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        assert_eq!(reopened.trees().unwrap(), [TIMESTAMP_INDEX]);
+    }
+
+    #[test]
+    fn keeps_metadata_per_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let logs = SledBackend::in_db(db, "logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        let metrics = logs.store("metrics".into(), &[TIMESTAMP_INDEX]).unwrap();
+        let mut batch = Batch::default();
+        batch.set_metadata("width", b"1");
+        logs.write_batch(batch).unwrap();
+
+        let reopened = metrics.store("logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        assert_eq!(
+            reopened.metadata("width").unwrap().as_deref(),
+            Some(&b"1"[..])
+        );
+        assert_eq!(metrics.metadata("width").unwrap(), None);
+        // Not taken for a partition of the store it belongs to
+        assert!(reopened.partitions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refused_batches_leave_no_partitions() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let logs = SledBackend::in_db(db, "logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        let mut batch = Batch::default();
+        batch.put(Partition(1), b"key", b"log");
+        batch.create_index(Partition(2), "unknown", b"key", b"index");
+        assert!(matches!(
+            logs.write_batch(batch),
+            Err(StorefulError::IndexNotFound(_))
+        ));
+        assert!(logs.partitions().unwrap().is_empty());
+        let reopened = logs.store("logs".into(), &[TIMESTAMP_INDEX]).unwrap();
+        assert!(reopened.partitions().unwrap().is_empty());
     }
 }
@@ -0,0 +1,1247 @@
+use std::{borrow::Cow, collections::BTreeMap, marker::PhantomData, sync::RwLock, time::Duration};
+
+use crate::{
+    label_index, prelude::*, read_versioned, split_versioned, timestamp_index_prefix, validate_key,
+    write_versioned, Access, BackendDatabase, Batch, ChunkConfig, Codec, Context, Expired,
+    IndexQuery, IndexValue, Key, LabelFilters, LabelMatcher, Order, Page, Partition, Plan,
+    Position, Primaries, SeriesIds, SeriesLocks, Storeable, DEFAULT_PARTITION_WIDTH,
+    TIMESTAMP_INDEX,
+};
+
+/// How many primaries are fetched from the backend at once while reading query results.
+const FETCH_CHUNK: usize = 256;
+
+/// The metadata holding the partition width a store was first opened with, in nanoseconds.
+const PARTITION_WIDTH: &str = "partition_width";
+
+/// The metadata holding the format the values of a store are written in.
+const STORE_FORMAT: &str = "format";
+
+/// The metadata listing the label indexes of a store by key, and whether each was backfilled.
+const LABEL_INDEXES: &str = "label_indexes";
+
+/// Values are written behind the tag of the codec that compressed them, records and series
+/// behind the version of the model they were written as.
+const FORMAT: u16 = 1;
+
+pub struct Storeful<B>
+where
+    B: BackendDatabase + Send + Sync,
+{
+    pub backend: B,
+    partition_width: i64,
+    /// How stored values are compressed, index entries and posting lists are kept as is.
+    pub(super) codec: Codec,
+    /// When the chunks of `Sampled` series are sealed.
+    pub(super) chunks: ChunkConfig,
+    /// Series ids known to be in each partition.
+    pub(super) series_ids: SeriesIds,
+    /// Held while series are added or their chunks rewritten.
+    pub(super) series_locks: SeriesLocks,
+    /// Read while records are written along with their index entries, and written while label
+    /// indexes are created or dropped, so no write can miss or outlive an index. Also written
+    /// while each chunk of stored records is rewritten, see `for_each_record`.
+    label_indexes: RwLock<()>,
+}
+
+/// Matches every record in `start..=end`, used to expire records by age alone.
+#[derive(Default)]
+pub(super) struct TimestampRange {
+    pub(super) start: Option<i64>,
+    pub(super) end: Option<i64>,
+}
+
+impl IndexQuery for TimestampRange {
+    fn timestamp_start(&self) -> Option<i64> {
+        self.start
+    }
+
+    fn timestamp_end(&self) -> Option<i64> {
+        self.end
+    }
+
+    fn index_values(&self) -> Vec<IndexValue> {
+        Vec::new()
+    }
+}
+
+impl<B> Storeful<B>
+where
+    B: BackendDatabase + Send + Sync,
+{
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            partition_width: DEFAULT_PARTITION_WIDTH.as_nanos() as i64,
+            codec: Codec::default(),
+            chunks: ChunkConfig::default(),
+            series_ids: SeriesIds::default(),
+            series_locks: SeriesLocks::default(),
+            label_indexes: RwLock::new(()),
+        }
+    }
+
+    /// Sets how much time each partition covers.
+    ///
+    /// Partitions are identified by `timestamp / width`, so this must stay the same for as long
+    /// as a database is in use, which `open` makes sure of.
+    pub fn with_partition_width(mut self, width: Duration) -> Self {
+        self.partition_width = i64::try_from(width.as_nanos()).unwrap_or(i64::MAX).max(1);
+        self
+    }
+
+    /// Sets how records are compressed from now on, those written before stay readable.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets how many samples, and how much time, each chunk of a series holds at most.
+    pub fn with_chunks(mut self, chunks: ChunkConfig) -> Self {
+        self.chunks = chunks;
+        self
+    }
+
+    /// Checks the store against what it was set up with when it was first opened, recording
+    /// that on the first open. Called once configured, before anything is read or written.
+    ///
+    /// Refuses a partition width other than the one the store was created with, which would
+    /// look for existing records in the wrong partitions and expire the wrong ones, and one
+    /// written in a format it doesn't know.
+    pub fn open(self) -> Result<Self> {
+        match self.backend.metadata(PARTITION_WIDTH)? {
+            Some(stored) => {
+                let stored = i64::from_be_bytes(
+                    (*stored)
+                        .try_into()
+                        .map_err(|_| StorefulError::InvalidMetadata(PARTITION_WIDTH))?,
+                );
+                if stored != self.partition_width {
+                    return Err(StorefulError::PartitionWidthMismatch {
+                        stored,
+                        configured: self.partition_width,
+                    });
+                }
+            }
+            None => {
+                let mut batch = Batch::default();
+                batch.set_metadata(PARTITION_WIDTH, &self.partition_width.to_be_bytes());
+                self.backend.write_batch(batch)?;
+            }
+        }
+        match self.backend.metadata(STORE_FORMAT)? {
+            Some(stored) => {
+                let stored = u16::from_be_bytes(
+                    (*stored)
+                        .try_into()
+                        .map_err(|_| StorefulError::InvalidMetadata(STORE_FORMAT))?,
+                );
+                if stored != FORMAT {
+                    return Err(StorefulError::UnknownStoreFormat(stored));
+                }
+            }
+            None => {
+                let mut batch = Batch::default();
+                batch.set_metadata(STORE_FORMAT, &FORMAT.to_be_bytes());
+                self.backend.write_batch(batch)?;
+            }
+        }
+        // Backends only find the trees of label indexes something was written to
+        for key in self.listed_label_indexes()?.keys() {
+            self.backend.create_tree(&label_index(key))?;
+        }
+        Ok(self)
+    }
+
+    /// The partition a record with `timestamp` is stored in.
+    pub fn partition(&self, timestamp: i64) -> Partition {
+        Partition(timestamp.div_euclid(self.partition_width))
+    }
+
+    /// Existing partitions that can hold timestamps in `timestamp_start..=timestamp_end`.
+    pub fn partitions(
+        &self,
+        timestamp_start: Option<i64>,
+        timestamp_end: Option<i64>,
+    ) -> Result<Vec<Partition>> {
+        let first = timestamp_start.map(|timestamp| self.partition(timestamp));
+        let last = timestamp_end.map(|timestamp| self.partition(timestamp));
+        Ok(self
+            .backend
+            .partitions()?
+            .into_iter()
+            .filter(|partition| first.is_none_or(|first| *partition >= first))
+            .filter(|partition| last.is_none_or(|last| *partition <= last))
+            .collect())
+    }
+
+    /// Drops every partition holding only timestamps before `before`, returning how many.
+    pub fn drop_partitions_before(&self, before: i64) -> Result<usize> {
+        let boundary = self.partition(before);
+        // Held throughout, or a post could cache ids of a partition that is about to go, or
+        // write samples to it as it goes
+        let (_adding, mut chunks) = self.series_locks.all()?;
+        let mut known = self.series_ids.lock()?;
+        let expired: Vec<Partition> = self
+            .backend
+            .partitions()?
+            .into_iter()
+            .filter(|partition| *partition < boundary)
+            .collect();
+        for partition in &expired {
+            known.remove(partition);
+            chunks.forget_partition(*partition);
+            self.backend.drop_partition(*partition)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Every `(index, key)` entry that points at the `primary` of `record`, including those in
+    /// the label indexes created so far.
+    fn index_entries<T: Storeable>(
+        &self,
+        primary: &Key,
+        record: &T,
+    ) -> Result<Vec<(Cow<'static, str>, Key)>> {
+        self.entries_of(
+            primary,
+            record.timestamp(),
+            record.index_values(),
+            record.context(),
+        )
+    }
+
+    /// Like `index_entries`, for a record with `timestamp`, `index_values` and `context`.
+    fn entries_of(
+        &self,
+        primary: &Key,
+        timestamp: i64,
+        index_values: Vec<IndexValue>,
+        context: Option<&Context>,
+    ) -> Result<Vec<(Cow<'static, str>, Key)>> {
+        let mut entries = vec![(
+            Cow::Borrowed(TIMESTAMP_INDEX),
+            timestamp_index_prefix(timestamp).with_key(primary.as_bytes()),
+        )];
+        for index_value in index_values {
+            entries.push((
+                index_value.index,
+                index_value.key.with_key(primary.as_bytes()),
+            ));
+        }
+        if let Some(context) = context {
+            for label in self.listed_label_indexes()?.into_keys() {
+                let Some(value) = context.get(&label) else {
+                    continue;
+                };
+                let key = Key::new().with_str(value).with_key(primary.as_bytes());
+                entries.push((Cow::Owned(label_index(&label)), key));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The keys of every label index created so far, and whether each was backfilled yet.
+    fn listed_label_indexes(&self) -> Result<BTreeMap<String, bool>> {
+        match self.backend.metadata(LABEL_INDEXES)? {
+            Some(listed) => Ok(bincode::deserialize(&listed)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    fn list_label_indexes(&self, listed: &BTreeMap<String, bool>) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.set_metadata(LABEL_INDEXES, &bincode::serialize(listed)?);
+        self.backend.write_batch(batch)
+    }
+
+    /// Adds `record` and its index entries to `batch`, so they are committed together.
+    pub fn write<T: Storeable>(&self, batch: &mut Batch, record: &T) -> Result<()> {
+        record.validate()?;
+        let partition = self.partition(record.timestamp());
+        let primary = record.primary();
+        let value = self.codec.encode(&write_versioned(record)?)?;
+        batch.put(partition, primary.as_bytes(), &value);
+        for (index, key) in self.index_entries(&primary, record)? {
+            batch.create_index(partition, &index, primary.as_bytes(), key.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Adds the removal of `record` and its index entries to `batch`.
+    pub fn remove<T: Storeable>(&self, batch: &mut Batch, record: &T) -> Result<()> {
+        let partition = self.partition(record.timestamp());
+        let primary = record.primary();
+        batch.delete(partition, primary.as_bytes());
+        for (index, key) in self.index_entries(&primary, record)? {
+            batch.delete_index(partition, &index, key.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Writes every record in `records` in a single batch.
+    pub fn post<T: Storeable>(&self, records: impl IntoIterator<Item = T>) -> Result<()> {
+        let _indexes = self.label_indexes.read()?;
+        let mut batch = Batch::default();
+        for record in records {
+            self.write(&mut batch, &record)?;
+        }
+        self.backend.write_batch(batch)
+    }
+
+    /// Whether `primary` has an entry in the index of `index_value`.
+    fn has_entry(
+        &self,
+        partition: Partition,
+        index_value: &IndexValue,
+        primary: &[u8],
+    ) -> Result<bool> {
+        let key = index_value.key.clone().with_key(primary);
+        self.backend
+            .contains_index(partition, &index_value.index, key.as_bytes())
+    }
+
+    /// Whether `primary` gets past the label matchers `plan` resolved, and has an entry for
+    /// every one of its probes and none of its excludes.
+    fn matches(&self, partition: Partition, plan: &Plan, primary: &[u8]) -> Result<bool> {
+        if !plan.labels.iter().all(|labels| labels.contains(primary)) {
+            return Ok(false);
+        }
+        for probe in &plan.probes {
+            if !self.has_entry(partition, probe, primary)? {
+                return Ok(false);
+            }
+        }
+        for exclude in &plan.excludes {
+            if self.has_entry(partition, exclude, primary)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The primaries in `partition` matching the index filters of `query`, found following `plan`.
+    fn find_in<Q: IndexQuery>(
+        &self,
+        partition: Partition,
+        query: &Q,
+        plan: Plan,
+        after: Option<&Position>,
+    ) -> Result<Primaries<'_>> {
+        if plan.estimate == 0 {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let candidates = match &plan.driver {
+            Access::Timestamp => self.backend.scan_timestamp_index(
+                partition,
+                query.timestamp_start(),
+                query.timestamp_end(),
+                query.order(),
+                after.map(Position::as_bytes),
+            )?,
+            Access::Index(index_value) => self.backend.scan_index(
+                partition,
+                &index_value.index,
+                index_value.key.as_bytes(),
+            )?,
+        };
+        Ok(Box::new(candidates.filter_map(move |primary| {
+            primary
+                .and_then(|primary| {
+                    Ok(self.matches(partition, &plan, &primary)?.then_some(primary))
+                })
+                .transpose()
+        })))
+    }
+
+    /// The records in `partition` matching `query` past `after`, in the order of the query.
+    fn query_partition<'a, T, Q>(
+        &'a self,
+        partition: Partition,
+        query: &Q,
+        filters: &LabelFilters,
+        after: Option<Position>,
+    ) -> Result<Box<dyn Iterator<Item = Result<T>> + Send + 'a>>
+    where
+        T: Storeable + 'a,
+        Q: IndexQuery,
+    {
+        let plan = Plan::new(&self.backend, partition, query, filters)?;
+        let sorted = plan.driver == Access::Timestamp;
+        let range = plan
+            .check_timestamp
+            .then(|| (query.timestamp_start(), query.timestamp_end()));
+        let records = Records {
+            backend: &self.backend,
+            codec: &self.codec,
+            partition,
+            primaries: self.find_in(partition, query, plan, after.as_ref())?,
+            range,
+            matchers: filters.matchers.clone(),
+            values: Vec::new().into_iter(),
+            _record: PhantomData,
+        };
+        if sorted {
+            return Ok(Box::new(records));
+        }
+        // Other indexes are sorted by their own key, so the partition is put in order here. The
+        // plan only takes them with a limit or few enough entries to hold
+        let records = sort_records(records, query.order(), after.as_ref(), query.limit())?;
+        Ok(Box::new(records.into_iter().map(Ok)))
+    }
+
+    /// Every record matching `query`, read from the backend as the iterator advances.
+    ///
+    /// Records come in timestamp order, starting past the continuation of the query if it has
+    /// one and stopping at its limit.
+    pub fn query_iter<'a, T, Q>(
+        &'a self,
+        query: &'a Q,
+    ) -> Result<impl Iterator<Item = Result<T>> + Send + 'a>
+    where
+        T: Storeable + 'a,
+        Q: IndexQuery,
+    {
+        let (mut start, mut end) = (query.timestamp_start(), query.timestamp_end());
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err(StorefulError::InvalidQueryRange);
+            }
+        }
+        let after = query.continuation().map(Position::parse).transpose()?;
+        // Partitions the previous pages went through entirely are skipped
+        if let Some(after) = &after {
+            let timestamp = after.timestamp();
+            match query.order() {
+                Order::Ascending => start = Some(start.map_or(timestamp, |s| s.max(timestamp))),
+                Order::Descending => end = Some(end.map_or(timestamp, |e| e.min(timestamp))),
+            }
+        }
+        // Only a backfilled index has an entry for every record with its label
+        let labels: Vec<String> = self
+            .listed_label_indexes()?
+            .into_iter()
+            .filter_map(|(label, backfilled)| backfilled.then_some(label))
+            .collect();
+        let filters =
+            LabelFilters::new(query.label_matchers()?, T::INDEXES).with_label_indexes(&labels);
+        let mut partitions = self.partitions(start, end)?;
+        if query.order() == Order::Descending {
+            partitions.reverse();
+        }
+        let records = partitions.into_iter().flat_map(move |partition| {
+            match self.query_partition(partition, query, &filters, after.clone()) {
+                Ok(records) => records,
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        });
+        Ok(records.take(query.limit().unwrap_or(usize::MAX)))
+    }
+
+    /// Every record matching `query`.
+    pub fn query<T: Storeable, Q: IndexQuery>(&self, query: &Q) -> Result<Vec<T>> {
+        self.query_iter(query)?.collect()
+    }
+
+    /// The records matching `query`, with a continuation token if its limit cut them short.
+    pub fn query_page<T: Storeable, Q: IndexQuery>(&self, query: &Q) -> Result<Page<T>> {
+        let records: Vec<T> = self.query(query)?;
+        let continuation = match query.limit() {
+            Some(limit) if records.len() == limit => {
+                records.last().map(|record| Position::of(record).token())
+            }
+            _ => None,
+        };
+        Ok(Page {
+            records,
+            continuation,
+        })
+    }
+
+    /// Removes every record matching `query`, returning how many were removed.
+    ///
+    /// Records are removed a chunk at a time as the query reads them, so a large range is never
+    /// held in memory at once.
+    pub fn delete<T: Storeable, Q: IndexQuery>(&self, query: &Q) -> Result<usize> {
+        let mut records = self.query_iter::<T, Q>(query)?;
+        let mut count = 0;
+        loop {
+            let chunk = (&mut records)
+                .take(FETCH_CHUNK)
+                .collect::<Result<Vec<_>>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+            let _indexes = self.label_indexes.read()?;
+            let mut batch = Batch::default();
+            for record in &chunk {
+                self.remove(&mut batch, record)?;
+            }
+            self.backend.write_batch(batch)?;
+            count += chunk.len();
+        }
+        Ok(count)
+    }
+
+    /// Removes every record with a timestamp before `before`.
+    ///
+    /// Whole partitions go at once, only the one `before` falls in is cleaned up key by key.
+    pub fn expire<T: Storeable>(&self, before: i64) -> Result<Expired> {
+        let partitions = self.drop_partitions_before(before)?;
+        let records = self.delete::<T, _>(&TimestampRange {
+            start: None,
+            end: Some(before.saturating_sub(1)),
+        })?;
+        Ok(Expired {
+            partitions,
+            records,
+        })
+    }
+
+    /// Rewrites every record written as an older version of `T` as its current one, returning
+    /// how many were rewritten.
+    ///
+    /// Records are rewritten a chunk at a time, so this can run while the store is in use,
+    /// and a record posted meanwhile is never replaced by its older copy. The older copy goes
+    /// along with its index entries, in case its current version is stored under another key.
+    /// Those are the entries of its upgrade, unless its version was added to the decoders of
+    /// `T` with `Decoders::with_indexed`.
+    pub fn migrate<T: Storeable>(&self) -> Result<usize> {
+        let decoders = T::decoders();
+        self.for_each_record::<T>(|batch, stored, record| {
+            if stored.version == T::VERSION {
+                return Ok(false);
+            }
+            let primary = Key::new().with_key(stored.primary);
+            let (_, body) = split_versioned(stored.value)?;
+            let entries = match decoders.indexed(stored.version, body)? {
+                Some(old) => self.entries_of(
+                    &primary,
+                    old.timestamp,
+                    old.index_values,
+                    old.context.as_ref(),
+                )?,
+                None => self.index_entries(&primary, &record)?,
+            };
+            batch.delete(stored.partition, stored.primary);
+            for (index, key) in entries {
+                batch.delete_index(stored.partition, &index, key.as_bytes());
+            }
+            self.write(batch, &record)?;
+            Ok(true)
+        })
+    }
+
+    /// The indexes records and samples can be looked up by.
+    pub fn indexes(&self) -> Result<Vec<String>> {
+        self.backend.trees()
+    }
+
+    /// Indexes the records of `T` by the value of their label `key` from now on, and backfills
+    /// the index from those already stored. Returns how many records were indexed.
+    ///
+    /// Records are then found with `IndexValue::label`. Creating an index that already exists
+    /// does nothing, while one whose backfill was interrupted is backfilled again. Writes in
+    /// flight finish before the index is created and every later one writes to it, so only the
+    /// backfill of each chunk of records holds them up.
+    ///
+    /// The index is listed in the metadata of the store, so it is kept even if nothing was
+    /// written to it yet.
+    pub fn create_label_index<T: Storeable>(&self, key: &str) -> Result<usize> {
+        validate_key(key)?;
+        let index = label_index(key);
+        {
+            let _indexes = self.label_indexes.write()?;
+            let mut listed = self.listed_label_indexes()?;
+            match listed.get(key) {
+                Some(true) => return Ok(0),
+                Some(false) => self.backend.create_tree(&index)?,
+                None => {
+                    // Left behind by a drop that was interrupted, entries and all
+                    if self.backend.trees()?.contains(&index) {
+                        self.backend.drop_tree(&index)?;
+                    }
+                    self.backend.create_tree(&index)?;
+                    listed.insert(key.into(), false);
+                    self.list_label_indexes(&listed)?;
+                }
+            }
+        }
+        let indexed = self.for_each_record::<T>(|batch, _, record| {
+            let Some(value) = record.context().and_then(|context| context.get(key)) else {
+                return Ok(false);
+            };
+            let primary = record.primary();
+            let entry = Key::new().with_str(value).with_key(primary.as_bytes());
+            batch.create_index(
+                self.partition(record.timestamp()),
+                &index,
+                primary.as_bytes(),
+                entry.as_bytes(),
+            );
+            Ok(true)
+        })?;
+        let _indexes = self.label_indexes.write()?;
+        let mut listed = self.listed_label_indexes()?;
+        // Unless it was dropped meanwhile
+        if let Some(backfilled) = listed.get_mut(key) {
+            *backfilled = true;
+            self.list_label_indexes(&listed)?;
+        }
+        Ok(indexed)
+    }
+
+    /// Removes the index on label `key` along with its entries, once writes in flight are done.
+    pub fn drop_label_index(&self, key: &str) -> Result<()> {
+        let _indexes = self.label_indexes.write()?;
+        let index = label_index(key);
+        let mut listed = self.listed_label_indexes()?;
+        if listed.remove(key).is_none() {
+            return Err(StorefulError::IndexNotFound(index));
+        }
+        self.list_label_indexes(&listed)?;
+        self.backend.drop_tree(&index)
+    }
+
+    /// Runs `f` on every stored record of `T` along with where and as what it was stored,
+    /// writing what it adds to the batch a chunk of records at a time. Returns how many
+    /// records `f` returned `true` for.
+    ///
+    /// Writes wait while a chunk is read and its batch written, so none of them can change a
+    /// record between `f` seeing it and what `f` added being written.
+    fn for_each_record<T: Storeable>(
+        &self,
+        mut f: impl FnMut(&mut Batch, Stored<'_>, T) -> Result<bool>,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for partition in self.backend.partitions()? {
+            let mut primaries =
+                self.backend
+                    .scan_timestamp_index(partition, None, None, Order::Ascending, None)?;
+            loop {
+                let chunk = (&mut primaries)
+                    .take(FETCH_CHUNK)
+                    .collect::<Result<Vec<_>>>()?;
+                if chunk.is_empty() {
+                    break;
+                }
+                let _indexes = self.label_indexes.write()?;
+                let mut batch = Batch::default();
+                for primary in chunk {
+                    let Some(value) = self.backend.get(partition, &primary)? else {
+                        continue;
+                    };
+                    let value = self.codec.decode(&value)?;
+                    let (version, record) = read_versioned::<T>(&value)?;
+                    let stored = Stored {
+                        partition,
+                        primary: &primary,
+                        value: &value,
+                        version,
+                    };
+                    if f(&mut batch, stored, record)? {
+                        count += 1;
+                    }
+                }
+                self.backend.write_batch(batch)?;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Where a record `for_each_record` runs on is stored, and what as.
+struct Stored<'a> {
+    partition: Partition,
+    primary: &'a [u8],
+    /// The value behind its version header, once decompressed.
+    value: &'a [u8],
+    version: u16,
+}
+
+/// Puts records read in index order into `order`, skipping those up to `after`.
+///
+/// With a `limit`, no more than that many are kept past each sort, since no partition can
+/// contribute more to the results than that.
+fn sort_records<T: Storeable>(
+    records: impl Iterator<Item = Result<T>>,
+    order: Order,
+    after: Option<&Position>,
+    limit: Option<usize>,
+) -> Result<Vec<T>> {
+    let sort = |sorted: &mut Vec<(Position, T)>| {
+        sorted.sort_by(|(a, _), (b, _)| match order {
+            Order::Ascending => a.cmp(b),
+            Order::Descending => b.cmp(a),
+        });
+        if let Some(limit) = limit {
+            sorted.truncate(limit);
+        }
+    };
+    let mut sorted = Vec::new();
+    for record in records {
+        let record = record?;
+        let position = Position::of(&record);
+        if after.is_some_and(|after| !position.is_past(after, order)) {
+            continue;
+        }
+        sorted.push((position, record));
+        if limit.is_some_and(|limit| sorted.len() >= limit.saturating_mul(2).max(FETCH_CHUNK)) {
+            sort(&mut sorted);
+        }
+    }
+    sort(&mut sorted);
+    Ok(sorted.into_iter().map(|(_, record)| record).collect())
+}
+
+/// The records behind a stream of primaries, fetched from the backend a chunk at a time.
+struct Records<'a, B, T> {
+    backend: &'a B,
+    codec: &'a Codec,
+    partition: Partition,
+    primaries: Primaries<'a>,
+    /// The range records still have to be checked against, when the plan didn't scan it.
+    range: Option<(Option<i64>, Option<i64>)>,
+    /// Conditions on the labels of records, which no index answers. Every candidate is read and
+    /// decoded to check them, so they cost as much as the rest of the query lets through.
+    matchers: Vec<LabelMatcher>,
+    values: std::vec::IntoIter<Box<[u8]>>,
+    _record: PhantomData<fn() -> T>,
+}
+
+impl<B, T> Records<'_, B, T>
+where
+    B: BackendDatabase,
+    T: Storeable,
+{
+    /// Fetches the values of the next chunk of primaries, `false` once they have run out.
+    fn fetch(&mut self) -> Result<bool> {
+        let chunk = (&mut self.primaries)
+            .take(FETCH_CHUNK)
+            .collect::<Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            return Ok(false);
+        }
+        self.values = self.backend.get_multi(self.partition, &chunk)?.into_iter();
+        Ok(true)
+    }
+}
+
+impl<B, T> Iterator for Records<'_, B, T>
+where
+    B: BackendDatabase,
+    T: Storeable,
+{
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(value) = self.values.next() else {
+                match self.fetch() {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(e) => return Some(Err(e)),
+                }
+            };
+            let record = self
+                .codec
+                .decode(&value)
+                .and_then(|value| Ok(read_versioned::<T>(&value)?.1));
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some((start, end)) = self.range {
+                let timestamp = record.timestamp();
+                if start.is_some_and(|start| timestamp < start)
+                    || end.is_some_and(|end| timestamp > end)
+                {
+                    continue;
+                }
+            }
+            if !self
+                .matchers
+                .iter()
+                .all(|matcher| matcher.matches_context(record.context()))
+            {
+                continue;
+            }
+            return Some(Ok(record));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{memory::MemoryBackend, sled::SledBackend, Context, ContextValue, Versioned};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Request {
+        timestamp: i64,
+        context: Context,
+    }
+
+    impl Versioned for Request {}
+
+    impl Storeable for Request {
+        const INDEXES: &'static [&'static str] = &[];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+
+        fn context(&self) -> Option<&Context> {
+            Some(&self.context)
+        }
+    }
+
+    struct ByLabel(&'static str, &'static str);
+
+    impl IndexQuery for ByLabel {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            vec![IndexValue::label(self.0, self.1)]
+        }
+    }
+
+    #[test]
+    fn manages_label_indexes() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Request::trees()));
+        let request = |timestamp, service: &str| Request {
+            timestamp,
+            context: Context::default().with_value("service.name", service),
+        };
+        let timestamps = |query: &ByLabel| -> Result<Vec<i64>> {
+            let requests: Vec<Request> = storeful.query(query)?;
+            Ok(requests.iter().map(|request| request.timestamp).collect())
+        };
+        storeful
+            .post([request(0, "api"), request(1, "web"), request(2, "api")])
+            .unwrap();
+        let api = ByLabel("service.name", "api");
+        assert!(matches!(
+            timestamps(&api),
+            Err(StorefulError::IndexNotFound(_))
+        ));
+
+        // Records stored before the index existed are backfilled, later ones indexed on write
+        assert_eq!(
+            storeful
+                .create_label_index::<Request>("service.name")
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            storeful
+                .create_label_index::<Request>("service.name")
+                .unwrap(),
+            0
+        );
+        storeful.post([request(3, "api")]).unwrap();
+        assert_eq!(timestamps(&api).unwrap(), [0, 2, 3]);
+        assert!(storeful
+            .indexes()
+            .unwrap()
+            .contains(&"label:service.name".to_string()));
+
+        // Removed records take their label entries with them
+        storeful.delete::<Request, _>(&api).unwrap();
+        assert_eq!(timestamps(&ByLabel("service.name", "web")).unwrap(), [1]);
+        assert_eq!(timestamps(&api).unwrap(), Vec::<i64>::new());
+
+        storeful.drop_label_index("service.name").unwrap();
+        assert!(matches!(
+            timestamps(&api),
+            Err(StorefulError::IndexNotFound(_))
+        ));
+        // Writes carry on without the index
+        storeful.post([request(4, "api")]).unwrap();
+        assert!(storeful.drop_label_index("service.name").is_err());
+    }
+
+    #[test]
+    fn keeps_label_indexes_created_before_any_partition() {
+        let db = ::sled::Config::new().temporary(true).open().unwrap();
+        let sled = SledBackend::in_db(db, "test".into(), &Request::trees()).unwrap();
+        let storeful = Storeful::new(sled).open().unwrap();
+        storeful
+            .create_label_index::<Request>("service.name")
+            .unwrap();
+
+        // Nothing was written to the index, so the backend has no tree of it to find
+        let sled = storeful
+            .backend
+            .store("test".into(), &Request::trees())
+            .unwrap();
+        let reopened = Storeful::new(sled).open().unwrap();
+        reopened
+            .post([Request {
+                timestamp: 0,
+                context: Context::default().with_value("service.name", "api"),
+            }])
+            .unwrap();
+        let requests: Vec<Request> = reopened.query(&ByLabel("service.name", "api")).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            reopened
+                .create_label_index::<Request>("service.name")
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn indexes_posts_during_the_backfill() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Request::trees()));
+        let request = |timestamp| Request {
+            timestamp,
+            context: Context::default().with_value("service.name", "api"),
+        };
+        let stored = FETCH_CHUNK as i64 * 4;
+        storeful.post((0..stored).map(request)).unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for timestamp in stored..stored + 100 {
+                    storeful.post([request(timestamp)]).unwrap();
+                }
+            });
+            storeful
+                .create_label_index::<Request>("service.name")
+                .unwrap();
+        });
+        let requests: Vec<Request> = storeful.query(&ByLabel("service.name", "api")).unwrap();
+        assert_eq!(requests.len(), stored as usize + 100);
+    }
+
+    #[test]
+    fn keeps_the_partition_width() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Request::trees()))
+            .with_partition_width(Duration::from_secs(3600))
+            .open()
+            .unwrap();
+        let reopened = Storeful::new(storeful.backend)
+            .with_partition_width(Duration::from_secs(3600))
+            .open()
+            .unwrap();
+        assert!(matches!(
+            Storeful::new(reopened.backend).open(),
+            Err(StorefulError::PartitionWidthMismatch {
+                stored: 3_600_000_000_000,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn deletes_past_a_chunk() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Request::trees()));
+        let count = FETCH_CHUNK as i64 * 3 + 1;
+        storeful
+            .post((0..count).map(|timestamp| Request {
+                timestamp,
+                context: Context::default(),
+            }))
+            .unwrap();
+
+        let older = TimestampRange {
+            start: None,
+            end: Some(count - 2),
+        };
+        assert_eq!(
+            storeful.delete::<Request, _>(&older).unwrap(),
+            count as usize - 1
+        );
+        let remaining: Vec<Request> = storeful.query(&TimestampRange::default()).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].timestamp, count - 1);
+    }
+
+    struct Limited(ByLabel, Option<usize>);
+
+    impl IndexQuery for Limited {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            self.0.index_values()
+        }
+
+        fn limit(&self) -> Option<usize> {
+            self.1
+        }
+    }
+
+    #[test]
+    fn streams_broad_indexes_without_a_limit() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Request::trees()));
+        storeful
+            .create_label_index::<Request>("service.name")
+            .unwrap();
+        storeful
+            .post((0..4000).map(|timestamp| {
+                Request {
+                    timestamp,
+                    context: Context::default()
+                        .with_value("service.name", ["api", "web"][timestamp as usize % 2]),
+                }
+            }))
+            .unwrap();
+        let plan = |limit| {
+            let query = Limited(ByLabel("service.name", "api"), limit);
+            Plan::new(
+                &storeful.backend,
+                storeful.partition(0),
+                &query,
+                &LabelFilters::default(),
+            )
+            .unwrap()
+        };
+
+        // Too many entries to sort in memory, so the timestamp index reads them in order
+        assert_eq!(plan(None).driver, Access::Timestamp);
+        assert_eq!(
+            plan(Some(10)).driver,
+            Access::Index(IndexValue::label("service.name", "api"))
+        );
+        let requests: Vec<Request> = storeful
+            .query(&Limited(ByLabel("service.name", "api"), None))
+            .unwrap();
+        assert_eq!(requests.len(), 2000);
+        assert!(requests
+            .iter()
+            .enumerate()
+            .all(|(i, request)| request.timestamp == i as i64 * 2));
+    }
+
+    struct Matching(&'static str);
+
+    impl IndexQuery for Matching {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+
+        fn label_matchers(&self) -> Result<Vec<LabelMatcher>> {
+            Ok(vec![LabelMatcher::parse("context", self.0)?])
+        }
+    }
+
+    #[test]
+    fn filters_records_by_label() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Request::trees()));
+        let contexts = [
+            Context::default().with_value("service.name", "api"),
+            Context::default().with_value("service.name", "web"),
+            Context::default(),
+        ];
+        storeful
+            .post(
+                contexts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(timestamp, context)| Request {
+                        timestamp: timestamp as i64,
+                        context,
+                    }),
+            )
+            .unwrap();
+        let timestamps = |matcher| {
+            let requests: Vec<Request> = storeful.query(&Matching(matcher)).unwrap();
+            requests
+                .iter()
+                .map(|request| request.timestamp)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps("service.name!=\"api\""), [1, 2]);
+        assert_eq!(timestamps("service.name=~\"a.*\""), [0]);
+        assert_eq!(timestamps("service.name"), [0, 1]);
+        assert_eq!(timestamps("!service.name"), [2]);
+    }
+
+    #[test]
+    fn matches_labels_through_a_label_index() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Request::trees()));
+        let contexts = [
+            Context::default().with_value("service.name", "api"),
+            Context::default().with_value("service.name", "web"),
+            Context::default(),
+            Context::default().with_value("service.name", "admin"),
+            Context::default().with_value("service.name", ""),
+        ];
+        storeful
+            .post(
+                contexts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(timestamp, context)| Request {
+                        timestamp: timestamp as i64,
+                        context,
+                    }),
+            )
+            .unwrap();
+        let timestamps = |matcher| {
+            let requests: Vec<Request> = storeful.query(&Matching(matcher)).unwrap();
+            requests
+                .iter()
+                .map(|request| request.timestamp)
+                .collect::<Vec<_>>()
+        };
+        let matchers = [
+            "service.name=\"api\"",
+            "service.name!=\"api\"",
+            "service.name=\"\"",
+            "service.name!=\"\"",
+            "service.name=~\"a.*\"",
+            "service.name!~\"a.*\"",
+            "service.name=~\".*\"",
+            "service.name",
+            "!service.name",
+        ];
+        let checked = matchers.map(timestamps);
+        storeful
+            .create_label_index::<Request>("service.name")
+            .unwrap();
+        // Answered the same from the index as from the records themselves
+        assert_eq!(matchers.map(timestamps), checked);
+        assert_eq!(checked[4], [0, 3]);
+        assert_eq!(checked[8], [2]);
+
+        let labels = ["service.name".to_string()];
+        let plan = |matcher| {
+            let filters = LabelFilters::new(Matching(matcher).label_matchers().unwrap(), &[])
+                .with_label_indexes(&labels);
+            assert!(filters.matchers.is_empty());
+            Plan::new(
+                &storeful.backend,
+                storeful.partition(0),
+                &Matching(matcher),
+                &filters,
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            plan("service.name=\"api\"").driver,
+            Access::Index(IndexValue::label("service.name", "api"))
+        );
+        assert_eq!(plan("service.name=~\"a.*\"").labels.len(), 1);
+        // Nothing is read from a partition none of the entries match in
+        assert_eq!(plan("service.name=~\"db\"").estimate, 0);
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Event {
+        timestamp: i64,
+        context: Context,
+    }
+
+    impl Versioned for Event {}
+
+    impl Storeable for Event {
+        const INDEXES: &'static [&'static str] = &["context"];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            self.context
+                .values()
+                .iter()
+                .map(|context_value| IndexValue::context_value("context", context_value))
+                .collect()
+        }
+
+        fn context(&self) -> Option<&Context> {
+            Some(&self.context)
+        }
+    }
+
+    #[test]
+    fn matches_labels_through_a_declared_index() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Event::trees()));
+        let contexts = [
+            Context::default().with_value("service.name", "api"),
+            Context::default().with_value("service.name", "web"),
+            Context::default(),
+        ];
+        storeful
+            .post(
+                contexts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(timestamp, context)| Event {
+                        timestamp: timestamp as i64,
+                        context,
+                    }),
+            )
+            .unwrap();
+        let api = ContextValue {
+            key: "service.name".into(),
+            value: "api".into(),
+        };
+        let api = IndexValue::context_value("context", &api);
+        let plan = |matcher| {
+            let filters =
+                LabelFilters::new(Matching(matcher).label_matchers().unwrap(), &["context"]);
+            Plan::new(
+                &storeful.backend,
+                storeful.partition(0),
+                &Matching(matcher),
+                &filters,
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            plan("service.name=\"api\"").driver,
+            Access::Index(api.clone())
+        );
+        assert_eq!(plan("service.name!=\"api\"").excludes, [api]);
+
+        let timestamps = |matcher| {
+            let events: Vec<Event> = storeful.query(&Matching(matcher)).unwrap();
+            events
+                .iter()
+                .map(|event| event.timestamp)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps("service.name=\"api\""), [0]);
+        assert_eq!(timestamps("service.name!=\"api\""), [1, 2]);
+        assert_eq!(timestamps("service.name=\"\""), [2]);
+        assert_eq!(timestamps("service.name=\"db\""), Vec::<i64>::new());
+    }
+}
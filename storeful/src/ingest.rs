@@ -0,0 +1,284 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
+    time::{timeout_at, Instant},
+};
+
+use crate::prelude::*;
+
+/// How an `IngestBuffer` groups posts into writes and how much it lets pile up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IngestConfig {
+    /// The most records written in one batch.
+    pub batch_size: usize,
+    /// How long the first record of a batch waits for more to join it.
+    pub batch_delay: Duration,
+    /// The most records waiting to be written before posts are refused.
+    pub queue_limit: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 1024,
+            batch_delay: Duration::from_millis(5),
+            queue_limit: 64 * 1024,
+        }
+    }
+}
+
+/// Parses `--ingest-queue-limit`, refusing more records than the queue can count.
+pub fn parse_queue_limit(s: &str) -> std::result::Result<usize, String> {
+    let limit: usize = s
+        .parse()
+        .map_err(|_| format!("invalid queue limit: {}", s))?;
+    if limit > Semaphore::MAX_PERMITS {
+        return Err(format!(
+            "queue limit {} is above the most of {}",
+            limit,
+            Semaphore::MAX_PERMITS
+        ));
+    }
+    Ok(limit)
+}
+
+/// Records of a single post waiting for their batch to be written.
+struct Pending<T> {
+    records: Vec<T>,
+    done: oneshot::Sender<Result<()>>,
+    permit: OwnedSemaphorePermit,
+}
+
+/// Groups the records of concurrent posts into larger writes.
+///
+/// Posts return once their batch is written, so a successful post is as durable as a direct
+/// write. When `queue_limit` records are already waiting, posts are refused right away instead.
+/// A batch that fails is written again one post at a time, so a post only fails for its own
+/// records.
+pub struct IngestBuffer<T> {
+    sender: mpsc::UnboundedSender<Pending<T>>,
+    queue: Arc<Semaphore>,
+    queue_limit: usize,
+}
+
+impl<T> IngestBuffer<T>
+where
+    T: Clone + Send + 'static,
+{
+    /// Starts the task writing batches with `write`, which has to run in a tokio runtime.
+    ///
+    /// A `queue_limit` above `Semaphore::MAX_PERMITS` is lowered to it.
+    pub fn new<F, Fut>(config: IngestConfig, write: F) -> Self
+    where
+        F: Fn(Vec<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let queue_limit = config.queue_limit.min(Semaphore::MAX_PERMITS);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::task::spawn(run(receiver, config, write));
+        Self {
+            sender,
+            queue: Arc::new(Semaphore::new(queue_limit)),
+            queue_limit,
+        }
+    }
+
+    /// Queues `records` to be written with those of other posts, returning once they are.
+    pub async fn post(&self, records: Vec<T>) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        // Permits are counted in `u32`, a post can't take up more of the queue than that
+        let permits = u32::try_from(records.len())
+            .ok()
+            .filter(|_| records.len() <= self.queue_limit)
+            .ok_or(StorefulError::IngestTooLarge(records.len()))?;
+        let permit = self
+            .queue
+            .clone()
+            .try_acquire_many_owned(permits)
+            .map_err(|_| StorefulError::IngestQueueFull)?;
+        let (done, written) = oneshot::channel();
+        self.sender
+            .send(Pending {
+                records,
+                done,
+                permit,
+            })
+            .map_err(|_| StorefulError::IngestStopped)?;
+        written.await.map_err(|_| StorefulError::IngestStopped)?
+    }
+}
+
+/// Writes whatever has been posted, waiting up to `batch_delay` for a batch to fill up.
+async fn run<T, F, Fut>(
+    mut receiver: mpsc::UnboundedReceiver<Pending<T>>,
+    config: IngestConfig,
+    write: F,
+) where
+    T: Clone,
+    F: Fn(Vec<T>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    while let Some(first) = receiver.recv().await {
+        let deadline = Instant::now() + config.batch_delay;
+        let mut size = first.records.len();
+        let mut pending = vec![first];
+        while size < config.batch_size {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(next)) => {
+                    size += next.records.len();
+                    pending.push(next);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        // The permits stay held until the write is done, so the queue covers it as well
+        if pending.len() == 1 {
+            let mut single = pending.pop().unwrap();
+            let result = write(std::mem::take(&mut single.records)).await;
+            finish(single, &result);
+            continue;
+        }
+        let mut records = Vec::with_capacity(size);
+        for pending in &pending {
+            records.extend(pending.records.iter().cloned());
+        }
+        let result = write(records).await;
+        if result.is_ok() {
+            for pending in pending {
+                finish(pending, &result);
+            }
+            continue;
+        }
+        // The batch doesn't tell which post failed it, so each is tried again on its own
+        for mut pending in pending {
+            let result = write(std::mem::take(&mut pending.records)).await;
+            finish(pending, &result);
+        }
+    }
+}
+
+/// Tells the post waiting on `pending` how its write went.
+fn finish<T>(pending: Pending<T>, result: &Result<()>) {
+    let result = match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(StorefulError::IngestFailed(e.to_string())),
+    };
+    let Pending { done, permit, .. } = pending;
+    // The post may have been given up on, there is no one left to tell then
+    let _ = done.send(result);
+    // Only now is the space the post took up in the queue given back
+    drop(permit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn coalesces_concurrent_posts() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let written = batches.clone();
+        let config = IngestConfig {
+            batch_size: 8,
+            batch_delay: Duration::from_secs(60),
+            queue_limit: 64,
+        };
+        let buffer = IngestBuffer::new(config, move |records: Vec<u32>| {
+            written.lock().unwrap().push(records.len());
+            async { Ok(()) }
+        });
+
+        // Full batches are written without waiting out the delay
+        let posts = (0..16).map(|i| buffer.post(vec![i]));
+        for result in futures::future::join_all(posts).await {
+            result.unwrap();
+        }
+        assert_eq!(*batches.lock().unwrap(), [8, 8]);
+    }
+
+    #[tokio::test]
+    async fn refuses_posts_past_the_queue_limit() {
+        let (release, released) = tokio::sync::watch::channel(false);
+        let config = IngestConfig {
+            batch_size: 1,
+            batch_delay: Duration::ZERO,
+            queue_limit: 2,
+        };
+        let buffer = Arc::new(IngestBuffer::new(config, move |_: Vec<u32>| {
+            let mut released = released.clone();
+            async move {
+                let _ = released.wait_for(|released| *released).await;
+                Ok(())
+            }
+        }));
+
+        let queued: Vec<_> = (0..2)
+            .map(|i| {
+                let buffer = buffer.clone();
+                tokio::task::spawn(async move { buffer.post(vec![i]).await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+
+        assert!(matches!(
+            buffer.post(vec![2]).await,
+            Err(StorefulError::IngestQueueFull)
+        ));
+        assert!(matches!(
+            buffer.post(vec![3, 4, 5]).await,
+            Err(StorefulError::IngestTooLarge(3))
+        ));
+
+        release.send(true).unwrap();
+        for post in queued {
+            post.await.unwrap().unwrap();
+        }
+        buffer.post(vec![6]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn caps_the_queue_limit() {
+        assert_eq!(parse_queue_limit("64"), Ok(64));
+        assert!(parse_queue_limit(&usize::MAX.to_string()).is_err());
+        assert!(parse_queue_limit("-1").is_err());
+
+        let config = IngestConfig {
+            queue_limit: usize::MAX,
+            ..IngestConfig::default()
+        };
+        let buffer = IngestBuffer::new(config, |_: Vec<u32>| async { Ok(()) });
+        buffer.post(vec![1, 2]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_only_the_bad_post_of_a_batch() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let written = batches.clone();
+        let config = IngestConfig {
+            batch_size: 4,
+            batch_delay: Duration::from_secs(60),
+            queue_limit: 64,
+        };
+        let buffer = IngestBuffer::new(config, move |records: Vec<u32>| {
+            written.lock().unwrap().push(records.clone());
+            let result = match records.contains(&0) {
+                true => Err(StorefulError::InvalidTimestamp("0".into())),
+                false => Ok(()),
+            };
+            async move { result }
+        });
+
+        let (bad, good) = tokio::join!(buffer.post(vec![0, 1]), buffer.post(vec![2, 3]));
+        assert!(matches!(bad, Err(StorefulError::IngestFailed(_))));
+        good.unwrap();
+        assert_eq!(
+            *batches.lock().unwrap(),
+            [vec![0, 1, 2, 3], vec![0, 1], vec![2, 3]]
+        );
+    }
+}
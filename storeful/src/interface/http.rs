@@ -1,26 +1,34 @@
 use std::{
+    convert::Infallible,
     future::Future,
     pin::Pin,
-    sync::{Arc, PoisonError},
+    sync::{Arc, Mutex as StdMutex, PoisonError},
+    task::{Context, Poll},
 };
 
-use crate::{prelude::*, ModelEndpoints, Query, Storeful};
-use http_body_util::{BodyExt, Full};
+use crate::{prelude::*, ModelEndpoints, Query, RetentionStatus};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::{
-    body::{Bytes, Incoming as IncomingBody},
+    body::{Body, Bytes, Frame, Incoming as IncomingBody},
     server::conn::http1::Builder,
     service::Service,
     Request, Response,
 };
 use hyper_util::rt::TokioIo;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::{net::TcpListener, sync::mpsc};
+
+/// How many records a streamed query reads ahead of what the client has received.
+const STREAM_BUFFER: usize = 64;
+
+type HttpBody = BoxBody<Bytes, Infallible>;
 
 pub struct Http;
 
 // impl Interface for Http {
 pub async fn start<T, Q, M>(
-    /*&self,*/ handler: Arc<Mutex<M>>,
+    /*&self,*/ handler: Arc<M>,
+    retention: Arc<StdMutex<RetentionStatus>>,
     host: &str,
     port: u16,
 ) -> Result<()>
@@ -35,6 +43,7 @@ where
 
     let svc = Svc {
         handler,
+        retention,
         _t: std::marker::PhantomData,
         _q: std::marker::PhantomData,
     };
@@ -59,7 +68,8 @@ where
     Q: Query,
     M: ModelEndpoints<T, Q> + Send + Sync + 'static,
 {
-    handler: Arc<Mutex<M>>,
+    handler: Arc<M>,
+    retention: Arc<StdMutex<RetentionStatus>>,
     _t: std::marker::PhantomData<T>,
     _q: std::marker::PhantomData<Q>,
 }
@@ -73,6 +83,7 @@ where
     fn clone(&self) -> Self {
         Self {
             handler: self.handler.clone(),
+            retention: self.retention.clone(),
             _t: std::marker::PhantomData,
             _q: std::marker::PhantomData,
         }
@@ -85,105 +96,494 @@ where
     Q: Query,
     M: ModelEndpoints<T, Q> + Send + Sync + 'static,
 {
-    type Response = Response<Full<Bytes>>;
+    type Response = Response<HttpBody>;
     type Error = hyper::Error;
     type Future =
         Pin<Box<dyn Future<Output = std::result::Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<IncomingBody>) -> Self::Future {
-        fn ok(content: String) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+        fn ok(content: String) -> std::result::Result<Response<HttpBody>, hyper::Error> {
             Ok(Response::builder()
                 .status(200)
                 .header("Content-Type", "application/json")
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"result\": {}}}",
-                    content
-                ))))
+                .body(Full::new(Bytes::from(result_body(&content))).boxed())
+                .unwrap())
+        }
+
+        fn stream(
+            lines: mpsc::Receiver<Bytes>,
+        ) -> std::result::Result<Response<HttpBody>, hyper::Error> {
+            Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "application/x-ndjson")
+                .body(ChannelBody(lines).boxed())
                 .unwrap())
         }
 
-        fn error(error: String) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+        fn error(error: HttpError) -> std::result::Result<Response<HttpBody>, hyper::Error> {
             Ok(Response::builder()
-                .status(500)
+                .status(error.status)
                 .header("Content-Type", "application/json")
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"{}\"}}",
-                    error
-                ))))
+                .body(Full::new(Bytes::from(error_body(&error))).boxed())
                 .unwrap())
         }
 
         let handler = self.handler.clone();
+        let retention = self.retention.clone();
 
         Box::pin(async move {
-            let result = request(handler, req).await;
+            let result = request(handler, retention, req).await;
             match result {
-                Ok(json) => ok(json),
-                Err(e) => error(e.0),
+                Ok(Reply::Json(json)) => ok(json),
+                Ok(Reply::Stream(lines)) => stream(lines),
+                Err(e) => error(e),
             }
         })
     }
 }
 
 async fn request<T, Q, M>(
-    handler: Arc<Mutex<M>>,
+    handler: Arc<M>,
+    retention: Arc<StdMutex<RetentionStatus>>,
     req: Request<IncomingBody>,
-) -> std::result::Result<String, HttpError>
+) -> std::result::Result<Reply, HttpError>
 where
     T: Send + Sync + Serialize + DeserializeOwned + 'static,
     Q: Query,
     M: ModelEndpoints<T, Q> + Send + Sync + 'static,
 {
-    let mut handler = handler.lock().await;
-    if req.method() != hyper::Method::POST {
-        return Err(HttpError("method not allowed".into()));
-    }
     let uri = req.uri().clone();
-    let path = uri.path();
-    let bytes = req.collect().await?;
+    let method = req.method().clone();
+    let bytes = req.collect().await?.to_bytes();
+    route(handler, retention, &method, uri.path(), bytes).await
+}
+
+/// Answers the request for `path`, whose body has been read into `bytes`.
+async fn route<T, Q, M>(
+    handler: Arc<M>,
+    retention: Arc<StdMutex<RetentionStatus>>,
+    method: &hyper::Method,
+    path: &str,
+    bytes: Bytes,
+) -> std::result::Result<Reply, HttpError>
+where
+    T: Send + Sync + Serialize + DeserializeOwned + 'static,
+    Q: Query,
+    M: ModelEndpoints<T, Q> + Send + Sync + 'static,
+{
+    if path == "/admin/retention" {
+        let status = retention.lock()?.clone();
+        return Ok(Reply::Json(serde_json::to_string(&status)?));
+    }
+    if method != hyper::Method::POST {
+        return Err(HttpError::new("method not allowed"));
+    }
     match path {
         "/query" => {
-            let query: Q = serde_json::from_slice(&bytes.to_bytes())?;
+            let query: Q = parse_body(&bytes)?;
             let result = handler.query(query).await?;
-            Ok(serde_json::to_string(&result)?)
+            Ok(Reply::Json(serde_json::to_string(&result)?))
+        }
+        "/query/stream" => {
+            let query: Q = parse_body(&bytes)?;
+            Ok(Reply::Stream(query_stream(handler, query)))
+        }
+        "/admin/migrate" => {
+            let migrated = handler.migrate().await?;
+            Ok(Reply::Json(serde_json::to_string(&migrated)?))
+        }
+        "/delete" => {
+            let query: Q = parse_body(&bytes)?;
+            let deleted = handler.delete(query).await?;
+            Ok(Reply::Json(serde_json::to_string(&deleted)?))
         }
         "/post" => {
-            let model: T = serde_json::from_slice(&bytes.to_bytes())?;
+            let model: T = parse_body(&bytes)?;
             handler.post(model).await?;
-            Ok("ok".to_string())
+            Ok(Reply::Json(serde_json::to_string("ok")?))
         }
         "/post_multi" => {
-            let models: Vec<T> = serde_json::from_slice(&bytes.to_bytes())?;
+            let models: Vec<T> = parse_body(&bytes)?;
             handler.post_multi(models).await?;
-            Ok("ok".to_string())
+            Ok(Reply::Json(serde_json::to_string("ok")?))
+        }
+        _ => Err(HttpError::new("not found")),
+    }
+}
+
+/// The body of a successful reply, `content` being the JSON of the result.
+fn result_body(content: &str) -> String {
+    format!("{{\"result\": {}}}", content)
+}
+
+/// The request body in `bytes`, refused with a 400 when it doesn't hold a `D`, such as a
+/// context giving a key two values.
+fn parse_body<D: DeserializeOwned>(bytes: &Bytes) -> std::result::Result<D, HttpError> {
+    serde_json::from_slice(bytes).map_err(|e| HttpError {
+        status: 400,
+        message: format!("invalid body: {}", e),
+    })
+}
+
+/// Runs `query` in a task of its own, returning the records as they come, one JSON line each.
+///
+/// The status is sent before the first record is read, so a failure partway is reported with a
+/// final `{"error": ...}` line instead.
+fn query_stream<T, Q, M>(handler: Arc<M>, query: Q) -> mpsc::Receiver<Bytes>
+where
+    T: Send + Sync + Serialize + DeserializeOwned + 'static,
+    Q: Query,
+    M: ModelEndpoints<T, Q> + Send + Sync + 'static,
+{
+    let (lines_tx, lines_rx) = mpsc::channel(STREAM_BUFFER);
+    tokio::task::spawn(async move {
+        let (records_tx, mut records_rx) = mpsc::channel::<T>(STREAM_BUFFER);
+        let forward_tx = lines_tx.clone();
+        // Dropping `records_rx` once the client is gone stops the query as well
+        let forward = async move {
+            while let Some(record) = records_rx.recv().await {
+                let line = match serde_json::to_vec(&record) {
+                    Ok(mut line) => {
+                        line.push(b'\n');
+                        line
+                    }
+                    Err(e) => error_line(&HttpError::from(e)),
+                };
+                if forward_tx.send(Bytes::from(line)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let (result, ()) = tokio::join!(handler.query_stream(query, records_tx), forward);
+        if let Err(e) = result {
+            let _ = lines_tx
+                .send(Bytes::from(error_line(&HttpError::from(e))))
+                .await;
         }
-        _ => Err(HttpError("not found".into())),
+    });
+    lines_rx
+}
+
+/// The body of a refused request, `{"error": ...}` with the message escaped as needed.
+fn error_body(error: &HttpError) -> String {
+    serde_json::json!({ "error": error.message }).to_string()
+}
+
+fn error_line(error: &HttpError) -> Vec<u8> {
+    let mut line = error_body(error).into_bytes();
+    line.push(b'\n');
+    line
+}
+
+/// What a request is answered with.
+enum Reply {
+    /// Wrapped as `{"result": ...}`.
+    Json(String),
+    /// Sent chunk by chunk as the lines come in.
+    Stream(mpsc::Receiver<Bytes>),
+}
+
+/// A response body streamed from a channel, ending once every sender is dropped.
+struct ChannelBody(mpsc::Receiver<Bytes>);
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, Infallible>>> {
+        self.0
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| Ok(Frame::data(chunk))))
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct HttpError(String);
+pub struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            status: 500,
+            message: message.into(),
+        }
+    }
+}
 
 impl From<StorefulError> for HttpError {
     fn from(e: StorefulError) -> Self {
-        Self(format!("storeful error: {}", e))
+        // Clients are meant to back off and retry these rather than give up
+        let status = match e {
+            StorefulError::IngestQueueFull => 429,
+            StorefulError::IngestTooLarge(_) => 413,
+            StorefulError::IngestStopped => 503,
+            // Querying an index that doesn't exist won't work any better the second time
+            StorefulError::IndexNotFound(_)
+            | StorefulError::InvalidContextKey(_)
+            | StorefulError::DuplicateContextKey(_)
+            | StorefulError::InvalidMatcher(_)
+            | StorefulError::InvalidTimestamp(_)
+            | StorefulError::InvalidContinuation
+            | StorefulError::InvalidQueryRange => 400,
+            _ => 500,
+        };
+        Self {
+            status,
+            message: format!("storeful error: {}", e),
+        }
     }
 }
 
 impl From<hyper::Error> for HttpError {
     fn from(e: hyper::Error) -> Self {
-        Self(format!("hyper error: {}", e))
+        Self::new(format!("hyper error: {}", e))
     }
 }
 
 impl<T> From<PoisonError<T>> for HttpError {
     fn from(_: PoisonError<T>) -> Self {
-        Self("lock poisoned".to_string())
+        Self::new("lock poisoned")
     }
 }
 
 impl From<serde_json::Error> for HttpError {
     fn from(e: serde_json::Error) -> Self {
-        Self(format!("json error: {}", e))
+        Self::new(format!("json error: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::Sender;
+
+    use super::*;
+    use crate::{
+        memory::MemoryBackend, BlockingStoreful, Context, Expired, IndexQuery, IndexValue, Key,
+        LabelMatcher, Page, Storeable, Storeful, Versioned,
+    };
+
+    #[derive(Serialize, Deserialize)]
+    struct Event {
+        timestamp: i64,
+        context: Context,
+    }
+
+    impl Versioned for Event {}
+
+    impl Storeable for Event {
+        const INDEXES: &'static [&'static str] = &[];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+
+        fn context(&self) -> Option<&Context> {
+            Some(&self.context)
+        }
+    }
+
+    /// Events with the label `key` set to `value`.
+    #[derive(Serialize, Deserialize, Default)]
+    struct ByLabel {
+        key: String,
+        value: String,
+        #[serde(default)]
+        timestamp_start: Option<i64>,
+        #[serde(default)]
+        timestamp_end: Option<i64>,
+        #[serde(default)]
+        continuation: Option<String>,
+        /// Matchers such as `host!="a"` on the other labels.
+        #[serde(default)]
+        matchers: Vec<String>,
+    }
+
+    impl Query for ByLabel {
+        fn from_str(s: &str) -> Self {
+            serde_json::from_str(s).unwrap()
+        }
+
+        fn to_string(&self) -> String {
+            serde_json::to_string(self).unwrap()
+        }
+    }
+
+    impl IndexQuery for ByLabel {
+        fn timestamp_start(&self) -> Option<i64> {
+            self.timestamp_start
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            self.timestamp_end
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            vec![IndexValue::label(&self.key, &self.value)]
+        }
+
+        fn continuation(&self) -> Option<&str> {
+            self.continuation.as_deref()
+        }
+
+        fn label_matchers(&self) -> Result<Vec<LabelMatcher>> {
+            self.matchers
+                .iter()
+                .map(|matcher| LabelMatcher::parse("context", matcher))
+                .collect()
+        }
+    }
+
+    /// Serves `Event`s, the way a model with label indexes would.
+    struct Events(BlockingStoreful<MemoryBackend>);
+
+    impl Events {
+        fn new() -> Self {
+            let memory = MemoryBackend::new("events".into(), &Event::trees());
+            Self(BlockingStoreful::new(Storeful::new(memory)))
+        }
+    }
+
+    impl ModelEndpoints<Event, ByLabel> for Events {
+        async fn post(&self, input: Event) -> Result<()> {
+            self.0.post(vec![input]).await
+        }
+
+        async fn post_multi(&self, multi: Vec<Event>) -> Result<()> {
+            self.0.post(multi).await
+        }
+
+        async fn query(&self, query: ByLabel) -> Result<Page<Event>> {
+            self.0.query_page(query).await
+        }
+
+        async fn query_stream(&self, query: ByLabel, results: Sender<Event>) -> Result<()> {
+            self.0.query_stream(query, results).await
+        }
+
+        async fn delete(&self, query: ByLabel) -> Result<usize> {
+            self.0.delete::<Event, _>(query).await
+        }
+
+        async fn expire(&self, before: i64) -> Result<Expired> {
+            self.0.expire::<Event>(before).await
+        }
+
+        async fn migrate(&self) -> Result<usize> {
+            self.0.migrate::<Event>().await
+        }
+    }
+
+    async fn post(
+        handler: &Arc<Events>,
+        path: &str,
+        body: &str,
+    ) -> std::result::Result<String, u16> {
+        let retention = Arc::new(StdMutex::new(RetentionStatus::new(None)));
+        let reply = route(
+            handler.clone(),
+            retention,
+            &hyper::Method::POST,
+            path,
+            Bytes::from(body.to_string()),
+        )
+        .await;
+        match reply {
+            Ok(Reply::Json(json)) => Ok(json),
+            Ok(Reply::Stream(_)) => Ok("stream".into()),
+            Err(e) => Err(e.status),
+        }
+    }
+
+    /// The message `path` refuses `body` with, read back from the JSON of the 400.
+    async fn refusal(handler: &Arc<Events>, path: &str, body: &str) -> String {
+        let retention = Arc::new(StdMutex::new(RetentionStatus::new(None)));
+        let reply = route(
+            handler.clone(),
+            retention,
+            &hyper::Method::POST,
+            path,
+            Bytes::from(body.to_string()),
+        )
+        .await;
+        let Err(error) = reply else {
+            panic!("{} accepted {}", path, body);
+        };
+        assert_eq!(error.status, 400);
+        let body: serde_json::Value = serde_json::from_str(&error_body(&error)).unwrap();
+        body["error"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_contexts() {
+        let handler = Arc::new(Events::new());
+        let event = |context: &str| format!(r#"{{"timestamp": 0, "context": {}}}"#, context);
+        let duplicate = r#"[{"key": "a", "value": "1"}, {"key": "a", "value": "2"}]"#;
+        assert_eq!(post(&handler, "/post", &event(duplicate)).await, Err(400));
+        let invalid = r#"[{"key": "a b", "value": "1"}]"#;
+        assert_eq!(post(&handler, "/post", &event(invalid)).await, Err(400));
+        assert_eq!(post(&handler, "/post", "not json").await, Err(400));
+        let posted = post(&handler, "/post", &event("[]")).await.unwrap();
+        let posted: serde_json::Value = serde_json::from_str(&result_body(&posted)).unwrap();
+        assert_eq!(posted, serde_json::json!({ "result": "ok" }));
+        // Messages quoting what was refused still make for a valid body
+        assert!(refusal(&handler, "/post", &event(invalid))
+            .await
+            .contains(r#""a b""#));
+
+        // Labels set from code rather than parsed are refused the same way
+        let error = HttpError::from(StorefulError::DuplicateContextKey("a".into()));
+        assert_eq!(error.status, 400);
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_queries() {
+        let handler = Arc::new(Events::new());
+        handler
+            .0
+            .create_label_index::<Event>("service.name".into())
+            .await
+            .unwrap();
+        let query =
+            |extra: &str| format!(r#"{{"key": "service.name", "value": "api", {}}}"#, extra);
+        assert!(post(&handler, "/query", &query(r#""continuation": null"#))
+            .await
+            .is_ok());
+        assert_eq!(
+            post(
+                &handler,
+                "/query",
+                &query(r#""continuation": "not a token""#)
+            )
+            .await,
+            Err(400)
+        );
+        assert_eq!(
+            post(
+                &handler,
+                "/query",
+                &query(r#""timestamp_start": 5, "timestamp_end": 1"#)
+            )
+            .await,
+            Err(400)
+        );
+        // The matcher is quoted back as written, in a body that is still valid JSON
+        assert!(
+            refusal(&handler, "/query", &query(r#""matchers": ["host=\"a"]"#))
+                .await
+                .contains(r#"host="a"#)
+        );
     }
 }
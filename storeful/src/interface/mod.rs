@@ -1,6 +1,7 @@
-use crate::prelude::*;
+use crate::{prelude::*, Expired, Page};
 use serde::{de::DeserializeOwned, Serialize};
 use std::future::Future;
+use tokio::sync::mpsc::Sender;
 
 pub mod http;
 // pub mod websocket;
@@ -9,7 +10,7 @@ pub mod http;
 // pub trait Interface {
 //     fn start<T, Q, M>(
 //         &self,
-//         handler: Arc<Mutex<M>>,
+//         handler: Arc<M>,
 //         host: &str,
 //         post: u16,
 //     ) -> impl Future<Output = Result<()>>
@@ -19,14 +20,30 @@ pub mod http;
 //         M: ModelEndpoints<T, Q> + Send + Sync + 'static;
 // }
 
+/// What the interfaces serve, shared between every request.
+///
+/// Every endpoint takes `&self`, so requests are handled at the same time rather than one by
+/// one; implementations synchronize internally where they have to.
 pub trait ModelEndpoints<T, Q>
 where
     T: Send + Sync + DeserializeOwned + Serialize + 'static,
     Q: Query,
 {
-    fn post(&mut self, input: T) -> impl Future<Output = Result<()>> + Send;
-    fn post_multi(&mut self, multi: Vec<T>) -> impl Future<Output = Result<()>> + Send;
-    fn query(&mut self, query: Q) -> impl Future<Output = Result<Vec<T>>> + Send;
+    fn post(&self, input: T) -> impl Future<Output = Result<()>> + Send;
+    fn post_multi(&self, multi: Vec<T>) -> impl Future<Output = Result<()>> + Send;
+    /// Everything matching `query` up to its limit, with a token to continue from if there is more.
+    fn query(&self, query: Q) -> impl Future<Output = Result<Page<T>>> + Send;
+    /// Sends everything matching `query` to `results` as it is read, stopping early once the
+    /// receiver is dropped.
+    fn query_stream(&self, query: Q, results: Sender<T>)
+        -> impl Future<Output = Result<()>> + Send;
+    /// Removes everything matching `query`, returning how many records were removed.
+    fn delete(&self, query: Q) -> impl Future<Output = Result<usize>> + Send;
+    /// Removes everything with a timestamp before `before`, dropping whole partitions where it can.
+    fn expire(&self, before: i64) -> impl Future<Output = Result<Expired>> + Send;
+    /// Rewrites everything written as an older version of the model as its current one,
+    /// returning how many records were rewritten.
+    fn migrate(&self) -> impl Future<Output = Result<usize>> + Send;
 }
 
 pub trait Query: Send + Sync + DeserializeOwned + Serialize + 'static {
@@ -0,0 +1,203 @@
+use crate::{prelude::*, Context};
+
+const STRING: u8 = 0x02;
+const INTEGER: u8 = 0x03;
+const TERMINATOR: u8 = 0x00;
+const ESCAPE: u8 = 0x01;
+
+/// A key built from typed components, encoded so keys sort the same way as their components.
+///
+/// Strings end in `0x00`. Any `0x00` inside them is escaped as `0x01 0x01` and any `0x01` as
+/// `0x01 0x02`, so the terminator only ever ends a string and no string is a prefix of another
+/// that extends it. Integers are big-endian with the sign bit flipped, so
+/// negative values sort before positive ones. A key is a prefix of every key that extends it,
+/// which is what index prefix scans rely on.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key(Vec<u8>);
+
+/// A single decoded component of a `Key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyComponent {
+    String(String),
+    Integer(i64),
+}
+
+impl Key {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.0.push(STRING);
+        for byte in s.bytes() {
+            match byte {
+                TERMINATOR | ESCAPE => self.0.extend_from_slice(&[ESCAPE, byte + 1]),
+                _ => self.0.push(byte),
+            }
+        }
+        self.0.push(TERMINATOR);
+    }
+
+    pub fn with_str(mut self, s: &str) -> Self {
+        self.push_str(s);
+        self
+    }
+
+    pub fn push_i64(&mut self, i: i64) {
+        self.0.push(INTEGER);
+        self.0
+            .extend_from_slice(&((i as u64) ^ (1 << 63)).to_be_bytes());
+    }
+
+    pub fn with_i64(mut self, i: i64) -> Self {
+        self.push_i64(i);
+        self
+    }
+
+    /// Adds every key and value of `context`, in order.
+    pub fn push_context(&mut self, context: &Context) {
+        for context_value in context.values() {
+            self.push_str(&context_value.key);
+            self.push_str(&context_value.value);
+        }
+    }
+
+    pub fn with_context(mut self, context: &Context) -> Self {
+        self.push_context(context);
+        self
+    }
+
+    /// Appends the components of an already encoded key, such as a primary.
+    pub fn with_key(mut self, key: &[u8]) -> Self {
+        self.0.extend_from_slice(key);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Splits an encoded key back into its components.
+    pub fn decode(bytes: &[u8]) -> Result<Vec<KeyComponent>> {
+        let mut components = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            match bytes[position] {
+                STRING => {
+                    position += 1;
+                    let mut string = Vec::new();
+                    loop {
+                        match bytes.get(position) {
+                            Some(&ESCAPE) => match bytes.get(position + 1) {
+                                Some(&escaped @ 1..=2) => {
+                                    string.push(escaped - 1);
+                                    position += 2;
+                                }
+                                _ => return Err(StorefulError::InvalidKey(position)),
+                            },
+                            Some(&TERMINATOR) => {
+                                position += 1;
+                                break;
+                            }
+                            Some(&byte) => {
+                                string.push(byte);
+                                position += 1;
+                            }
+                            None => return Err(StorefulError::InvalidKey(position)),
+                        }
+                    }
+                    components.push(KeyComponent::String(String::from_utf8(string)?));
+                }
+                INTEGER => {
+                    let integer = bytes
+                        .get(position + 1..position + 9)
+                        .ok_or(StorefulError::InvalidKey(position))?;
+                    let integer = u64::from_be_bytes(integer.try_into().unwrap()) ^ (1 << 63);
+                    components.push(KeyComponent::Integer(integer as i64));
+                    position += 9;
+                }
+                _ => return Err(StorefulError::InvalidKey(position)),
+            }
+        }
+        Ok(components)
+    }
+}
+
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Key> for Vec<u8> {
+    fn from(key: Key) -> Self {
+        key.0
+    }
+}
+
+impl From<Key> for Box<[u8]> {
+    fn from(key: Key) -> Self {
+        key.0.into_boxed_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_sort_numerically() {
+        let integers = [i64::MIN, -1_000, -1, 0, 1, 42, i64::MAX];
+        let keys: Vec<Key> = integers.iter().map(|i| Key::new().with_i64(*i)).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn strings_cannot_collide() {
+        // `a:b` + `c` and `a` + `b:c` used to produce the same formatted key
+        let label = |key: &str, value: &str| Key::new().with_str(key).with_str(value);
+        assert_ne!(label("a:b", "c"), label("a", "b:c"));
+
+        let prefix = label("a", "b");
+        assert!(!label("a", "b:c").as_bytes().starts_with(prefix.as_bytes()));
+        assert!(label("a", "b")
+            .with_i64(1)
+            .as_bytes()
+            .starts_with(prefix.as_bytes()));
+    }
+
+    #[test]
+    fn decode_round_trips() {
+        let key = Key::new()
+            .with_str("cpu|usage\"")
+            .with_i64(-5)
+            .with_str("nul\0byte");
+        assert_eq!(
+            Key::decode(key.as_bytes()).unwrap(),
+            vec![
+                KeyComponent::String("cpu|usage\"".into()),
+                KeyComponent::Integer(-5),
+                KeyComponent::String("nul\0byte".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_strings_keep_prefixes_apart() {
+        let strings = ["a", "a\0", "a\0b", "a\x01", "a\x02", "ab"];
+        let keys: Vec<Key> = strings.iter().map(|s| Key::new().with_str(s)).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        for (string, key) in strings.iter().zip(&keys) {
+            let decoded = Key::decode(key.as_bytes()).unwrap();
+            assert_eq!(decoded, [KeyComponent::String(string.to_string())]);
+        }
+
+        // A scan for `a` must not find `a\0b`
+        let prefix = Key::new().with_str("a");
+        assert!(!Key::new()
+            .with_str("a\0b")
+            .as_bytes()
+            .starts_with(prefix.as_bytes()));
+        assert!(Key::decode(&[STRING, b'a', ESCAPE, 0x03, TERMINATOR]).is_err());
+    }
+}
@@ -1,17 +1,31 @@
 pub mod prelude;
 
 mod args;
+mod chunk;
+mod codec;
 mod config;
 mod db;
+mod ingest;
 mod interface;
+mod key;
+mod matcher;
 mod models;
-// mod traits;
+mod retention;
+mod schema;
+mod traits;
 mod util;
 
 pub use args::*;
+pub use chunk::*;
+pub use codec::*;
 pub use config::*;
 pub use db::*;
+pub use ingest::*;
 pub use interface::*;
+pub use key::*;
+pub use matcher::*;
 pub use models::*;
-// pub use traits::*;
+pub use retention::*;
+pub use schema::*;
+pub use traits::*;
 pub use util::*;
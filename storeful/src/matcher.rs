@@ -0,0 +1,253 @@
+use std::borrow::Cow;
+
+use regex::Regex;
+
+use crate::{prelude::*, validate_key, Context, ContextValue, IndexValue};
+
+/// How a `LabelMatcher` checks the value of its label.
+///
+/// Like in Prometheus, a missing label counts as an empty value, so `key!="value"` and
+/// `key=~".*"` also match what lacks the label.
+#[derive(Debug, Clone)]
+pub enum LabelMatch {
+    /// `key="value"`
+    Equal(String),
+    /// `key!="value"`
+    NotEqual(String),
+    /// `key=~"regex"`, the whole value has to match.
+    Matches(Regex),
+    /// `key!~"regex"`
+    NotMatches(Regex),
+    /// `key`, has the label with any value.
+    Exists,
+    /// `!key`, lacks the label.
+    Missing,
+}
+
+/// A condition on one label, checked against the `(key, value)` entries of `index`.
+#[derive(Debug, Clone)]
+pub struct LabelMatcher {
+    pub index: Cow<'static, str>,
+    pub key: String,
+    pub matches: LabelMatch,
+}
+
+impl LabelMatcher {
+    pub fn new(index: impl Into<Cow<'static, str>>, key: &str, matches: LabelMatch) -> Self {
+        Self {
+            index: index.into(),
+            key: key.into(),
+            matches,
+        }
+    }
+
+    /// Reads a matcher written as `key="value"`, `key!="value"`, `key=~"regex"`,
+    /// `key!~"regex"`, `key` or `!key`.
+    pub fn parse(index: impl Into<Cow<'static, str>>, matcher: &str) -> Result<Self> {
+        let invalid = || StorefulError::InvalidMatcher(matcher.into());
+        let matcher = matcher.trim();
+        let Some(operator) = matcher.find(['=', '!']).filter(|at| *at > 0) else {
+            let (key, matches) = match matcher.strip_prefix('!') {
+                Some(key) => (key.trim(), LabelMatch::Missing),
+                None => (matcher, LabelMatch::Exists),
+            };
+            validate_key(key)?;
+            return Ok(Self::new(index, key, matches));
+        };
+        let (key, rest) = matcher.split_at(operator);
+        let key = key.trim();
+        validate_key(key)?;
+        let (operator, value) = ["!=", "=~", "!~", "="]
+            .into_iter()
+            .find_map(|operator| Some((operator, rest.strip_prefix(operator)?)))
+            .ok_or_else(invalid)?;
+        let value = unquote(value.trim()).ok_or_else(invalid)?;
+        let regex = || {
+            Regex::new(&format!("^(?:{})$", value))
+                .map_err(|e| StorefulError::InvalidMatcher(e.to_string()))
+        };
+        let matches = match operator {
+            "=" => LabelMatch::Equal(value),
+            "!=" => LabelMatch::NotEqual(value),
+            "=~" => LabelMatch::Matches(regex()?),
+            _ => LabelMatch::NotMatches(regex()?),
+        };
+        Ok(Self::new(index, key, matches))
+    }
+
+    /// Whether a label with `value` is matched, `None` when the label is missing.
+    pub fn is_match(&self, value: Option<&str>) -> bool {
+        match &self.matches {
+            LabelMatch::Exists => value.is_some(),
+            LabelMatch::Missing => value.is_none(),
+            LabelMatch::Equal(expected) => value.unwrap_or_default() == expected,
+            LabelMatch::NotEqual(expected) => value.unwrap_or_default() != expected,
+            LabelMatch::Matches(regex) => regex.is_match(value.unwrap_or_default()),
+            LabelMatch::NotMatches(regex) => !regex.is_match(value.unwrap_or_default()),
+        }
+    }
+
+    /// Whether the labels in `context` are matched, every label is missing without any.
+    pub fn matches_context(&self, context: Option<&Context>) -> bool {
+        self.is_match(context.and_then(|context| context.get(&self.key)))
+    }
+}
+
+/// Reads a value between double quotes, where `\"` and `\\` stand for `"` and `\`.
+///
+/// Any other escape is kept as written, so regexes such as `"a\.b"` read as they look. `None`
+/// when the quotes are missing or a bare `"` turns up before the end.
+fn unquote(quoted: &str) -> Option<String> {
+    let mut chars = quoted.strip_prefix('"')?.chars();
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return chars.as_str().is_empty().then_some(value),
+            '\\' => match chars.next()? {
+                c @ ('"' | '\\') => value.push(c),
+                c => value.extend(['\\', c]),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+/// The label matchers of a query, split by how they are answered for a model.
+#[derive(Debug, Clone, Default)]
+pub struct LabelFilters {
+    /// Entries a record must have, from `key="value"` matchers.
+    pub include: Vec<IndexValue>,
+    /// Entries a record must not have, from `key!="value"` matchers.
+    pub exclude: Vec<IndexValue>,
+    /// Matchers no index entry answers, checked against the labels of each record once it is
+    /// read and decoded.
+    pub matchers: Vec<LabelMatcher>,
+    /// Matchers on labels with a label index, resolved from all of its entries.
+    pub scanned: Vec<LabelMatcher>,
+}
+
+impl LabelFilters {
+    /// Looks up `key="value"` and `key!="value"` matchers in their index when it is one of
+    /// `indexes`, those hold an entry for every label of a record.
+    ///
+    /// An empty value stands for a missing label, which has no entry, so those matchers are
+    /// checked against records like the rest.
+    pub fn new(matchers: Vec<LabelMatcher>, indexes: &[&str]) -> Self {
+        let mut filters = Self::default();
+        for matcher in matchers {
+            let entry = |value: &String| {
+                let context_value = ContextValue {
+                    key: matcher.key.clone(),
+                    value: value.clone(),
+                };
+                IndexValue::context_value(matcher.index.clone(), &context_value)
+            };
+            match &matcher.matches {
+                _ if !indexes.contains(&matcher.index.as_ref()) => filters.matchers.push(matcher),
+                LabelMatch::Equal(value) if !value.is_empty() => filters.include.push(entry(value)),
+                LabelMatch::NotEqual(value) if !value.is_empty() => {
+                    filters.exclude.push(entry(value))
+                }
+                _ => filters.matchers.push(matcher),
+            }
+        }
+        filters
+    }
+
+    /// Looks up the matchers left on any of `labels` in their label index, see
+    /// `Storeful::create_label_index`.
+    ///
+    /// Those holding a value become entries like with declared indexes, and the rest are
+    /// resolved from the entries of the index rather than by reading every record.
+    pub fn with_label_indexes(mut self, labels: &[String]) -> Self {
+        let matchers = std::mem::take(&mut self.matchers);
+        for matcher in matchers {
+            match &matcher.matches {
+                _ if !labels.contains(&matcher.key) => self.matchers.push(matcher),
+                LabelMatch::Equal(value) if !value.is_empty() => {
+                    self.include.push(IndexValue::label(&matcher.key, value))
+                }
+                LabelMatch::NotEqual(value) if !value.is_empty() => {
+                    self.exclude.push(IndexValue::label(&matcher.key, value))
+                }
+                _ => self.scanned.push(matcher),
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_matches() {
+        let cases = [
+            ("host=\"a\"", [true, false, false]),
+            ("host != \"a\"", [false, true, true]),
+            ("host=~\"a|b\"", [true, true, false]),
+            ("host=~\"\"", [false, false, true]),
+            ("host!~\"a.*\"", [false, true, true]),
+            ("host", [true, true, false]),
+            ("!host", [false, false, true]),
+        ];
+        for (matcher, expected) in cases {
+            let matcher = LabelMatcher::parse("context", matcher).unwrap();
+            assert_eq!(matcher.key, "host");
+            let matched = [Some("a"), Some("b"), None].map(|value| matcher.is_match(value));
+            assert_eq!(matched, expected, "{:?}", matcher);
+        }
+
+        let quoted = LabelMatcher::parse("context", r#"path="say \"hi\"""#).unwrap();
+        assert!(quoted.is_match(Some("say \"hi\"")));
+        let escaped = LabelMatcher::parse("context", r#"path="C:\\""#).unwrap();
+        assert!(escaped.is_match(Some("C:\\")));
+        let regex = LabelMatcher::parse("context", r#"host=~"a\.b""#).unwrap();
+        assert!(regex.is_match(Some("a.b")) && !regex.is_match(Some("axb")));
+        for invalid in [
+            "",
+            "!",
+            "=\"a\"",
+            "!host=\"a\"",
+            "host=a",
+            "host=~\"(\"",
+            "host==\"a\"",
+            "host=\"a\"b\"",
+            "host=\"a\\\"",
+            "host=\"a\" \"b\"",
+            "1host=\"a\"",
+            "ho st=\"a\"",
+            "ho-st",
+        ] {
+            assert!(
+                LabelMatcher::parse("context", invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn answers_equality_from_declared_indexes() {
+        let matchers = ["host=\"a\"", "host!=\"b\"", "host=\"\"", "host=~\"a\""]
+            .map(|matcher| LabelMatcher::parse("context", matcher).unwrap());
+        let entry = |value: &str| {
+            let context_value = ContextValue {
+                key: "host".into(),
+                value: value.into(),
+            };
+            IndexValue::context_value("context", &context_value)
+        };
+
+        let filters = LabelFilters::new(matchers.to_vec(), &["context"]);
+        assert_eq!(filters.include, [entry("a")]);
+        assert_eq!(filters.exclude, [entry("b")]);
+        assert_eq!(filters.matchers.len(), 2);
+
+        // Without the index every matcher is checked against the records themselves
+        let filters = LabelFilters::new(matchers.to_vec(), &["name"]);
+        assert!(filters.include.is_empty() && filters.exclude.is_empty());
+        assert_eq!(filters.matchers.len(), 4);
+    }
+}
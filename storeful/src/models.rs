@@ -1,13 +1,24 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt::Display;
 
+use crate::{prelude::*, Versioned};
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IncomingBody<T> {
     pub value: Option<T>,
     pub values: Option<Vec<T>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// The order in which timestamp ordered results are returned.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct ContextValue {
     pub key: String,
     pub value: String,
@@ -20,8 +31,26 @@ impl Display for ContextValue {
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct Context(pub Vec<ContextValue>);
+/// A set of labels, kept sorted by key with every key at most once.
+///
+/// Records with the same labels therefore always have the same `Context`, however the labels
+/// were given, which keeps the keys built from it stable.
+#[derive(Debug, Default, Serialize, Clone, PartialEq, Eq)]
+pub struct Context(Vec<ContextValue>);
+
+/// Labels sent by clients are checked like `Context::new` does. Stored labels are only put in
+/// order, records written before labels were checked would no longer be readable otherwise.
+impl<'de> Deserialize<'de> for Context {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        // Values are stored with bincode, requests come in as JSON
+        let human_readable = deserializer.is_human_readable();
+        let values = Vec::<ContextValue>::deserialize(deserializer)?;
+        if human_readable {
+            return Self::new(values).map_err(serde::de::Error::custom);
+        }
+        Ok(Self::stored(values))
+    }
+}
 
 /// `{ key1="value1", key2="value2" }`
 impl Display for Context {
@@ -36,9 +65,62 @@ impl Display for Context {
     }
 }
 
+impl TryFrom<Vec<ContextValue>> for Context {
+    type Error = StorefulError;
+
+    fn try_from(values: Vec<ContextValue>) -> Result<Self> {
+        Self::new(values)
+    }
+}
+
 impl Context {
+    /// Sorts `values` by key, merging repeated labels and rejecting keys given different values.
+    pub fn new(values: impl IntoIterator<Item = ContextValue>) -> Result<Self> {
+        let mut values: Vec<ContextValue> = values.into_iter().collect();
+        for context_value in &values {
+            validate_key(&context_value.key)?;
+        }
+        values.sort_by(|a, b| a.key.cmp(&b.key));
+        values.dedup();
+        if let Some(pair) = values.windows(2).find(|pair| pair[0].key == pair[1].key) {
+            return Err(StorefulError::DuplicateContextKey(pair[0].key.clone()));
+        }
+        Ok(Self(values))
+    }
+
+    /// `values` in order, keeping the first value of a repeated key rather than failing.
+    fn stored(mut values: Vec<ContextValue>) -> Self {
+        values.sort_by(|a, b| a.key.cmp(&b.key));
+        values.dedup_by(|next, first| next.key == first.key);
+        Self(values)
+    }
+
+    /// Sets the label `key` to `value`, replacing what it was set to before.
+    ///
+    /// # Panics
+    ///
+    /// If `key` is not a valid key, use `try_add_value` for keys that aren't known up front.
     pub fn add_value(&mut self, key: &str, value: &str) {
-        self.0.push(ContextValue { key: key.into(), value: value.into() });
+        if let Err(e) = self.try_add_value(key, value) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Like `add_value`, failing on an invalid key instead of panicking.
+    pub fn try_add_value(&mut self, key: &str, value: &str) -> Result<()> {
+        validate_key(key)?;
+        let context_value = ContextValue {
+            key: key.into(),
+            value: value.into(),
+        };
+        match self
+            .0
+            .binary_search_by(|existing| existing.key.as_str().cmp(key))
+        {
+            Ok(index) => self.0[index] = context_value,
+            Err(index) => self.0.insert(index, context_value),
+        }
+        Ok(())
     }
 
     pub fn with_value(mut self, key: &str, value: &str) -> Self {
@@ -46,11 +128,109 @@ impl Context {
         self
     }
 
-    pub fn to_key_string(&self) -> String {
+    /// The value of the label `key`, if it is set.
+    pub fn get(&self, key: &str) -> Option<&str> {
         self.0
-            .iter()
-            .map(|context_value| format!("{}=\"{}\"", context_value.key, context_value.value))
-            .collect::<Vec<String>>()
-            .join(",")
+            .binary_search_by(|existing| existing.key.as_str().cmp(key))
+            .ok()
+            .map(|index| self.0[index].value.as_str())
+    }
+
+    /// Every label, sorted by key.
+    pub fn values(&self) -> &[ContextValue] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Keys start with a letter or `_`, followed by letters, digits, `_` or `.`.
+pub(crate) fn validate_key(key: &str) -> Result<()> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(StorefulError::InvalidContextKey(key.into()))
+    }
+}
+
+/// What identifies a series: every sample with the same name and labels belongs to it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Series {
+    pub name: String,
+    pub context: Context,
+}
+
+impl Versioned for Series {}
+
+/// A single value of a series, at nanoseconds since the epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub timestamp: i64,
+    pub value: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(key: &str, value: &str) -> ContextValue {
+        ContextValue {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn order_does_not_matter() {
+        let a = Context::new([value("b", "2"), value("a", "1")]).unwrap();
+        let b = Context::default().with_value("a", "1").with_value("b", "2");
+        assert_eq!(a, b);
+        assert_eq!(a.to_string(), "{a=\"1\", b=\"2\"}");
+    }
+
+    #[test]
+    fn duplicates_merge_or_fail() {
+        let merged = Context::new([value("a", "1"), value("a", "1")]).unwrap();
+        assert_eq!(merged.values().len(), 1);
+        assert!(matches!(
+            Context::new([value("a", "1"), value("a", "2")]),
+            Err(StorefulError::DuplicateContextKey(key)) if key == "a"
+        ));
+    }
+
+    #[test]
+    fn keys_are_validated() {
+        for key in ["host", "_private", "user.id", "http_status2"] {
+            assert!(Context::new([value(key, "")]).is_ok(), "{}", key);
+        }
+        for key in ["", "2xx", "a b", "a=b", "a\"", "{}"] {
+            assert!(Context::new([value(key, "")]).is_err(), "{}", key);
+        }
+        let json = r#"[{"key": "b", "value": "2"}, {"key": "a", "value": "1"}]"#;
+        let context: Context = serde_json::from_str(json).unwrap();
+        assert_eq!(context.get("a"), Some("1"));
+        assert!(serde_json::from_str::<Context>(r#"[{"key": "a b", "value": ""}]"#).is_err());
+
+        let mut context = Context::default();
+        assert!(matches!(
+            context.try_add_value("a b", ""),
+            Err(StorefulError::InvalidContextKey(_))
+        ));
+        assert!(context.is_empty());
+    }
+
+    #[test]
+    fn reads_labels_stored_before_they_were_checked() {
+        let legacy = vec![value("b", "2"), value("a b", "1"), value("b", "3")];
+        let bytes = bincode::serialize(&legacy).unwrap();
+        let context: Context = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(context.values(), [value("a b", "1"), value("b", "2")]);
     }
 }
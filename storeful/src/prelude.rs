@@ -27,8 +27,10 @@ pub enum StorefulError {
     #[error("bincode error")]
     Bincode(#[from] bincode::Error),
 
-    // #[error("rocksdb error")]
-    // Rocks(#[from] rocksdb::Error),
+    #[cfg(feature = "rocksdb")]
+    #[error("rocksdb error")]
+    Rocks(#[from] rocksdb::Error),
+
     #[error("sled error")]
     Sled(#[from] sled::Error),
 
@@ -44,16 +46,92 @@ pub enum StorefulError {
     #[error("column family not found: {0}")]
     ColumnFamilyNotFound(String),
 
-    #[error("batch already started")]
-    BatchAlreadyStarted,
-    #[error("batch not started")]
-    BatchNotStarted,
+    #[error("invalid label matcher: {0}")]
+    InvalidMatcher(String),
+
+    #[error("index not found: {0}")]
+    IndexNotFound(String),
+
+    #[error("invalid store name, it can't be empty or contain '@' or ':': {0:?}")]
+    InvalidMasterKey(String),
+
+    #[error("timestamp out of range: {0}")]
+    InvalidTimestamp(String),
+
+    #[error("store was created with partitions {stored}ns wide, not {configured}ns")]
+    PartitionWidthMismatch { stored: i64, configured: i64 },
+
+    #[error("invalid store metadata: {0}")]
+    InvalidMetadata(&'static str),
+
+    #[error("store written in an unknown format: {0}")]
+    UnknownStoreFormat(u16),
 
     #[error("invalid query range")]
     InvalidQueryRange,
 
+    #[error("invalid key at byte {0}")]
+    InvalidKey(usize),
+
+    #[error("invalid continuation token")]
+    InvalidContinuation,
+
+    #[error("invalid context key: {0:?}")]
+    InvalidContextKey(String),
+
+    #[error("context key given more than one value: {0}")]
+    DuplicateContextKey(String),
+
+    #[error("series id of {0} bytes, expected 4")]
+    InvalidSeriesId(usize),
+
+    #[error("no series ids left in partition {0}")]
+    SeriesIdsExhausted(crate::Partition),
+
+    #[error("invalid posting list")]
+    InvalidPostings,
+
+    #[error("failed to compress or decompress value: {0}")]
+    Compression(String),
+
+    #[error("value written with an unknown codec: {0:?}")]
+    UnknownCodec(Option<u8>),
+
+    #[error("value compressed with a dictionary that wasn't added: {0}")]
+    UnknownDictionary(u32),
+
+    #[error("invalid sample chunk")]
+    InvalidChunk,
+
+    #[error("series {0} not found")]
+    SeriesNotFound(u32),
+
+    #[error("value written as an unknown model version: {0:?}")]
+    UnknownVersion(Option<u16>),
+
+    #[error("value written in an unknown format version: {0}")]
+    UnknownFormat(u8),
+
     #[error("lock poisoned")]
     LockPoisoned,
+
+    #[error("ingest queue is full")]
+    IngestQueueFull,
+
+    #[error("post of {0} records is larger than the ingest queue")]
+    IngestTooLarge(usize),
+
+    #[error("ingest buffer has stopped")]
+    IngestStopped,
+
+    #[error("failed to write ingested batch: {0}")]
+    IngestFailed(String),
+
+    #[error("blocking task failed")]
+    Join(#[from] tokio::task::JoinError),
+
+    #[error("backend not available in this build: {0}")]
+    BackendUnavailable(String),
 }
 
 impl<T> From<PoisonError<T>> for StorefulError {
@@ -61,3 +139,12 @@ impl<T> From<PoisonError<T>> for StorefulError {
         StorefulError::LockPoisoned
     }
 }
+
+impl From<sled::transaction::TransactionError> for StorefulError {
+    fn from(e: sled::transaction::TransactionError) -> Self {
+        match e {
+            sled::transaction::TransactionError::Abort(e)
+            | sled::transaction::TransactionError::Storage(e) => StorefulError::Sled(e),
+        }
+    }
+}
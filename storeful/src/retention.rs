@@ -0,0 +1,132 @@
+use std::{
+    sync::{Arc, Mutex as StdMutex, PoisonError},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{prelude::*, ModelEndpoints, Query};
+
+/// What a single `ModelEndpoints::expire` call removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Expired {
+    /// Partitions that were dropped whole.
+    pub partitions: usize,
+    /// Records removed one by one from partitions that were only partly expired.
+    pub records: usize,
+}
+
+/// How long records are kept, given as `--retention 30d` for every model or as
+/// `--retention metrics=30d` for the model stored under that name alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub model: Option<String>,
+    pub retention: Duration,
+}
+
+/// How long the records of `model` are kept under `rules`. A rule naming the model wins over
+/// one for every model, and later rules over earlier ones.
+pub fn model_retention(rules: &[RetentionRule], model: &str) -> Option<Duration> {
+    let retention = |named: bool| {
+        rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                rule.model
+                    .as_deref()
+                    .map_or(!named, |rule| named && rule == model)
+            })
+            .map(|rule| rule.retention)
+    };
+    retention(true).or_else(|| retention(false))
+}
+
+/// How often the retention task looks for expired records.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the retention task has done so far, served on `/admin/retention`.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RetentionStatus {
+    pub retention_seconds: Option<u64>,
+    pub cutoff: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_removed: usize,
+    pub total_removed: usize,
+    pub last_dropped_partitions: usize,
+    pub total_dropped_partitions: usize,
+    pub last_error: Option<String>,
+}
+
+impl RetentionStatus {
+    pub fn new(retention: Option<Duration>) -> Self {
+        Self {
+            retention_seconds: retention.map(|retention| retention.as_secs()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Periodically expires everything older than `retention` from `handler`.
+pub async fn start<T, Q, M>(
+    handler: Arc<M>,
+    retention: Duration,
+    status: Arc<StdMutex<RetentionStatus>>,
+) -> Result<()>
+where
+    T: Send + Sync + Serialize + DeserializeOwned + 'static,
+    Q: Query,
+    M: ModelEndpoints<T, Q> + Send + Sync + 'static,
+{
+    let retention = chrono::Duration::from_std(retention).ok();
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let cutoff = retention.and_then(|retention| now.checked_sub_signed(retention));
+        let result = match cutoff.and_then(|cutoff| cutoff.timestamp_nanos_opt()) {
+            Some(before) => handler.expire(before).await,
+            // Older than anything a nanosecond timestamp can hold, nothing to expire
+            None => Ok(Expired::default()),
+        };
+
+        // A panic elsewhere while holding the status doesn't stop records from expiring
+        let mut status = status.lock().unwrap_or_else(PoisonError::into_inner);
+        status.cutoff = cutoff;
+        status.last_run = Some(now);
+        match result {
+            Ok(expired) => {
+                status.last_removed = expired.records;
+                status.total_removed += expired.records;
+                status.last_dropped_partitions = expired.partitions;
+                status.total_dropped_partitions += expired.partitions;
+                status.last_error = None;
+            }
+            Err(e) => {
+                eprintln!("Error expiring records: {}", e);
+                status.last_removed = 0;
+                status.last_dropped_partitions = 0;
+                status.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_retention;
+
+    #[test]
+    fn prefers_rules_for_the_model() {
+        let rules: Vec<RetentionRule> = ["30d", "logs=7d", "traces=1d", "logs=2d"]
+            .into_iter()
+            .map(|rule| parse_retention(rule).unwrap())
+            .collect();
+        let days = |days: u64| Some(Duration::from_secs(days * 24 * 60 * 60));
+        assert_eq!(model_retention(&rules, "logs"), days(2));
+        assert_eq!(model_retention(&rules, "traces"), days(1));
+        assert_eq!(model_retention(&rules, "metrics"), days(30));
+        assert_eq!(model_retention(&rules[1..], "metrics"), None);
+    }
+}
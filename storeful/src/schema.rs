@@ -0,0 +1,483 @@
+use std::collections::BTreeMap;
+
+use serde::de::DeserializeOwned;
+
+use crate::{prelude::*, Context, IndexValue, Storeable, Versioned};
+
+/// Bytes before the body of every stored value, the `u16` version it was written as.
+const VERSION_HEADER: usize = 2;
+
+/// Starts values whose encoding has a version of its own, such as sample chunks and posting
+/// lists. Neither ever started with it before they had one.
+const FORMAT_MARKER: u8 = 0xff;
+
+/// Reads the body of a value written as one version of a model, as its current version.
+pub type Decoder<T> = fn(&[u8]) -> Result<T>;
+
+/// What a record written as an older version was indexed by, see `Decoders::with_indexed`.
+pub struct Indexed {
+    pub timestamp: i64,
+    pub index_values: Vec<IndexValue>,
+    pub context: Option<Context>,
+}
+
+/// Reads what the body of a value written as one version of a model was indexed by.
+pub type Indexer = fn(&[u8]) -> Result<Indexed>;
+
+/// How to read a model from each of the versions it was written as before its current one,
+/// e.g. `Decoders::default().with::<LogV1>(1)` once `Log` is at version 2.
+pub struct Decoders<T> {
+    decoders: BTreeMap<u16, Decoder<T>>,
+    indexers: BTreeMap<u16, Indexer>,
+}
+
+impl<T> Default for Decoders<T> {
+    fn default() -> Self {
+        Self {
+            decoders: BTreeMap::new(),
+            indexers: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> Decoders<T> {
+    /// Reads values written as `version` as the model `Old` was back then, upgrading them
+    /// with its `Into<T>`.
+    pub fn with<Old>(mut self, version: u16) -> Self
+    where
+        Old: DeserializeOwned + Into<T>,
+    {
+        self.decoders.insert(
+            version,
+            |body| Ok(bincode::deserialize::<Old>(body)?.into()),
+        );
+        self
+    }
+
+    /// Like `with`, also reading what records written as `version` were indexed by as `Old`.
+    ///
+    /// Needed once an upgrade changes the index values or labels of a record, so migrating it
+    /// removes the entries it was written with rather than those of its upgrade.
+    pub fn with_indexed<Old>(mut self, version: u16) -> Self
+    where
+        Old: Storeable + Into<T>,
+    {
+        self = self.with::<Old>(version);
+        self.indexers.insert(version, |body| {
+            let old = bincode::deserialize::<Old>(body)?;
+            Ok(Indexed {
+                timestamp: old.timestamp(),
+                index_values: old.index_values(),
+                context: old.context().cloned(),
+            })
+        });
+        self
+    }
+
+    /// Reads values written as `version` with `decode`, for upgrades `Into` can't express.
+    pub fn with_fn(mut self, version: u16, decode: Decoder<T>) -> Self {
+        self.decoders.insert(version, decode);
+        self
+    }
+
+    /// What the `body` of a value written as `version` was indexed by, `None` when it was
+    /// indexed like its upgrade.
+    pub fn indexed(&self, version: u16, body: &[u8]) -> Result<Option<Indexed>> {
+        self.indexers
+            .get(&version)
+            .map(|indexer| indexer(body))
+            .transpose()
+    }
+
+    pub fn decode(&self, version: u16, body: &[u8]) -> Result<T> {
+        let decode = self
+            .decoders
+            .get(&version)
+            .ok_or(StorefulError::UnknownVersion(Some(version)))?;
+        decode(body)
+    }
+}
+
+/// `value` behind the header `read_versioned` reads its version from.
+pub fn write_versioned<T: Versioned>(value: &T) -> Result<Vec<u8>> {
+    let mut bytes = T::VERSION.to_be_bytes().to_vec();
+    bincode::serialize_into(&mut bytes, value)?;
+    Ok(bytes)
+}
+
+/// The version a value was written as, and its body.
+pub(crate) fn split_versioned(bytes: &[u8]) -> Result<(u16, &[u8])> {
+    let (version, body) = bytes
+        .split_first_chunk::<VERSION_HEADER>()
+        .ok_or(StorefulError::UnknownVersion(None))?;
+    Ok((u16::from_be_bytes(*version), body))
+}
+
+/// The value `write_versioned` was given and the version it was written as, upgraded to the
+/// current version if it was older.
+pub fn read_versioned<T: Versioned>(bytes: &[u8]) -> Result<(u16, T)> {
+    let (version, body) = split_versioned(bytes)?;
+    if version == T::VERSION {
+        return Ok((version, bincode::deserialize(body)?));
+    }
+    Ok((version, T::decoders().decode(version, body)?))
+}
+
+/// The header `read_format` reads the `version` of an encoding from.
+pub(crate) fn format_header(version: u8) -> [u8; 2] {
+    [FORMAT_MARKER, version]
+}
+
+/// What follows the header `format_header` wrote for `version`. Values written before their
+/// encoding had a version are read whole, as its first version.
+pub(crate) fn read_format(bytes: &[u8], version: u8) -> Result<&[u8]> {
+    match bytes {
+        [FORMAT_MARKER, written, body @ ..] if *written == version => Ok(body),
+        [FORMAT_MARKER, written, ..] => Err(StorefulError::UnknownFormat(*written)),
+        _ => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{
+        memory::MemoryBackend, BackendDatabase, IndexQuery, IndexValue, Key, Storeable, Storeful,
+    };
+
+    #[derive(Serialize, Deserialize)]
+    struct LogV1 {
+        timestamp: i64,
+        message: String,
+    }
+
+    impl Versioned for LogV1 {}
+
+    impl Storeable for LogV1 {
+        const INDEXES: &'static [&'static str] = &[];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Log {
+        timestamp: i64,
+        message: String,
+        level: u8,
+    }
+
+    impl From<LogV1> for Log {
+        fn from(old: LogV1) -> Self {
+            Self {
+                timestamp: old.timestamp,
+                message: old.message,
+                level: 0,
+            }
+        }
+    }
+
+    impl Versioned for Log {
+        const VERSION: u16 = 2;
+
+        fn decoders() -> Decoders<Self> {
+            Decoders::default().with::<LogV1>(1)
+        }
+    }
+
+    impl Storeable for Log {
+        const INDEXES: &'static [&'static str] = &[];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct SpanV1 {
+        timestamp: i64,
+        id: u8,
+    }
+
+    impl Versioned for SpanV1 {}
+
+    impl Storeable for SpanV1 {
+        const INDEXES: &'static [&'static str] = &[];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+    }
+
+    /// Keyed by its id as well from version 2 on.
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Span {
+        timestamp: i64,
+        id: u8,
+    }
+
+    impl From<SpanV1> for Span {
+        fn from(old: SpanV1) -> Self {
+            Self {
+                timestamp: old.timestamp,
+                id: old.id,
+            }
+        }
+    }
+
+    impl Versioned for Span {
+        const VERSION: u16 = 2;
+
+        fn decoders() -> Decoders<Self> {
+            Decoders::default().with::<SpanV1>(1)
+        }
+    }
+
+    impl Storeable for Span {
+        const INDEXES: &'static [&'static str] = &[];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp).with_i64(self.id.into())
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct TagV1 {
+        timestamp: i64,
+        tag: String,
+    }
+
+    impl Versioned for TagV1 {}
+
+    impl Storeable for TagV1 {
+        const INDEXES: &'static [&'static str] = &["tag"];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            vec![IndexValue::new("tag", Key::new().with_str(&self.tag))]
+        }
+    }
+
+    /// Tagged in lowercase from version 2 on.
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Tag {
+        timestamp: i64,
+        tag: String,
+    }
+
+    impl From<TagV1> for Tag {
+        fn from(old: TagV1) -> Self {
+            Self {
+                timestamp: old.timestamp,
+                tag: old.tag.to_lowercase(),
+            }
+        }
+    }
+
+    impl Versioned for Tag {
+        const VERSION: u16 = 2;
+
+        fn decoders() -> Decoders<Self> {
+            Decoders::default().with_indexed::<TagV1>(1)
+        }
+    }
+
+    impl Storeable for Tag {
+        const INDEXES: &'static [&'static str] = &["tag"];
+
+        fn primary(&self) -> Key {
+            Key::new().with_i64(self.timestamp)
+        }
+
+        fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            vec![IndexValue::new("tag", Key::new().with_str(&self.tag))]
+        }
+    }
+
+    struct ByTag(&'static str);
+
+    impl IndexQuery for ByTag {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            vec![IndexValue::new("tag", Key::new().with_str(self.0))]
+        }
+    }
+
+    struct All;
+
+    impl IndexQuery for All {
+        fn timestamp_start(&self) -> Option<i64> {
+            None
+        }
+
+        fn timestamp_end(&self) -> Option<i64> {
+            None
+        }
+
+        fn index_values(&self) -> Vec<IndexValue> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn upgrades_older_versions() {
+        let old = write_versioned(&LogV1 {
+            timestamp: 1,
+            message: "started".into(),
+        })
+        .unwrap();
+        let upgraded = Log {
+            timestamp: 1,
+            message: "started".into(),
+            level: 0,
+        };
+        assert_eq!(read_versioned::<Log>(&old).unwrap(), (1, upgraded));
+
+        let current = Log {
+            timestamp: 2,
+            message: "stopped".into(),
+            level: 3,
+        };
+        let bytes = write_versioned(&current).unwrap();
+        assert_eq!(read_versioned::<Log>(&bytes).unwrap(), (2, current));
+
+        // Nothing knows how to read a version newer than the model
+        let newer = [&[0, 3][..], &bytes[VERSION_HEADER..]].concat();
+        assert!(matches!(
+            read_versioned::<Log>(&newer),
+            Err(StorefulError::UnknownVersion(Some(3)))
+        ));
+        assert!(read_versioned::<Log>(&[0]).is_err());
+    }
+
+    #[test]
+    fn migrates_older_records() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Log::trees()));
+        storeful
+            .post((0..3).map(|timestamp| LogV1 {
+                timestamp,
+                message: "old".into(),
+            }))
+            .unwrap();
+        storeful
+            .post([Log {
+                timestamp: 3,
+                message: "new".into(),
+                level: 2,
+            }])
+            .unwrap();
+
+        assert_eq!(storeful.migrate::<Log>().unwrap(), 3);
+        assert_eq!(storeful.migrate::<Log>().unwrap(), 0);
+        let logs: Vec<Log> = storeful.query(&All).unwrap();
+        let levels: Vec<u8> = logs.iter().map(|log| log.level).collect();
+        assert_eq!(levels, [0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn migrates_records_to_another_primary() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Span::trees()));
+        storeful
+            .post((0..3).map(|timestamp| SpanV1 { timestamp, id: 7 }))
+            .unwrap();
+
+        assert_eq!(storeful.migrate::<Span>().unwrap(), 3);
+        // The older copies went with their index entries, nothing is left to migrate or find twice
+        assert_eq!(storeful.migrate::<Span>().unwrap(), 0);
+        let spans: Vec<Span> = storeful.query(&All).unwrap();
+        assert_eq!(
+            spans,
+            (0..3)
+                .map(|timestamp| Span { timestamp, id: 7 })
+                .collect::<Vec<_>>()
+        );
+        let old = SpanV1 {
+            timestamp: 0,
+            id: 7,
+        };
+        assert!(storeful
+            .backend
+            .get(storeful.partition(0), old.primary().as_bytes())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn migrates_records_whose_index_values_changed() {
+        let storeful = Storeful::new(MemoryBackend::new("test".into(), &Tag::trees()));
+        storeful
+            .post((0..3).map(|timestamp| TagV1 {
+                timestamp,
+                tag: "Web".into(),
+            }))
+            .unwrap();
+
+        assert_eq!(storeful.migrate::<Tag>().unwrap(), 3);
+        // The entries under the old tag went along with the older copies
+        let old: Vec<Tag> = storeful.query(&ByTag("Web")).unwrap();
+        assert!(old.is_empty());
+        let tags: Vec<Tag> = storeful.query(&ByTag("web")).unwrap();
+        assert_eq!(
+            tags,
+            (0..3)
+                .map(|timestamp| Tag {
+                    timestamp,
+                    tag: "web".into(),
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+}
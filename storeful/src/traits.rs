@@ -1,3 +1,146 @@
-// pub trait Storeable {
-//     fn store(&self, store: &mut Storeful) -> Result<()>;
-// }
+use std::{borrow::Cow, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    prelude::*, Context, ContextValue, Decoders, Key, LabelMatcher, Order, Sample, Series,
+};
+
+/// The tree every `Storeable` is indexed by timestamp in.
+pub const TIMESTAMP_INDEX: &str = "timestamp";
+
+/// What the trees of label indexes are named with, before the key of their label.
+pub const LABEL_INDEX_PREFIX: &str = "label:";
+
+/// `label:service.name`, the tree of the index on the values of one label.
+///
+/// Label indexes are created at runtime with `Storeful::create_label_index`, rather than
+/// declared by the model.
+pub fn label_index(key: &str) -> String {
+    format!("{}{}", LABEL_INDEX_PREFIX, key)
+}
+
+/// An entry in one of the indexes of a `Storeable`, pointing at the record's primary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexValue {
+    pub index: Cow<'static, str>,
+    pub key: Key,
+}
+
+impl IndexValue {
+    pub fn new(index: impl Into<Cow<'static, str>>, key: Key) -> Self {
+        Self {
+            index: index.into(),
+            key,
+        }
+    }
+
+    /// `(key, value)` of a single label, so records can be looked up by label.
+    pub fn context_value(
+        index: impl Into<Cow<'static, str>>,
+        context_value: &ContextValue,
+    ) -> Self {
+        Self::new(
+            index,
+            Key::new()
+                .with_str(&context_value.key)
+                .with_str(&context_value.value),
+        )
+    }
+
+    /// `(value)` in the label index of `key`, see `label_index`.
+    pub fn label(key: &str, value: &str) -> Self {
+        Self::new(label_index(key), Key::new().with_str(value))
+    }
+}
+
+/// A value stored along with the version of its model, so older ones can still be read once the
+/// model changes.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Raised whenever the serialized form of the model changes.
+    const VERSION: u16 = 1;
+
+    /// How values written as earlier versions are read and upgraded to this one.
+    fn decoders() -> Decoders<Self> {
+        Decoders::default()
+    }
+}
+
+/// A model `Storeful` can write, index, query and delete without any model specific code.
+///
+/// An upgraded record has to keep the primary and timestamp it was written with.
+pub trait Storeable: Versioned + Send {
+    /// The trees of the indexes in `index_values`, besides `TIMESTAMP_INDEX`.
+    const INDEXES: &'static [&'static str];
+
+    /// Identifies the record, writing a record with the same primary replaces it.
+    fn primary(&self) -> Key;
+    /// Nanoseconds since the epoch, deciding the partition and timestamp index entry.
+    fn timestamp(&self) -> i64;
+    /// Every index entry the record can be found by.
+    fn index_values(&self) -> Vec<IndexValue>;
+    /// The labels the label indexes created at runtime are read from, if the model has any.
+    fn context(&self) -> Option<&Context> {
+        None
+    }
+    /// Refuses a record that can't be stored, such as one whose timestamp doesn't fit in
+    /// nanoseconds. Checked before the record is written.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Every tree a backend storing this model has to open.
+    fn trees() -> Vec<&'static str> {
+        let mut trees = vec![TIMESTAMP_INDEX];
+        trees.extend_from_slice(Self::INDEXES);
+        trees
+    }
+}
+
+/// A model made of samples of many series, stored once per series rather than per record.
+///
+/// The name and labels are kept in the series dictionary, each sample only stores its series
+/// id, timestamp and value.
+pub trait Sampled: Send {
+    fn name(&self) -> &str;
+    fn context(&self) -> &Context;
+    /// The timestamp and value of the record, failing when the timestamp can't be stored.
+    fn sample(&self) -> Result<Sample>;
+    /// Every posting list the series is listed in, matched by the `index_values` of queries.
+    fn index_values(series: &Series) -> Vec<IndexValue>;
+    /// Puts a record back together from its series and one of its samples. Every sample of a
+    /// series read by a query shares it, so records can hold on to it instead of copying it.
+    fn from_sample(series: &Arc<Series>, sample: Sample) -> Self;
+}
+
+/// A query `Storeful` can answer from the indexes of a `Storeable`.
+pub trait IndexQuery: Sync {
+    fn timestamp_start(&self) -> Option<i64>;
+    fn timestamp_end(&self) -> Option<i64>;
+    /// Index values a record must have to match, each equal to one of its `index_values`.
+    ///
+    /// Values are compared whole. A key holding only some components of an index value, such
+    /// as a label key without its value, matches nothing.
+    fn index_values(&self) -> Vec<IndexValue>;
+
+    /// Conditions on labels a record must meet besides its `index_values`, such as
+    /// `host!="a"` or `region=~"eu-.*"`.
+    fn label_matchers(&self) -> Result<Vec<LabelMatcher>> {
+        Ok(Vec::new())
+    }
+
+    /// Whether results come oldest or newest first.
+    fn order(&self) -> Order {
+        Order::default()
+    }
+
+    /// The most results to return, all of them when `None`.
+    fn limit(&self) -> Option<usize> {
+        None
+    }
+
+    /// The token of the previous `Page`, to return the results following it.
+    fn continuation(&self) -> Option<&str> {
+        None
+    }
+}
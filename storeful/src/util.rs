@@ -1,9 +1,174 @@
-use std::collections::HashSet;
+use std::{ops::Bound, time::Duration};
 
-pub fn intersect(a: &mut HashSet<Box<[u8]>>, b: HashSet<Box<[u8]>>) {
-    if a.is_empty() {
-        a.extend(b);
-    } else {
-        a.retain(|item| b.contains(item));
+use crate::{prelude::*, Key, Order, RetentionRule, TIMESTAMP_INDEX};
+
+/// `("timestamp", 42)`, the prefix every timestamp index entry starts with.
+pub fn timestamp_index_prefix(timestamp: i64) -> Key {
+    Key::new().with_str(TIMESTAMP_INDEX).with_i64(timestamp)
+}
+
+/// The timestamp index keys covering `timestamp_start..=timestamp_end`.
+pub fn timestamp_index_range(
+    timestamp_start: Option<i64>,
+    timestamp_end: Option<i64>,
+) -> Result<(Bound<Key>, Bound<Key>)> {
+    if let (Some(start), Some(end)) = (timestamp_start, timestamp_end) {
+        if start > end {
+            return Err(StorefulError::InvalidQueryRange);
+        }
+    }
+    let lower = match timestamp_start {
+        Some(start) => Bound::Included(timestamp_index_prefix(start)),
+        None => Bound::Unbounded,
+    };
+    let upper = match timestamp_end.and_then(|end| end.checked_add(1)) {
+        Some(after_end) => Bound::Excluded(timestamp_index_prefix(after_end)),
+        None => Bound::Unbounded,
+    };
+    Ok((lower, upper))
+}
+
+/// Narrows `range` to the keys past `after` when scanning in `order`, `None` if none are left.
+pub fn resume_after(
+    (lower, upper): (Bound<Key>, Bound<Key>),
+    order: Order,
+    after: Option<&[u8]>,
+) -> Option<(Bound<Key>, Bound<Key>)> {
+    let range = match (after, order) {
+        (None, _) => (lower, upper),
+        (Some(after), Order::Ascending) => {
+            let past = Bound::Excluded(Key::new().with_key(after));
+            match lower {
+                Bound::Included(lower) if lower.as_bytes() > after => {
+                    (Bound::Included(lower), upper)
+                }
+                Bound::Excluded(lower) if lower.as_bytes() >= after => {
+                    (Bound::Excluded(lower), upper)
+                }
+                _ => (past, upper),
+            }
+        }
+        (Some(after), Order::Descending) => {
+            let before = Key::new().with_key(after);
+            match upper {
+                Bound::Excluded(upper) if upper < before => (lower, Bound::Excluded(upper)),
+                Bound::Included(upper) if upper < before => (lower, Bound::Included(upper)),
+                _ => (lower, Bound::Excluded(before)),
+            }
+        }
+    };
+    half_open(range)
+}
+
+/// `range` as an inclusive lower and an exclusive upper bound, `None` if it is empty.
+///
+/// Backends that can't express anything else can take the bounds as is.
+pub fn half_open((lower, upper): (Bound<Key>, Bound<Key>)) -> Option<(Bound<Key>, Bound<Key>)> {
+    // Nothing sorts between a key and the key with a 0x00 appended
+    let lower = match lower {
+        Bound::Excluded(lower) => Bound::Included(lower.with_key(&[0x00])),
+        lower => lower,
+    };
+    let upper = match upper {
+        Bound::Included(upper) => Bound::Excluded(upper.with_key(&[0x00])),
+        upper => upper,
+    };
+    if let (Bound::Included(lower), Bound::Excluded(upper)) = (&lower, &upper) {
+        if lower >= upper {
+            return None;
+        }
+    }
+    Some((lower, upper))
+}
+
+/// Parses retention rules like `30d` for every model or `logs=7d` for one.
+pub fn parse_retention(s: &str) -> std::result::Result<RetentionRule, String> {
+    match s.split_once('=') {
+        Some((model, retention)) if !model.is_empty() => Ok(RetentionRule {
+            model: Some(model.into()),
+            retention: parse_duration(retention)?,
+        }),
+        Some(_) => Err(format!("missing model name: {}", s)),
+        None => Ok(RetentionRule {
+            model: None,
+            retention: parse_duration(s)?,
+        }),
+    }
+}
+
+/// Parses durations like `30d`, `12h`, `15m` or `90s`.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit, expected s, m, h, d or w: {}",
+                s
+            ))
+        }
+    };
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration too large: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_duration("30d"),
+            Ok(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("2w"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        for invalid in [
+            "",
+            "d",
+            "30",
+            "30x",
+            "-1d",
+            "1.5h",
+            &format!("{}w", u64::MAX),
+        ] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_retention_rules() {
+        assert_eq!(
+            parse_retention("logs=7d"),
+            Ok(RetentionRule {
+                model: Some("logs".into()),
+                retention: Duration::from_secs(7 * 24 * 60 * 60),
+            })
+        );
+        assert_eq!(
+            parse_retention("1h"),
+            Ok(RetentionRule {
+                model: None,
+                retention: Duration::from_secs(60 * 60),
+            })
+        );
+        assert!(parse_retention("=7d").is_err());
+        assert!(parse_retention("logs=").is_err());
     }
 }
@@ -1,6 +1,6 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use storeful::{Context, ContextValue};
+use storeful::{prelude::*, Context, IndexValue, Key, Storeable, Versioned};
 use typed_builder::TypedBuilder;
 use ulid::Ulid;
 
@@ -22,14 +22,21 @@ impl Trace {
         }
     }
 
-    pub fn add_span(&mut self, mut span: Span) {
+    pub fn add_span(&mut self, span: Span) {
         self.spans.push(span);
     }
 
-    pub fn with_span(mut self, mut span: Span) -> Self {
+    pub fn with_span(mut self, span: Span) -> Self {
         self.add_span(span);
         self
     }
+
+    /// The time in the trace id in nanoseconds, if it fits.
+    fn timestamp_nanos(&self) -> Option<i64> {
+        i64::try_from(self.trace_id.timestamp_ms())
+            .ok()?
+            .checked_mul(1_000_000)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
@@ -51,6 +58,19 @@ pub struct Span {
 }
 
 impl Span {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            span_id: Ulid::new(),
+            parent_span_id: None,
+            context: Context::default(),
+            start_time: chrono::Utc::now(),
+            end_time: chrono::Utc::now(),
+            events: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
     pub fn add_event(&mut self, name: &str, context: Context) {
         self.events.push(Event::new(name.into(), context));
     }
@@ -60,10 +80,18 @@ impl Span {
         self
     }
 
+    /// # Panics
+    ///
+    /// If `key` is not a valid context key, see `try_add_context`.
     pub fn add_context(&mut self, key: &str, value: &str) {
         self.context.add_value(key, value);
     }
 
+    /// Like `add_context`, failing on an invalid key instead of panicking.
+    pub fn try_add_context(&mut self, key: &str, value: &str) -> Result<()> {
+        self.context.try_add_value(key, value)
+    }
+
     pub fn with_context(mut self, key: &str, value: &str) -> Self {
         self.add_context(key, value);
         self
@@ -79,19 +107,6 @@ impl Span {
         self.add_child(span);
         self
     }
-
-    fn new(name: &str) -> Self {
-        Self {
-            name: name.into(),
-            span_id: Ulid::new(),
-            parent_span_id: None,
-            context: Context::default(),
-            start_time: chrono::Utc::now(),
-            end_time: chrono::Utc::now(),
-            events: Vec::new(),
-            children: Vec::new(),
-        }
-    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -113,6 +128,42 @@ impl Event {
     }
 }
 
+impl Versioned for Trace {}
+
+impl Storeable for Trace {
+    const INDEXES: &'static [&'static str] = &["name", "context"];
+
+    fn primary(&self) -> Key {
+        Key::new().with_str(&self.trace_id.to_string())
+    }
+
+    /// When the trace id was generated, which unlike its spans never changes once stored.
+    ///
+    /// Ids from past 2262 are clamped, `validate` keeps them from being stored.
+    fn timestamp(&self) -> i64 {
+        self.timestamp_nanos().unwrap_or(i64::MAX)
+    }
+
+    fn index_values(&self) -> Vec<IndexValue> {
+        let mut index_values = vec![IndexValue::new("name", Key::new().with_str(&self.name))];
+        for context_value in self.context.values() {
+            index_values.push(IndexValue::context_value("context", context_value));
+        }
+        index_values
+    }
+
+    fn context(&self) -> Option<&Context> {
+        Some(&self.context)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self.timestamp_nanos() {
+            Some(_) => Ok(()),
+            None => Err(StorefulError::InvalidTimestamp(self.trace_id.to_string())),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -209,4 +260,17 @@ mod tests {
         // Add the main span to the trace
         trace.add_span(main_span);
     }
+
+    #[test]
+    fn refuses_ids_past_nanoseconds() {
+        let mut trace = Trace::new("GET /", Context::default());
+        assert!(trace.validate().is_ok());
+        // Ulids go on until the year 10889, nanoseconds stop in 2262
+        trace.trace_id = Ulid::from_parts(10_000_000_000_000, 0);
+        assert!(matches!(
+            trace.validate(),
+            Err(StorefulError::InvalidTimestamp(_))
+        ));
+        assert_eq!(trace.timestamp(), i64::MAX);
+    }
 }